@@ -0,0 +1,201 @@
+use std::alloc::{handle_alloc_error, Layout};
+use std::cell::RefCell;
+use std::ptr::{null_mut, NonNull};
+#[cfg(not(rust_map_loom))]
+use parking_lot::{const_mutex, Mutex};
+#[cfg(rust_map_loom)]
+use crate::model::Mutex;
+
+//nodes of maps with ARENA are allocated in size classes of 16 bytes up to 1KB, and freed blocks are kept on free lists
+//rather than given back, first on a list for the freeing thread then once that holds LOCAL_LIMIT blocks on a shared one
+//that threads refill from. shrink gives the free blocks back. larger or more aligned allocations aren't kept
+const CLASS_SIZE: usize = 16;
+const CLASSES: usize = 64;
+const LOCAL_LIMIT: usize = 64;
+
+//free blocks linked through their first word
+struct FreeList{
+    head: *mut u8,
+    len: usize
+}
+
+unsafe impl Send for FreeList{}
+
+impl FreeList{
+    const fn new() -> Self{
+        Self{head: null_mut(), len: 0}
+    }
+
+    fn push(&mut self, block: *mut u8){
+        unsafe{block.cast::<*mut u8>().write(self.head)}
+        self.head = block;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8>{
+        if self.head.is_null() {return None}
+        let block = self.head;
+        self.head = unsafe{block.cast::<*mut u8>().read()};
+        self.len -= 1;
+        Some(block)
+    }
+
+    //moves up to count blocks onto other
+    fn move_to(&mut self, other: &mut Self, count: usize){
+        for _ in 0..count{
+            let Some(block) = self.pop() else {break};
+            other.push(block);
+        }
+    }
+}
+
+#[cfg(not(rust_map_loom))]
+static SHARED: [Mutex<FreeList>; CLASSES] = [const {const_mutex(FreeList::new())}; CLASSES];
+#[cfg(rust_map_loom)]
+loom::lazy_static!{
+    static ref SHARED: [Mutex<FreeList>; CLASSES] = std::array::from_fn(|_| Mutex::new(FreeList::new()));
+}
+
+//a thread's free lists go to the shared ones when it exits
+struct Local([FreeList; CLASSES]);
+
+impl Drop for Local{
+    fn drop(&mut self){
+        for (class, list) in self.0.iter_mut().enumerate(){
+            let len = list.len;
+            list.move_to(&mut SHARED[class].lock(), len);
+        }
+    }
+}
+
+#[cfg(not(rust_map_loom))]
+thread_local!{
+    static LOCAL: RefCell<Local> = const {RefCell::new(Local([const {FreeList::new()}; CLASSES]))};
+}
+//under loom every block goes through the shared lists. the local ones are only ever used by their own thread, and loom
+//drops a thread's thread locals in no fixed order, which with the epoch's would differ between runs of one interleaving
+#[cfg(rust_map_loom)]
+struct NoLocal;
+
+#[cfg(rust_map_loom)]
+impl NoLocal{
+    fn try_with<R>(&self, _: impl FnOnce(&RefCell<Local>) -> R) -> Result<R, ()>{
+        Err(())
+    }
+}
+
+#[cfg(rust_map_loom)]
+static LOCAL: NoLocal = NoLocal;
+
+fn class(layout: Layout) -> Option<usize>{
+    if (layout.align() <= CLASS_SIZE) && (layout.size() <= (CLASS_SIZE * CLASSES)) {Some(layout.size().max(1).div_ceil(CLASS_SIZE) - 1)}
+    else {None}
+}
+
+fn class_layout(class: usize) -> Layout{
+    Layout::from_size_align(CLASS_SIZE * (class + 1), CLASS_SIZE).unwrap()
+}
+
+//bytes alloc takes from the allocator for layout, a whole size class for maps with ARENA
+pub(crate) fn allocated_size<const ARENA: bool>(layout: Layout) -> usize{
+    match class(layout).filter(|_| ARENA){
+        Some(class) => class_layout(class).size(),
+        None => layout.pad_to_align().size()
+    }
+}
+
+pub(crate) fn alloc<const ARENA: bool>(layout: Layout) -> NonNull<u8>{
+    let ptr = match class(layout).filter(|_| ARENA){
+        Some(class) => {
+            let block = LOCAL.try_with(|x| {
+                let list = &mut x.borrow_mut().0[class];
+                if list.len == 0 {SHARED[class].lock().move_to(list, LOCAL_LIMIT / 2)}
+                list.pop()
+            });
+            //during thread exit the local lists may already be gone
+            let block = block.unwrap_or_else(|_| SHARED[class].lock().pop());
+            block.unwrap_or_else(|| unsafe{std::alloc::alloc(class_layout(class))})
+        }
+        None => unsafe{std::alloc::alloc(layout)}
+    };
+    NonNull::new(ptr).unwrap_or_else(|| handle_alloc_error(layout))
+}
+
+pub(crate) fn dealloc<const ARENA: bool>(ptr: NonNull<u8>, layout: Layout){
+    match class(layout).filter(|_| ARENA){
+        Some(class) => {
+            //during thread exit the local lists may already be gone
+            let pushed = LOCAL.try_with(|x| {
+                let list = &mut x.borrow_mut().0[class];
+                if list.len >= LOCAL_LIMIT {list.move_to(&mut SHARED[class].lock(), LOCAL_LIMIT / 2)}
+                list.push(ptr.as_ptr());
+            });
+            if pushed.is_err() {SHARED[class].lock().push(ptr.as_ptr())}
+        }
+        None => unsafe{std::alloc::dealloc(ptr.as_ptr(), layout)}
+    }
+}
+
+//gives the shared free blocks and the calling thread's back to the global allocator, returning how many bytes that was
+//other threads keep theirs until they exit, which is at most LOCAL_LIMIT blocks per class each
+pub(crate) fn shrink() -> usize{
+    let mut freed = 0;
+    for (class, shared) in SHARED.iter().enumerate(){
+        let mut shared = shared.lock();
+        let _ = LOCAL.try_with(|x| {
+            let list = &mut x.borrow_mut().0[class];
+            let len = list.len;
+            list.move_to(&mut shared, len);
+        });
+        while let Some(block) = shared.pop(){
+            unsafe{std::alloc::dealloc(block, class_layout(class))}
+            freed += class_layout(class).size();
+        }
+    }
+    freed
+}
+
+//where a map's nodes are allocated, which nodes take as a type parameter
+pub(crate) trait Alloc{
+    fn alloc(layout: Layout) -> NonNull<u8>;
+    fn dealloc(ptr: NonNull<u8>, layout: Layout);
+    fn allocated_size(layout: Layout) -> usize;
+}
+
+//freed straight away, into the arena if ARENA
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct Heap<const ARENA: bool>;
+
+impl<const ARENA: bool> Alloc for Heap<ARENA>{
+    fn alloc(layout: Layout) -> NonNull<u8>{
+        alloc::<ARENA>(layout)
+    }
+
+    fn dealloc(ptr: NonNull<u8>, layout: Layout){
+        dealloc::<ARENA>(ptr, layout)
+    }
+
+    fn allocated_size(layout: Layout) -> usize{
+        allocated_size::<ARENA>(layout)
+    }
+}
+
+//freed once no optimistic reader can still be in it, see epoch
+#[cfg(feature = "optimistic")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct Deferred<const ARENA: bool>;
+
+#[cfg(feature = "optimistic")]
+impl<const ARENA: bool> Alloc for Deferred<ARENA>{
+    fn alloc(layout: Layout) -> NonNull<u8>{
+        alloc::<ARENA>(layout)
+    }
+
+    fn dealloc(ptr: NonNull<u8>, layout: Layout){
+        unsafe{crate::epoch::retire(ptr, layout, dealloc::<ARENA>)}
+    }
+
+    fn allocated_size(layout: Layout) -> usize{
+        allocated_size::<ARENA>(layout)
+    }
+}
@@ -0,0 +1,198 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::concurrent::Map;
+use crate::{get_first_different_digit, InsertOrUpdateResult};
+
+//what a bounded map does with an insert of a key it doesn't have once it is full. V is a parameter of the trait rather
+//than the method so a policy chosen at runtime can be a Box<dyn EvictionPolicy<N, V>>
+pub trait EvictionPolicy<const N: usize, V>: Send + Sync{
+    //the key to remove to make room for key, or None to reject the insert. key wasn't in the map, which was full, but
+    //other threads can be writing to it while the policy chooses, and if the key chosen is gone by the time it is
+    //removed the policy is asked again
+    fn choose_victim(&self, map: &Map<N, V>, key: [u8; N]) -> Option<[u8; N]>;
+}
+
+impl<const N: usize, V, P: EvictionPolicy<N, V> + ?Sized> EvictionPolicy<N, V> for Box<P>{
+    fn choose_victim(&self, map: &Map<N, V>, key: [u8; N]) -> Option<[u8; N]>{
+        (**self).choose_victim(map, key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reject;
+
+impl<const N: usize, V: Copy> EvictionPolicy<N, V> for Reject{
+    fn choose_victim(&self, _: &Map<N, V>, _: [u8; N]) -> Option<[u8; N]>{
+        None
+    }
+}
+
+//keeps the largest keys, so the new key is rejected if it is smaller than every key in the map
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvictMin;
+
+impl<const N: usize, V: Copy> EvictionPolicy<N, V> for EvictMin{
+    fn choose_victim(&self, map: &Map<N, V>, key: [u8; N]) -> Option<[u8; N]>{
+        map.get_min().map(|x| x.0).filter(|x| *x < key)
+    }
+}
+
+//keeps the smallest keys
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvictMax;
+
+impl<const N: usize, V: Copy> EvictionPolicy<N, V> for EvictMax{
+    fn choose_victim(&self, map: &Map<N, V>, key: [u8; N]) -> Option<[u8; N]>{
+        map.get_max().map(|x| x.0).filter(|x| *x > key)
+    }
+}
+
+//keeps the keys nearest the reference key, nearest being most leading bits in common with it as in
+//get_or_closest_by_key_leading_zeroes, like the buckets of a kademlia routing table. the new key is rejected if it
+//isn't nearer than the farthest key in the map, so of keys as near as each other the ones already there stay
+#[derive(Debug, Clone, Copy)]
+pub struct FarthestFrom<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> FarthestFrom<N>{
+    fn common_bits(&self, key: [u8; N]) -> usize{
+        get_first_different_digit::<N, 1>(key, self.0).unwrap_or(N * 8)
+    }
+}
+
+impl<const N: usize, V: Copy> EvictionPolicy<N, V> for FarthestFrom<N>{
+    fn choose_victim(&self, map: &Map<N, V>, key: [u8; N]) -> Option<[u8; N]>{
+        //every key shares the leading bits the smallest and largest share, and at the bit after those one of the two
+        //differs from the reference, so whichever of them has fewer bits in common with it has as few as any key. that
+        //is two walks down the map whatever the size of the keys
+        let farthest = [map.get_min()?.0, map.get_max()?.0].into_iter().min_by_key(|x| self.common_bits(*x))?;
+        Some(farthest).filter(|x| self.common_bits(*x) < self.common_bits(key))
+    }
+}
+
+//an insert a full map's eviction policy turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rejected<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Display for Rejected<N>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        write!(f, "insert of {:?} rejected by a full map", self.0)
+    }
+}
+
+impl<const N: usize> Error for Rejected<N>{}
+
+//what an insert did along with the entry evicted to make room for it
+pub type BoundedInsert<const N: usize, V> = Result<(InsertOrUpdateResult, Option<([u8; N], V)>), Rejected<N>>;
+
+//a concurrent map holding at most max_len entries, an insert of a new key into a full map first removes the entry its
+//policy picks. an insert takes a place in the count before it goes into the map and gives it back if it turns out to
+//be an update, and a remove gives its place back after it comes out, so the count is never less than what is in the
+//map and the bound always holds without writes having to take turns
+pub struct BoundedMap<const N: usize, V, P: EvictionPolicy<N, V> = Reject>{
+    map: Map<N, V>,
+    max_len: usize,
+    len: AtomicUsize,
+    policy: P
+}
+
+impl<const N: usize, V: Copy, P: EvictionPolicy<N, V>> BoundedMap<N, V, P>{
+    pub const fn new(max_len: usize, policy: P) -> Self{
+        Self{map: Map::new(), max_len, len: AtomicUsize::new(0), policy}
+    }
+
+    pub fn max_len(&self) -> usize{
+        self.max_len
+    }
+
+    //counting inserts that are still going on
+    pub fn len(&self) -> usize{
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool{
+        self.len() >= self.max_len
+    }
+
+    pub fn clear(&self){
+        self.len.fetch_sub(self.map.clear_counted(), Ordering::SeqCst);
+    }
+
+    pub fn get(&self, key: [u8; N]) -> Option<V>{
+        self.map.get(key)
+    }
+
+    pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
+        self.map.get_or_closest_by_key(key, include_key, loop_around)
+    }
+
+    pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
+        self.map.get_or_closest_by_key_leading_zeroes(key, include_key)
+    }
+
+    pub fn get_min(&self) -> Option<([u8; N], V)>{
+        self.map.get_min()
+    }
+
+    pub fn get_max(&self) -> Option<([u8; N], V)>{
+        self.map.get_max()
+    }
+
+    //calls f with every entry in key order, f mustn't use the map
+    pub fn for_each(&self, f: impl FnMut([u8; N], V)){
+        self.map.for_each(f);
+    }
+
+    pub fn insert_or_update(&self, key: [u8; N], value: V) -> BoundedInsert<N, V>{
+        self.insert_or_update_if(key, value, &|_, _| true)
+    }
+
+    pub fn insert_or_update_if(&self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool) -> BoundedInsert<N, V>{
+        //an update doesn't need a place so only a key that isn't there takes one
+        let updated = Cell::new(false);
+        if self.map.update_if(key, value, &|x| {
+            updated.set(should_update(x, &value));
+            updated.get()
+        }).is_some(){
+            return Ok((if updated.get() {InsertOrUpdateResult::Updated} else {InsertOrUpdateResult::Neither}, None))
+        }
+        let mut evicted = None;
+        while !self.reserve(){
+            //the place the victim had passes to this insert rather than being given back
+            let victim = self.policy.choose_victim(&self.map, key).ok_or(Rejected(key))?;
+            let removed = Cell::new(None);
+            if self.map.remove_if(victim, &|x| {
+                removed.set(Some(*x));
+                true
+            }){
+                evicted = removed.get().map(|x| (victim, x));
+                break
+            }
+            //another thread removed the victim first, which may have left a place
+        }
+        let result = self.map.insert_or_update_if(key, value, should_update);
+        //another thread put key in first, an entry evicted for it stays evicted
+        if result != InsertOrUpdateResult::Inserted {self.len.fetch_sub(1, Ordering::SeqCst);}
+        Ok((result, evicted))
+    }
+
+    //takes a place in the count if there is one
+    fn reserve(&self) -> bool{
+        self.len.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| Some(x + 1).filter(|x| *x <= self.max_len)).is_ok()
+    }
+
+    pub fn remove(&self, key: [u8; N]){
+        self.remove_if(key, &|_| true);
+    }
+
+    pub fn remove_if(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> bool{
+        let removed = self.map.remove_if(key, should_remove);
+        if removed {self.len.fetch_sub(1, Ordering::SeqCst);}
+        removed
+    }
+}
@@ -1,164 +1,423 @@
-use parking_lot::RwLock;
-use crate::{get_index, InsertOrUpdateResult};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use crate::{arena, get_first_different_digit, get_index, get_prefix, get_prefix_with_digit, get_split_depth, leading_zeroes_order, BitsPerLevel, InsertOrUpdateResult, MapStats, MemoryUsage, SupportedBitsPerLevel, ValidationError, Violation};
+use crate::lock::{Backoff, Lockable, ReadGuard, RwLock, WriteGuard};
+use crate::non_concurrent;
+#[cfg(feature = "optimistic")]
+use crate::lock::{self, Peek, Reads};
+#[cfg(feature = "optimistic")]
+use crate::version::{self, View};
+use crate::dump::Dump;
+use crate::node::{self, Slot};
 
+//the lock and the tagged pointer to what is below it share one word so a slot in a list is 8 bytes
 #[derive(Debug)]
-pub struct Map<const N: usize, V>(RwLock<MapInternal<N, V>>);
+pub struct Map<const N: usize, V, const BITS: usize = 2, const B: usize = 1, const COMPACT: bool = false, const ARENA: bool = false, const COUNTED: bool = false>(RwLock<MapInternal<N, V, BITS, B, COMPACT, ARENA>>, <Count<COUNTED> as Counting>::Counter) where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting;
 
+//a map with COUNTED keeps a count of its entries as they are inserted and removed so len_approx doesn't have to walk
+//it, spread over stripes on their own cache lines so threads updating it at once mostly don't share one
+//only the map itself has the count, the maps in its lists' slots are uncounted so are still one word
+pub struct Count<const COUNTED: bool>;
+
+pub trait Counting{
+    type Counter: Debug + Send + Sync;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Self::Counter;
+    fn add(counter: &Self::Counter, change: isize);
+}
+
+impl Counting for Count<false>{
+    type Counter = ();
+    const NEW: () = ();
+    fn add(_: &(), _: isize){}
+}
+
+impl Counting for Count<true>{
+    type Counter = Stripes;
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NEW: Stripes = Stripes([const {Stripe(AtomicIsize::new(0))}; STRIPES]);
+
+    //a map changed from a thread local's destructor after STRIPE is gone uses the first stripe
+    fn add(counter: &Stripes, change: isize){
+        counter.0[STRIPE.try_with(|x| *x).unwrap_or(0)].0.fetch_add(change, Ordering::Relaxed);
+    }
+}
+
+const STRIPES: usize = 16;
+
+#[derive(Debug)]
+pub struct Stripes([Stripe; STRIPES]);
+
+//an entry can be added on one stripe and taken off another so a stripe can go below 0
 #[derive(Debug)]
-enum MapInternal<const N: usize, V>{
-    Item(Box<([u8; N], V)>),
-    List(Box<[Map<N, V>; 4]>),
-    Empty
+#[repr(align(128))]
+struct Stripe(AtomicIsize);
+
+thread_local!{
+    //threads take the stripes in turn
+    static STRIPE: usize = {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        NEXT.fetch_add(1, Ordering::Relaxed) % STRIPES
+    };
+}
+
+type MapInternal<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> = node::MapInternal<N, V, B, COMPACT, Alloc<ARENA>, List<N, V, BITS, B, COMPACT, ARENA>>;
+type Bucket<const N: usize, V, const B: usize, const COMPACT: bool, const ARENA: bool> = node::Bucket<N, V, B, COMPACT, Alloc<ARENA>>;
+type NodeBox<T, const ARENA: bool> = node::NodeBox<T, Alloc<ARENA>>;
+type Nodes<T, const BITS: usize, const ARENA: bool> = node::Nodes<T, BITS, Alloc<ARENA>>;
+
+//with optimistic reads what writers free may still be being read, so it is only freed once no reader can be in it
+#[cfg(not(feature = "optimistic"))]
+type Alloc<const ARENA: bool> = Lockable<arena::Heap<ARENA>>;
+#[cfg(feature = "optimistic")]
+type Alloc<const ARENA: bool> = Lockable<arena::Deferred<ARENA>>;
+
+//the digits between the parent list's depth and this list's depth are the same for every key below it so are skipped,
+//prefix holds those bits (and everything above them) so a key can be checked against them
+//sharded lists are the pre-expanded top levels of a map from with_sharded_root, they have a child for every digit and
+//are never collapsed so they are only ever read locked
+#[derive(Debug)]
+struct List<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    depth: usize,
+    prefix: [u8; N],
+    children: Nodes<Map<N, V, BITS, B, COMPACT, ARENA>, BITS, ARENA>,
+    sharded: bool
+}
+
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> List<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    //through the list rather than its map so a read locked map isn't read locked again
+    fn get_min<'a>(&'a self, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        self.children.iter().map(|x| Ok(x.1.get_min_held(guards)?.map(|y| self.restore(x.0, y)))).find_map(Result::transpose).transpose()
+    }
+
+    fn get_max<'a>(&'a self, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        self.children.iter().rev().map(|x| Ok(x.1.get_max_held(guards)?.map(|y| self.restore(x.0, y)))).find_map(Result::transpose).transpose()
+    }
+
+    fn get_child_skip(&self) -> usize{
+        Bucket::<N, V, B, COMPACT, ARENA>::skip_in_list::<BITS>(self.depth)
+    }
+
+    //puts back the key bytes a compact bucket below the child at digit skipped, which it reads as 0
+    fn restore(&self, digit: usize, entry: ([u8; N], V)) -> ([u8; N], V){
+        if !COMPACT {return entry}
+        let prefix = get_prefix_with_digit::<N, BITS>(self.prefix, self.depth, digit);
+        (std::array::from_fn(|i| entry.0[i] | prefix[i]), entry.1)
+    }
+}
+
+#[cfg(feature = "optimistic")]
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> node::CopyOnWrite for List<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    unsafe fn copy(&self) -> Self{
+        List{depth: self.depth, prefix: self.prefix, children: unsafe{self.children.copy()}, sharded: self.sharded}
+    }
+
+    unsafe fn free_copied(self){
+        unsafe{self.children.free_copied()}
+    }
 }
 
-impl<const N: usize, V: Copy> MapInternal<N, V> {
-    fn new_item(key: [u8; N], value: V) -> Self{
-        Self::Item(Box::new((key, value)))
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> Default for Map<N, V, BITS, B, COMPACT, ARENA, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    fn default() -> Self{
+        Self(RwLock::empty(), <Count<COUNTED> as Counting>::NEW)
     }
 }
 
-impl<const N: usize, V: Copy> Map<N, V>{
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> Slot for Map<N, V, BITS, B, COMPACT, ARENA, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    fn is_vacant(&self) -> bool{
+        matches!(*self.0.read(), MapInternal::Empty)
+    }
+
+    #[cfg(all(rust_map_loom, feature = "optimistic"))]
+    fn moved(&mut self){
+        self.0.moved()
+    }
+}
+
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> Map<N, V, BITS, B, COMPACT, ARENA, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
 
     pub fn get_used_percent(&self) -> f64{
         (((size_of::<[u8; N]>() + size_of::<V>()) * self.len()) as f64) / (self.get_memory_size() as f64)
     }
 
     pub fn get_memory_size(&self) -> usize{
-        size_of::<Self>() +
-            match &*self.0.read() {
-                MapInternal::Item(_) => size_of::<[u8; N]>() + size_of::<V>(),
-                MapInternal::List(list) => list.iter().map(|x| x.get_memory_size()).sum(),
-                MapInternal::Empty => 0
+        self.get_memory_usage().total()
+    }
+
+    //bytes of buckets, lists and slots, as allocated
+    pub fn get_memory_usage(&self) -> MemoryUsage{
+        let mut usage = MemoryUsage{slots: size_of::<Self>(), ..MemoryUsage::default()};
+        self.add_memory_usage(&mut usage);
+        usage
+    }
+
+    fn add_memory_usage(&self, usage: &mut MemoryUsage){
+        let internal = self.0.read();
+        Self::add_node_memory_usage(&internal, usage);
+        if let MapInternal::List(list) = &*internal {list.children.slots().iter().for_each(|x| x.add_memory_usage(usage))}
+    }
+
+    //what the bucket or list here allocated, the slots of a list's children but not what is below them
+    fn add_node_memory_usage(internal: &MapInternal<N, V, BITS, B, COMPACT, ARENA>, usage: &mut MemoryUsage){
+        match internal{
+            MapInternal::Bucket(bucket) => usage.buckets += bucket.get_memory_size(),
+            MapInternal::List(list) => {
+                let slots = list.children.get_slots_size();
+                usage.lists += (list.allocated_size() + list.children.get_memory_size()) - slots;
+                usage.slots += slots;
             }
+            MapInternal::Empty => ()
+        }
     }
 
     pub fn is_empty(&self) -> bool{
         match &*self.0.read(){
-            MapInternal::Item(_) => false,
-            MapInternal::List(list) => list.iter().all(|x| x.is_empty()),
+            MapInternal::Bucket(_) => false,
+            MapInternal::List(list) => list.children.slots().iter().all(|x| x.is_empty()),
             MapInternal::Empty => true
         }
     }
 
     pub fn depth(&self) -> usize{
         match &*self.0.read(){
-            MapInternal::Item(_) => 1,
-            MapInternal::List(list) => 1 + list.iter().map(|x| x.depth()).max().unwrap(),
+            MapInternal::Bucket(_) => 1,
+            MapInternal::List(list) => 1 + list.children.slots().iter().map(|x| x.depth()).max().unwrap(),
             MapInternal::Empty => 0
         }
     }
 
+    #[cfg(not(feature = "optimistic"))]
     pub fn len(&self) -> usize{
         match &*self.0.read(){
-            MapInternal::Item(_) => 1,
-            MapInternal::List(list) => list.iter().map(|x| x.len()).sum(),
+            MapInternal::Bucket(bucket) => bucket.len(),
+            MapInternal::List(list) => list.children.slots().iter().map(|x| x.len()).sum(),
+            MapInternal::Empty => 0
+        }
+    }
+
+    //without taking any locks, like for_each
+    #[cfg(feature = "optimistic")]
+    pub fn len(&self) -> usize{
+        self.len_optimistic(&View::latest())
+    }
+
+    #[cfg(feature = "optimistic")]
+    fn len_optimistic(&self, view: &View) -> usize{
+        match &*self.0.peek(view){
+            MapInternal::Bucket(bucket) => bucket.len(),
+            MapInternal::List(list) => list.children.slots().iter().map(|x| x.len_optimistic(view)).sum(),
             MapInternal::Empty => 0
         }
     }
 
     pub const fn new() -> Self{
-        Self(RwLock::new(MapInternal::Empty))
+        const {assert!((B > 0) && (B <= u8::MAX as usize), "buckets must hold between 1 and 255 entries")}
+        Self(RwLock::empty(), <Count<COUNTED> as Counting>::NEW)
+    }
+
+    //a map whose first levels of lists are pre-expanded into a fixed array of shards, fan out ^ levels of them, which are
+    //never collapsed so writers never have to write lock those levels, at the cost of their memory and depth
+    pub fn with_sharded_root(levels: usize) -> Self{
+        assert!((levels * BITS) <= (N * 8), "can't shard past the end of the key");
+        Self(RwLock::new(Self::new_shards([0; N], 0, levels)), <Count<COUNTED> as Counting>::NEW)
+    }
+
+    //the maps in lists' slots are never counted
+    fn new_child(internal: MapInternal<N, V, BITS, B, COMPACT, ARENA>) -> Map<N, V, BITS, B, COMPACT, ARENA>{
+        Map::<N, V, BITS, B, COMPACT, ARENA>(RwLock::new(internal), ())
+    }
+
+    fn new_shards(prefix: [u8; N], depth: usize, levels: usize) -> MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        if depth == levels {return MapInternal::Empty}
+        let children = (0..(1 << BITS)).map(|x| (x, Self::new_child(Self::new_shards(get_prefix_with_digit::<N, BITS>(prefix, depth, x), depth + 1, levels)))).collect();
+        MapInternal::List(NodeBox::new(List{depth, prefix, children: Nodes::from_children(children), sharded: true}))
     }
 
     pub fn clear(&self){
-        *self.0.write() = MapInternal::Empty;
+        let removed = self.clear_internal(COUNTED);
+        <Count<COUNTED> as Counting>::add(&self.1, -(removed as isize));
     }
 
+    //clear returning how many entries it removed
+    pub(crate) fn clear_counted(&self) -> usize{
+        let removed = self.clear_internal(true);
+        <Count<COUNTED> as Counting>::add(&self.1, -(removed as isize));
+        removed
+    }
+
+    //returns how many entries went if count, otherwise 0 without counting them
+    fn clear_internal(&self, count: bool) -> usize{
+        if let MapInternal::List(list) = &*self.0.read(){
+            //the shards stay, only what is below them goes
+            if list.sharded {return list.children.slots().iter().map(|x| x.clear_internal(count)).sum()}
+        }
+        let removed = Self::new_child(std::mem::replace(&mut *self.0.write(), MapInternal::Empty));
+        if count {removed.len()} else {0}
+    }
+
+    #[cfg(not(feature = "optimistic"))]
     pub fn get(&self, key: [u8; N]) -> Option<V>{
-        self.get_internal(key, 0)
+        Self::get_in(&self.0.read(), key)
     }
 
-    fn get_internal(&self, key: [u8; N], depth: usize) -> Option<V>{
-        match &*self.0.read(){
-            MapInternal::Item(item) => if item.0 == key {Some(item.1)} else {None},
-            MapInternal::List(list) => list[get_index(key, depth)].get_internal(key, depth + 1),
+    //without taking any locks, what is read is never changed in place and isn't freed while the thread is pinned
+    #[cfg(feature = "optimistic")]
+    pub fn get(&self, key: [u8; N]) -> Option<V>{
+        self.get_optimistic(key, &View::latest())
+    }
+
+    #[cfg(feature = "optimistic")]
+    fn get_optimistic(&self, key: [u8; N], view: &View) -> Option<V>{
+        match &*self.0.peek(view){
+            MapInternal::Bucket(bucket) => bucket.get(key),
+            MapInternal::List(list) => {
+                if COMPACT && get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return None}
+                list.children.get(get_index::<N, BITS>(key, list.depth)).and_then(|x| x.get_optimistic(key, view))
+            }
+            MapInternal::Empty => None
+        }
+    }
+
+    //through what is already locked, so a transaction can read what it has write locked
+    fn get_in(internal: &MapInternal<N, V, BITS, B, COMPACT, ARENA>, key: [u8; N]) -> Option<V>{
+        match internal{
+            MapInternal::Bucket(bucket) => bucket.get(key),
+            MapInternal::List(list) => {
+                //a compact bucket doesn't store the bytes the list's prefix covers so they are checked here
+                if COMPACT && get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return None}
+                list.children.get(get_index::<N, BITS>(key, list.depth)).and_then(|x| x.get(key))
+            }
             MapInternal::Empty => None
         }
     }
 
     pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
-        self.get_or_closest_by_key_leading_zeroes_internal(key, include_key, 0)
+        self.query(|guards| self.get_or_closest_by_key_leading_zeroes_held(key, include_key, guards))
     }
 
-    fn get_or_closest_by_key_leading_zeroes_internal(&self, key: [u8; N], include_key: bool, depth: usize) -> Option<([u8; N], V)>{
-        match &*self.0.read(){
-            MapInternal::Item(item_key_value) => {
-                if (item_key_value.0 != key) || include_key {Some((item_key_value.0, item_key_value.1))} else {None}
+    fn get_or_closest_by_key_leading_zeroes_held<'a>(&'a self, key: [u8; N], include_key: bool, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        let guard = self.read_held(guards)?;
+        let list = match &*guard{
+            MapInternal::Bucket(bucket) => {
+                //most leading bits in common, then numerically nearest without looping around, as the keys with the most
+                //in common are all on one side of key that is the lowest above it or the highest below it
+                let closest = bucket.iter(key).filter(|x| (x.0 != key) || include_key)
+                    .min_by_key(|x| (Reverse(get_first_different_digit::<N, 1>(key, x.0).unwrap_or(N * 8)), if x.0 > key {x.0} else {x.0.map(|y| !y)}));
+                guards.push(guard);
+                return Ok(closest)
             }
-            MapInternal::List(list) => {
-                let index = get_index(key, depth);
-                match index{
-                    0 => [0,1,2,3],
-                    1 => [1,0,2,3],
-                    2 => [2,3,1,0],
-                    _ => [3,2,1,0]
-                }.iter().find_map(|i| {
-                    if *i == index {list[*i].get_or_closest_by_key_leading_zeroes_internal(key, include_key, depth + 1)}
-                    else if *i < index {list[*i].get_max()}
-                    else {list[*i].get_min()}
-                })
+            MapInternal::List(list) => unsafe{Self::borrow_list(list)},
+            MapInternal::Empty => {
+                guards.push(guard);
+                return Ok(None)
+            }
+        };
+        guards.push(guard);
+        match get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+            //every key in the list has the same leading bits in common with key so take the numerically nearest
+            Some(split_depth) => {
+                if get_index::<N, BITS>(key, split_depth) < get_index::<N, BITS>(list.prefix, split_depth) {list.get_min(guards)}
+                else {list.get_max(guards)}
+            }
+            None => {
+                let index = get_index::<N, BITS>(key, list.depth);
+                leading_zeroes_order::<BITS>(index).filter_map(|i| Some((i, list.children.get(i)?))).map(|(i, child)| {
+                    if i == index {child.get_or_closest_by_key_leading_zeroes_held(key, include_key, guards)}
+                    else if i < index {Ok(child.get_max_held(guards)?.map(|x| list.restore(i, x)))}
+                    else {Ok(child.get_min_held(guards)?.map(|x| list.restore(i, x)))}
+                }).find_map(Result::transpose).transpose()
             }
-            MapInternal::Empty => None
         }
     }
 
     pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
-        let (result, found_left, found_right) = self.get_or_closest_by_key_internal(key, include_key, 0, None);
-        if !loop_around || (found_left == found_right) {result}
+        self.query(|guards| self.get_or_closest_by_key_loop_held(key, include_key, loop_around, guards))
+    }
+
+    //with loop_around the entries at the far ends of the map are closer to key from the other side
+    fn get_or_closest_by_key_loop_held<'a>(&'a self, key: [u8; N], include_key: bool, loop_around: bool, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        let (result, found_left, found_right) = self.get_or_closest_by_key_held(key, include_key, guards)?;
+        Ok(if !loop_around || (found_left == found_right) {result}
         else if !found_left{
-            [result, self.get_max()].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
+            [result, self.get_max_held(guards)?].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
         }
         else{
-            [result, self.get_min()].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
-        }
+            [result, self.get_min_held(guards)?].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
+        })
     }
 
-    fn get_or_closest_by_key_internal(&self, key: [u8; N], include_key: bool, depth: usize, closest: Option<([u8; N], V)>) -> (Option<([u8; N], V)>, bool, bool){
+    fn get_or_closest_by_key_held<'a>(&'a self, key: [u8; N], include_key: bool, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Closest<N, V>, Conflict>{
         //go down to where key would be
         //if key is there and include key is true, return
         //if above is false then go up and down the right hand side of left index in list and left hand side of right index
         //if no left or right index then need to go up until there is one
-        match &*self.0.read(){
-            MapInternal::Item(item_key_value) => {
-                (if (item_key_value.0 != key) || include_key {Some((item_key_value.0, item_key_value.1))} else {None}, false, false)
+        let guard = self.read_held(guards)?;
+        let list = match &*guard{
+            MapInternal::Bucket(bucket) => {
+                //the bucket is on key's path so key has the bytes it skipped
+                let (exact, position) = match bucket.search(key){
+                    Ok(position) => (Some(bucket.entry(position, key)), position),
+                    Err(position) => (None, position)
+                };
+                let left = position.checked_sub(1).map(|x| bucket.entry(x, key));
+                let right = Some(position + (exact.is_some() as usize)).filter(|x| *x < bucket.len()).map(|x| bucket.entry(x, key));
+                let min = [
+                    if include_key {exact} else {None},
+                    left,
+                    right
+                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
+                guards.push(guard);
+                return Ok((min, left.is_some(), right.is_some()))
             }
-            MapInternal::List(list) => {
-                let index = get_index(key, depth);
-                let (mut min, mut left, mut right) = list[index].get_or_closest_by_key_internal(key, include_key, depth + 1, closest);
-                if !left && (index > 0){
-                    for i in (0..(index-1)).rev(){
-                        match list[i].get_max(){
-                            None => (),
-                            Some(left_item_key_value) => {
-                                left = true;
-                                min = [
-                                    min,
-                                    Some(left_item_key_value)
-                                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
-                                break;
-                            }
-                        }
-                    }
-                }
-                if !right && ((index+1) < list.len()){
-                    for i in (index+1)..list.len(){
-                        match list[i].get_min(){
-                            None => (),
-                            Some(right_item_key_value) => {
-                                right = true;
-                                min = [
-                                    min,
-                                    Some(right_item_key_value)
-                                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
-                                break;
-                            }
-                        }
-                    }
-                }
-                (min, left, right)
+            MapInternal::List(list) => unsafe{Self::borrow_list(list)},
+            MapInternal::Empty => {
+                guards.push(guard);
+                return Ok((None, false, false))
+            }
+        };
+        guards.push(guard);
+        if let Some(split_depth) = get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+            //key is outside the skipped prefix so the whole list is to one side of it
+            return if get_index::<N, BITS>(key, split_depth) < get_index::<N, BITS>(list.prefix, split_depth){
+                let min = list.get_min(guards)?;
+                Ok((min, false, min.is_some()))
+            }
+            else{
+                let max = list.get_max(guards)?;
+                Ok((max, max.is_some(), false))
+            }
+        }
+        let index = get_index::<N, BITS>(key, list.depth);
+        let (mut min, mut left, mut right) = match list.children.get(index){
+            Some(child) => child.get_or_closest_by_key_held(key, include_key, guards)?,
+            None => (None, false, false)
+        };
+        if !left{
+            let left_item = list.children.iter().rev().skip_while(|x| x.0 >= index)
+                .map(|x| Ok(x.1.get_max_held(guards)?.map(|y| list.restore(x.0, y)))).find_map(Result::transpose).transpose()?;
+            if let Some(left_item_key_value) = left_item{
+                left = true;
+                min = [
+                    min,
+                    Some(left_item_key_value)
+                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
+            }
+        }
+        if !right{
+            let right_item = list.children.iter().skip_while(|x| x.0 <= index)
+                .map(|x| Ok(x.1.get_min_held(guards)?.map(|y| list.restore(x.0, y)))).find_map(Result::transpose).transpose()?;
+            if let Some(right_item_key_value) = right_item{
+                right = true;
+                min = [
+                    min,
+                    Some(right_item_key_value)
+                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
             }
-            MapInternal::Empty => (None, false, false)
         }
+        Ok((min, left, right))
     }
 
     const HALF_POINT: [u8; N] = {
@@ -192,19 +451,156 @@ impl<const N: usize, V: Copy> Map<N, V>{
     }
 
     pub fn get_min(&self) -> Option<([u8; N], V)>{
-        match &*self.0.read(){
-            MapInternal::Item(item_key_value) => Some((item_key_value.0, item_key_value.1)),
-            MapInternal::List(list) => list.iter().find_map(|x| x.get_min()),
+        self.query(|guards| self.get_min_held(guards))
+    }
+
+    pub fn get_max(&self) -> Option<([u8; N], V)>{
+        self.query(|guards| self.get_max_held(guards))
+    }
+
+    fn get_min_held<'a>(&'a self, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        let guard = self.read_held(guards)?;
+        let min = match &*guard{
+            MapInternal::Bucket(bucket) => bucket.iter([0; N]).next(),
+            MapInternal::List(list) => {
+                let list = unsafe{Self::borrow_list(list)};
+                guards.push(guard);
+                return list.get_min(guards)
+            }
             MapInternal::Empty => None
+        };
+        guards.push(guard);
+        Ok(min)
+    }
+
+    fn get_max_held<'a>(&'a self, guards: &mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Option<([u8; N], V)>, Conflict>{
+        let guard = self.read_held(guards)?;
+        let max = match &*guard{
+            MapInternal::Bucket(bucket) => bucket.iter([0; N]).next_back(),
+            MapInternal::List(list) => {
+                let list = unsafe{Self::borrow_list(list)};
+                guards.push(guard);
+                return list.get_max(guards)
+            }
+            MapInternal::Empty => None
+        };
+        guards.push(guard);
+        Ok(max)
+    }
+
+    //a query that looks down more than one path keeps every map it looked at read locked until it is done, so what it
+    //returns is what the map held at one moment rather than pieced together from before and after other threads' writes.
+    //those locks aren't taken in key order, so rather than wait for one a writer has, which could deadlock against a
+    //transaction or pop holding locks it took in order, the query lets go of them all and starts again
+    #[cfg(not(feature = "optimistic"))]
+    fn query<'a, T>(&'a self, f: impl Fn(&mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<T, Conflict>) -> T{
+        let mut backoff = Backoff::new();
+        loop{
+            let mut guards = Vec::new();
+            let result = f(&mut guards);
+            //children first as in to_non_concurrent
+            guards.into_iter().rev().for_each(drop);
+            if let Ok(result) = result {return result}
+            backoff.snooze();
         }
     }
 
-    pub fn get_max(&self) -> Option<([u8; N], V)>{
+    //with optimistic reads a query reads without locking and then checks nothing it read has changed, starting again if
+    //something has, so it is still what the map held at one moment but neither waits for writers nor holds them up
+    #[cfg(feature = "optimistic")]
+    fn query<'a, T>(&'a self, f: impl Fn(&mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<T, Conflict>) -> T{
+        self.query_at(version::LATEST, f)
+    }
+
+    //reading the versions at the generation at, a snapshot's reads never change so it never starts again
+    #[cfg(feature = "optimistic")]
+    fn query_at<'a, T>(&'a self, at: usize, f: impl Fn(&mut Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<T, Conflict>) -> T{
+        let mut backoff = Backoff::new();
+        loop{
+            let mut guards = Reads::new(at);
+            if let Ok(result) = f(&mut guards){
+                if guards.validate() {return result}
+            }
+            backoff.snooze();
+        }
+    }
+
+    //waits for the first lock a query takes, when it holds none, and only tries the rest
+    #[cfg(not(feature = "optimistic"))]
+    fn read_held<'a>(&'a self, guards: &Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>, Conflict>{
+        if guards.is_empty() {Ok(self.0.read())} else {self.0.try_read().ok_or(Conflict)}
+    }
+
+    #[cfg(feature = "optimistic")]
+    fn read_held<'a>(&'a self, guards: &Guards<'a, N, V, BITS, B, COMPACT, ARENA>) -> Result<Peek<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>, Conflict>{
+        Ok(guards.peek(&self.0))
+    }
+
+    //calls f with every entry in key order, holding read locks on the way down to each bucket so f mustn't use the map
+    #[cfg(not(feature = "optimistic"))]
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        self.for_each_internal(&mut f, [0; N]);
+    }
+
+    //prefix fills in the key bytes a compact bucket here leaves out
+    #[cfg(not(feature = "optimistic"))]
+    fn for_each_internal(&self, f: &mut impl FnMut([u8; N], V), prefix: [u8; N]){
         match &*self.0.read(){
-            MapInternal::Item(item_key_value) => Some((item_key_value.0, item_key_value.1)),
-            MapInternal::List(list) => list.iter().rev().find_map(|x| x.get_max()),
-            MapInternal::Empty => None
+            MapInternal::Bucket(bucket) => bucket.iter(prefix).for_each(|x| f(x.0, x.1)),
+            MapInternal::List(list) => list.children.iter().for_each(|x| x.1.for_each_internal(f, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, x.0))),
+            MapInternal::Empty => ()
+        }
+    }
+
+    //without taking any locks, each bucket as it was when the walk got to it or as it was when a list above it that the
+    //walk went through was replaced, so an entry there for the whole walk is seen once
+    #[cfg(feature = "optimistic")]
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        self.for_each_optimistic(&mut f, [0; N], &View::latest());
+    }
+
+    #[cfg(feature = "optimistic")]
+    fn for_each_optimistic(&self, f: &mut impl FnMut([u8; N], V), prefix: [u8; N], view: &View){
+        match &*self.0.peek(view){
+            MapInternal::Bucket(bucket) => bucket.iter(prefix).for_each(|x| f(x.0, x.1)),
+            MapInternal::List(list) => list.children.iter().for_each(|x| x.1.for_each_optimistic(f, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, x.0), view)),
+            MapInternal::Empty => ()
+        }
+    }
+
+    //a copy of the map as it was at one moment. every node is read locked on the way down and the locks are only let
+    //go of once the walk is done, so each write either finished before the walk got to its node or waits for the walk.
+    //writers to parts already walked wait while the entries are copied out but not while the copy is built
+    pub fn to_non_concurrent(&self) -> non_concurrent::Map<N, V, BITS, B, COMPACT, ARENA>{
+        let (mut guards, mut entries) = (Vec::new(), Vec::new());
+        self.copy_internal(&mut guards, &mut entries, [0; N]);
+        //children first, as a list that is let go of first could be collapsed by a remove taking its children unlocked
+        guards.into_iter().rev().for_each(drop);
+        let mut map = non_concurrent::Map::new();
+        entries.into_iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        map
+    }
+
+    //a read only view of the map as it is now, which writers neither wait for nor change, see Snapshot
+    #[cfg(feature = "optimistic")]
+    pub fn snapshot_handle(&self) -> Snapshot<'_, N, V, BITS, B, COMPACT, ARENA, COUNTED>{
+        Snapshot{map: self, held: version::Snapshot::new()}
+    }
+
+    //prefix fills in the key bytes a compact bucket here leaves out
+    fn copy_internal<'a>(&'a self, guards: &mut Vec<ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>>, entries: &mut Vec<([u8; N], V)>, prefix: [u8; N]){
+        let guard = self.0.read();
+        match &*guard{
+            MapInternal::Bucket(bucket) => entries.extend(bucket.iter(prefix)),
+            MapInternal::List(list) => {
+                //the guard is held until to_non_concurrent lets go of every guard
+                let list = unsafe{Self::borrow_list(list)};
+                guards.push(guard);
+                return list.children.iter().for_each(|x| x.1.copy_internal(guards, entries, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, x.0)))
+            }
+            MapInternal::Empty => ()
         }
+        guards.push(guard);
     }
 
     pub fn insert_or_update(&self, key: [u8; N], value: V) -> InsertOrUpdateResult{
@@ -212,54 +608,159 @@ impl<const N: usize, V: Copy> Map<N, V>{
     }
 
     pub fn insert_or_update_if(&self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool) -> InsertOrUpdateResult{
-        self.insert_or_update_if_internal(key, value, should_update, 0)
+        let result = self.insert_or_update_if_internal(key, value, should_update, 0);
+        if result == InsertOrUpdateResult::Inserted {<Count<COUNTED> as Counting>::add(&self.1, 1)}
+        result
     }
 
-    fn insert_or_update_if_internal(&self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, depth: usize) -> InsertOrUpdateResult{
+    //skip is how many leading key bytes a compact bucket here can leave out
+    fn insert_or_update_if_internal(&self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, skip: usize) -> InsertOrUpdateResult{
         loop{
             match &*self.0.read(){
-                MapInternal::Item(_) => (), //change to write_lock
-                MapInternal::List(list) => return list[get_index(key, depth)].insert_or_update_if_internal(key, value, should_update, depth + 1),
+                MapInternal::Bucket(_) => (), //change to write_lock
+                MapInternal::List(list) => {
+                    if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_none(){
+                        if let Some(child) = list.children.get(get_index::<N, BITS>(key, list.depth)){
+                            return child.insert_or_update_if_internal(key, value, should_update, list.get_child_skip())
+                        }
+                    }
+                    //change to write lock to add a child or a list above this one
+                }
                 MapInternal::Empty => () //change to write lock
             }
-            let mut write_lock = self.0.write();
-            match &mut *write_lock{
-                MapInternal::Item(item_key_value) => {
-                    return if item_key_value.0 == key{
+            //or change back to read lock if a child for key was added in between
+            if let Some(result) = Self::insert_locked(&mut self.0.write(), key, value, should_update, skip) {return result}
+        }
+    }
+
+    //the write locked part of an insert, None if key belongs in a child of a list here
+    fn insert_locked(write_lock: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, skip: usize) -> Option<InsertOrUpdateResult>{
+        match write_lock{
+            MapInternal::Bucket(bucket) => {
+                Some(match bucket.search(key){
+                    Ok(position) => {
                         //update
-                        if should_update(&item_key_value.1, &value){
-                            item_key_value.1 = value;
+                        if should_update(bucket.value(position), &value){
+                            bucket.update(position, value);
                             InsertOrUpdateResult::Updated
                         }
                         else {InsertOrUpdateResult::Neither}
                     }
-                    else{
-                        //insert and restructure
-                        *write_lock = Self::deepen_tree((item_key_value.0, item_key_value.1), (key, value), depth);
+                    Err(position) => {
+                        //insert, restructuring into a list if the bucket is full
+                        if bucket.len() < B {bucket.insert(position, (key, value))}
+                        else {*write_lock = Self::split_bucket(bucket, position, (key, value))}
                         InsertOrUpdateResult::Inserted
                     }
+                })
+            }
+            MapInternal::List(list) => {
+                let list = &mut **list; //a plain reference so its fields can be borrowed separately
+                if let Some(split_depth) = get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+                    //key leaves the skipped prefix so a new list goes in above this one
+                    let prefix = list.prefix;
+                    Self::split(write_lock, prefix, key, value, split_depth);
+                    return Some(InsertOrUpdateResult::Inserted)
                 }
-                MapInternal::List(_) => (), //change back to read lock
-                MapInternal::Empty => {
-                    *write_lock = MapInternal::new_item(key, value);
-                    return InsertOrUpdateResult::Inserted
-                }
+                let index = get_index::<N, BITS>(key, list.depth);
+                if list.children.get(index).is_some() {return None}
+                list.children.insert(index, Self::new_child(Self::new_item(list.get_child_skip(), key, value)));
+                Some(InsertOrUpdateResult::Inserted)
+            }
+            MapInternal::Empty => {
+                *write_lock = Self::new_item(skip, key, value);
+                Some(InsertOrUpdateResult::Inserted)
             }
         }
     }
 
-    fn deepen_tree(item_1: ([u8; N], V), item_2: ([u8; N], V), depth: usize) -> MapInternal<N, V> {
-        let item_1_index = get_index(item_1.0, depth);
-        let item_2_index = get_index(item_2.0, depth);
-        let new_list = [const {Self::new()}; 4];
-        if item_1_index == item_2_index {
-            *new_list[item_1_index].0.write() = Self::deepen_tree(item_1, item_2, depth + 1);
+    fn new_item(skip: usize, key: [u8; N], value: V) -> MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        MapInternal::Bucket(Bucket::new(skip, &[(key, value)]))
+    }
+
+    //list at the first digit the entries differ at with a bucket for each digit
+    fn split_bucket(bucket: &Bucket<N, V, B, COMPACT, ARENA>, position: usize, entry: ([u8; N], V)) -> MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        let (depth, groups) = bucket.split::<BITS>(position, entry);
+        let children = Nodes::from_children(groups.into_iter().map(|(digit, x)| (digit, Self::new_child(MapInternal::Bucket(x)))).collect());
+        MapInternal::List(NodeBox::new(List{depth, prefix: get_prefix::<N, BITS>(entry.0, depth), children, sharded: false}))
+    }
+
+    //a list whose children are all buckets with no more than B entries between them can go back to being a bucket
+    fn merge_list(list: &List<N, V, BITS, B, COMPACT, ARENA>, skip: usize) -> Option<Bucket<N, V, B, COMPACT, ARENA>>{
+        let mut entries = Vec::new();
+        for (digit, child) in list.children.iter(){
+            match &*child.0.read(){
+                MapInternal::Bucket(bucket) if (entries.len() + bucket.len()) <= B => entries.extend(bucket.iter(get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit))),
+                MapInternal::Empty => (),
+                _ => return None
+            }
         }
-        else{
-            *new_list[item_1_index].0.write() = MapInternal::new_item(item_1.0, item_1.1);
-            *new_list[item_2_index].0.write() = MapInternal::new_item(item_2.0, item_2.1);
+        Some(Bucket::new(skip, &entries))
+    }
+
+    //replaces the write locked contents with a list at split_depth holding what was there and the new item
+    fn split(write_lock: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, existing_key: [u8; N], key: [u8; N], value: V, split_depth: usize){
+        let children = Nodes::new_pair(
+            get_index::<N, BITS>(existing_key, split_depth), Self::new_child(std::mem::replace(write_lock, MapInternal::Empty)),
+            get_index::<N, BITS>(key, split_depth), Self::new_child(Self::new_item(Bucket::<N, V, B, COMPACT, ARENA>::skip_in_list::<BITS>(split_depth), key, value))
+        );
+        *write_lock = MapInternal::List(NodeBox::new(List{depth: split_depth, prefix: get_prefix::<N, BITS>(key, split_depth), children, sharded: false}));
+    }
+
+    //the value that was at key, replacing it with value or inserting it
+    pub fn swap(&self, key: [u8; N], value: V) -> Option<V>{
+        let old = Cell::new(None);
+        self.insert_or_update_if(key, value, &|x, _| {
+            old.set(Some(*x));
+            true
+        });
+        old.get()
+    }
+
+    //like AtomicU64::compare_exchange with None for no entry, if what is at key is expected it becomes new (inserted,
+    //updated or removed) and Ok(expected) is returned, otherwise it is left and Err of what is there is returned
+    pub fn compare_exchange(&self, key: [u8; N], expected: Option<V>, new: Option<V>) -> Result<Option<V>, Option<V>> where V: PartialEq{
+        let current = Cell::new(None);
+        let exchanged = match (expected, new){
+            (None, None) => {
+                current.set(self.get(key));
+                current.get().is_none()
+            }
+            (None, Some(new)) => self.insert_or_update_if(key, new, &|x, _| {
+                current.set(Some(*x));
+                false
+            }) == InsertOrUpdateResult::Inserted,
+            (Some(expected), Some(new)) => {
+                current.set(self.update_if(key, new, &|x| *x == expected));
+                current.get() == Some(expected)
+            }
+            (Some(expected), None) => self.remove_if(key, &|x| {
+                current.set(Some(*x));
+                *x == expected
+            })
+        };
+        if exchanged {Ok(expected)} else {Err(current.get())}
+    }
+
+    //updates key if it is there and should_update says to, without inserting it if it isn't, returning what was there
+    pub(crate) fn update_if(&self, key: [u8; N], value: V, should_update: &impl Fn(&V) -> bool) -> Option<V>{
+        loop{
+            match &*self.0.read(){
+                MapInternal::Bucket(bucket) => if bucket.search(key).is_err() {return None}, //change to write lock
+                MapInternal::List(list) => {
+                    if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return None}
+                    return list.children.get(get_index::<N, BITS>(key, list.depth)).and_then(|x| x.update_if(key, value, should_update))
+                }
+                MapInternal::Empty => return None
+            }
+            if let MapInternal::Bucket(bucket) = &mut *self.0.write(){
+                let Ok(position) = bucket.search(key) else {return None};
+                let old = *bucket.value(position);
+                if should_update(&old) {bucket.update(position, value)}
+                return Some(old)
+            }
+            //the bucket was split into a list or emptied, so go round again
         }
-        MapInternal::List(Box::new(new_list))
     }
 
     pub fn remove(&self, key: [u8; N]){
@@ -267,49 +768,551 @@ impl<const N: usize, V: Copy> Map<N, V>{
     }
 
     pub fn remove_if(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> bool{
-        self.remove_if_internal(key, should_remove, 0).0
+        let removed = self.remove_if_internal(key, should_remove, 0);
+        if removed {<Count<COUNTED> as Counting>::add(&self.1, -1)}
+        removed
+    }
+
+    //skip is how many leading key bytes a compact bucket here can leave out
+    //read locks on the way down, only write locking the bucket holding key and lists that are left able to shrink, so
+    //removes don't meet at the top of the map and block the readers there
+    fn remove_if_internal(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool, skip: usize) -> bool{
+        loop{
+            //whether a child was removed from, with the list needing to shrink, or None for a bucket holding key
+            let removed = match &*self.0.read(){
+                MapInternal::Bucket(bucket) => {
+                    if bucket.search(key).is_err() {return false}
+                    None
+                }
+                MapInternal::List(list) => {
+                    if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return false}
+                    let Some(child) = list.children.get(get_index::<N, BITS>(key, list.depth)) else {return false};
+                    let removed = child.remove_if_internal(key, should_remove, list.get_child_skip());
+                    //a shard is never collapsed so doesn't need write locking
+                    if !removed || list.sharded || !Self::can_shrink(list) {return removed}
+                    Some(removed)
+                }
+                MapInternal::Empty => return false
+            };
+            //what was read may have changed before the write lock so it is checked again
+            let mut write_lock = self.0.write();
+            match (&mut *write_lock, removed){
+                (MapInternal::Bucket(bucket), None) => {
+                    return match bucket.search(key){
+                        Ok(position) if should_remove(bucket.value(position)) => {
+                            if bucket.len() == 1 {*write_lock = MapInternal::Empty} else {bucket.remove(position)}
+                            true
+                        }
+                        _ => false
+                    }
+                }
+                (MapInternal::List(_), Some(removed)) => {
+                    Self::shrink(&mut write_lock, skip);
+                    return removed
+                }
+                //the list was collapsed by another remove, which did what this one would have
+                (_, Some(removed)) => return removed,
+                //the bucket was split into a list or emptied, so go round again
+                (_, None) => ()
+            }
+        }
+    }
+
+    //a write locked list with vacant slots, one child or none, or few enough entries to be a bucket is rebuilt smaller,
+    //replaced by its child or merged
+    fn shrink(write_lock: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, skip: usize){
+        let MapInternal::List(list) = write_lock else {return};
+        let list = &mut **list; //a plain reference so its fields can be borrowed separately
+        list.children.remove_vacant();
+        //a list left with one child is replaced by that child, whether it is an item or another list
+        //nothing can be below a write locked list so the children are taken without locking them
+        if list.children.len() <= 1{
+            *write_lock = match list.children.iter().find(|x| !x.1.is_vacant()).map(|x| x.0){
+                Some(digit) => match std::mem::replace(&mut *list.children.get_mut(digit).unwrap().0.get_mut(), MapInternal::Empty){
+                    //a bucket moving up has fewer bytes implied by its position
+                    MapInternal::Bucket(bucket) if bucket.skip() > skip => {
+                        MapInternal::Bucket(bucket.with_skip(skip, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit)))
+                    }
+                    child => child
+                },
+                None => MapInternal::Empty
+            };
+        }
+        else if let Some(bucket) = Self::merge_list(list, skip){
+            *write_lock = MapInternal::Bucket(bucket);
+        }
+    }
+
+    pub fn pop_min(&self) -> Option<([u8; N], V)>{
+        self.pop_if(false, &|_, _| true)
+    }
+
+    pub fn pop_max(&self) -> Option<([u8; N], V)>{
+        self.pop_if(true, &|_, _| true)
     }
 
-    fn remove_if_internal(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool, depth: usize) -> (bool, bool){ //(removed, at bottom)
+    //removes and returns the entry with the smallest key if should_pop says to, so of threads popping at once each
+    //entry goes to one of them
+    pub fn pop_min_if(&self, should_pop: &impl Fn(&[u8; N], &V) -> bool) -> Option<([u8; N], V)>{
+        self.pop_if(false, should_pop)
+    }
+
+    pub fn pop_max_if(&self, should_pop: &impl Fn(&[u8; N], &V) -> bool) -> Option<([u8; N], V)>{
+        self.pop_if(true, should_pop)
+    }
 
-        let result = match &*self.0.read(){
-            MapInternal::Item(_) => (false, true),
-            MapInternal::List(list) => list[get_index(key, depth)].remove_if_internal(key, should_remove, depth + 1),
-            MapInternal::Empty => (false, true)
+    fn pop_if(&self, max: bool, should_pop: &impl Fn(&[u8; N], &V) -> bool) -> Option<([u8; N], V)>{
+        let popped = loop{
+            let (mut guards, mut passed) = (Vec::new(), Vec::new());
+            let popped = self.pop_internal(max, should_pop, &mut guards, &mut passed, [0; N]);
+            //children first as in to_non_concurrent
+            guards.into_iter().rev().for_each(drop);
+            match popped{
+                Pop::Nothing | Pop::Declined => return None,
+                Pop::Popped(entry) => break entry,
+                Pop::Retry => ()
+            }
         };
-        let mut write_lock = self.0.write();
-        match &mut *write_lock{
-            MapInternal::Item(item_key_value) => {
-                if result.1 && (item_key_value.0 == key) && should_remove(&item_key_value.1) {
-                    *write_lock = MapInternal::Empty;
-                    return (true, false)
+        self.shrink_path(popped.0, 0);
+        <Count<COUNTED> as Counting>::add(&self.1, -1);
+        Some(popped)
+    }
+
+    //the lists on the way down stay read locked in guards until pop_if lets go of them. locks are taken in key order
+    //like a transaction's, so for pop_min the empty maps passed over on the way to the edge are kept read locked as they
+    //are passed. for pop_max they come after the edge so are let go of, kept in passed, and read locked again once the
+    //bucket at the edge is write locked, the pop going round again if anything was put in them in between
+    fn pop_internal<'a>(&'a self, max: bool, should_pop: &impl Fn(&[u8; N], &V) -> bool, guards: &mut Vec<ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>>, passed: &mut Vec<&'a Map<N, V, BITS, B, COMPACT, ARENA>>, prefix: [u8; N]) -> Pop<N, V>{
+        loop{
+            let guard = self.0.read();
+            match &*guard{
+                MapInternal::Bucket(_) => (), //change to write lock
+                MapInternal::List(list) => {
+                    let list = unsafe{Self::borrow_list(list)};
+                    guards.push(guard);
+                    let mut children = list.children.iter();
+                    while let Some((digit, child)) = if max {children.next_back()} else {children.next()}{
+                        let (held, skipped) = (guards.len(), passed.len());
+                        match child.pop_internal(max, should_pop, guards, passed, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit)){
+                            Pop::Nothing if max => {
+                                while guards.len() > held {guards.pop();}
+                                passed.truncate(skipped);
+                                passed.push(child);
+                            }
+                            Pop::Nothing => (),
+                            popped => return popped
+                        }
+                    }
+                    return Pop::Nothing
+                }
+                MapInternal::Empty => {
+                    guards.push(guard);
+                    return Pop::Nothing
                 }
             }
+            drop(guard);
+            let mut write_lock = self.0.write();
+            if let MapInternal::Bucket(bucket) = &mut *write_lock{
+                //the maps passed over nearest the bucket come first in key order
+                if max && !passed.iter().rev().all(|x| x.hold_empty(guards)) {return Pop::Retry}
+                let position = if max {bucket.len() - 1} else {0};
+                let entry = bucket.entry(position, prefix);
+                if !should_pop(&entry.0, &entry.1) {return Pop::Declined}
+                if bucket.len() == 1 {*write_lock = MapInternal::Empty} else {bucket.remove(position)}
+                return Pop::Popped(entry)
+            }
+            //the bucket was split into a list or emptied, so go round again
+        }
+    }
+
+    //read locks everything below here in key order, keeping the locks in guards, and returns whether it is all empty
+    fn hold_empty<'a>(&'a self, guards: &mut Vec<ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>>) -> bool{
+        let guard = self.0.read();
+        match &*guard{
+            MapInternal::Bucket(_) => false,
             MapInternal::List(list) => {
-                let mut item_count = 0;
-                if list.iter().all(|x| {
-                    match &*x.0.read(){
-                        MapInternal::Item(_) => {
-                            item_count += 1;
-                            true
-                        },
-                        MapInternal::List(_) => false,
-                        MapInternal::Empty => true
+                let list = unsafe{Self::borrow_list(list)};
+                guards.push(guard);
+                list.children.iter().all(|x| x.1.hold_empty(guards))
+            }
+            MapInternal::Empty => {
+                guards.push(guard);
+                true
+            }
+        }
+    }
+
+    //runs f on a transaction reading and writing the map, and if it returns Ok makes all of its writes at once or none
+    //of them if it returns Err or panics. f runs without locks, reading through to the map, then the buckets (or the
+    //lists to add to) holding every key it used are write locked in key order, the way down read locked and held as
+    //to_non_concurrent does. if a key read no longer has the value f saw, the locks are let go of and f is run again,
+    //so f can be run more than once and mustn't use the map other than through the transaction
+    pub fn transaction<T, E>(&self, mut f: impl FnMut(&mut Transaction<'_, N, V, BITS, B, COMPACT, ARENA, COUNTED>) -> Result<T, E>) -> Result<T, E> where V: PartialEq{
+        loop{
+            let mut transaction = Transaction{map: self, reads: BTreeMap::new(), writes: BTreeMap::new()};
+            let result = f(&mut transaction);
+            let mut keys: Vec<[u8; N]> = transaction.reads.keys().chain(transaction.writes.keys()).copied().collect();
+            keys.sort_unstable();
+            keys.dedup();
+            if keys.is_empty() {return result}
+            let (mut locks, mut leaves) = (Locks(Vec::new()), Vec::new());
+            self.lock_keys(&keys, 0, &mut locks.0, &mut leaves);
+            let leaf = |key| leaves[keys.binary_search(&key).unwrap()];
+            if !transaction.reads.iter().all(|(key, value)| Self::get_in(locks.0[leaf(*key)].get(), *key) == *value) {continue}
+            let value = result?;
+            let (mut change, mut removed) = (0, Vec::new());
+            for (key, value) in transaction.writes{
+                let Lock::Write(write_lock, skip) = &mut locks.0[leaf(key)] else {unreachable!()};
+                match value{
+                    Some(value) => change += (Self::insert_under(write_lock, key, value, *skip) == InsertOrUpdateResult::Inserted) as isize,
+                    None => if Self::remove_under(write_lock, key) {
+                        change -= 1;
+                        removed.push(key);
                     }
-                }) && (item_count <= 1){
-                    *write_lock = list.iter().find_map(|x| {
-                        match &*x.0.read() {
-                            MapInternal::Item(item_key_value) => Some(MapInternal::new_item(item_key_value.0, item_key_value.1)),
-                            MapInternal::List(_) => None,
-                            MapInternal::Empty => None
-                        }
-                    }).unwrap_or(MapInternal::Empty)
+                }
+            }
+            drop(locks);
+            //lists left able to shrink by the removes, the lowest of which may have been write locked, shrink on a
+            //second way down as a remove's do on its way back up
+            removed.into_iter().for_each(|x| self.shrink_path(x, 0));
+            <Count<COUNTED> as Counting>::add(&self.1, change);
+            return Ok(value)
+        }
+    }
+
+    //keys are sorted and all below here. a list is read locked and gone down through if every key has a child in it,
+    //otherwise this map is write locked for all of them, leaves gets the index of the lock each key is under
+    fn lock_keys<'a>(&'a self, keys: &[[u8; N]], skip: usize, locks: &mut Vec<Lock<'a, N, V, BITS, B, COMPACT, ARENA>>, leaves: &mut Vec<usize>){
+        let guard = self.0.read();
+        if let MapInternal::List(list) = &*guard{
+            if keys.iter().all(|x| get_split_depth::<N, BITS>(*x, list.prefix, list.depth).is_none() && list.children.get(get_index::<N, BITS>(*x, list.depth)).is_some()){
+                //the guard is held until the transaction lets go of every lock
+                let list = unsafe{Self::borrow_list(list)};
+                locks.push(Lock::Read(guard));
+                return keys.chunk_by(|x, y| get_index::<N, BITS>(*x, list.depth) == get_index::<N, BITS>(*y, list.depth)).for_each(|x| {
+                    list.children.get(get_index::<N, BITS>(x[0], list.depth)).unwrap().lock_keys(x, list.get_child_skip(), locks, leaves)
+                })
+            }
+        }
+        drop(guard);
+        leaves.extend(std::iter::repeat_n(locks.len(), keys.len()));
+        locks.push(Lock::Write(self.0.write(), skip));
+    }
+
+    //an insert into a write locked map, which nothing else can be below so its children are locked as usual
+    fn insert_under(write_lock: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, key: [u8; N], value: V, skip: usize) -> InsertOrUpdateResult{
+        if let Some(result) = Self::insert_locked(write_lock, key, value, &|_, _| true, skip) {return result}
+        let MapInternal::List(list) = write_lock else {unreachable!()};
+        list.children.get(get_index::<N, BITS>(key, list.depth)).unwrap().insert_or_update_if_internal(key, value, &|_, _| true, list.get_child_skip())
+    }
+
+    //a remove from a write locked map, leaving any list below it that can shrink for shrink_path
+    fn remove_under(write_lock: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, key: [u8; N]) -> bool{
+        match write_lock{
+            MapInternal::Bucket(bucket) => {
+                let Ok(position) = bucket.search(key) else {return false};
+                if bucket.len() == 1 {*write_lock = MapInternal::Empty} else {bucket.remove(position)}
+                true
+            }
+            MapInternal::List(list) => {
+                if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return false}
+                list.children.get(get_index::<N, BITS>(key, list.depth)).is_some_and(|x| x.remove_if_internal(key, &|_| true, list.get_child_skip()))
+            }
+            MapInternal::Empty => false
+        }
+    }
+
+    //shrinks the lists on the way to key that can, lowest first
+    fn shrink_path(&self, key: [u8; N], skip: usize){
+        let shrinkable = match &*self.0.read(){
+            MapInternal::List(list) => {
+                if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_none(){
+                    if let Some(child) = list.children.get(get_index::<N, BITS>(key, list.depth)) {child.shrink_path(key, list.get_child_skip())}
+                }
+                !list.sharded && Self::can_shrink(list)
+            }
+            _ => false
+        };
+        //shrinking a list another thread has changed since is still right, it only rebuilds what is there
+        if shrinkable {Self::shrink(&mut self.0.write(), skip)}
+    }
+
+    //counts of what is in the map, its memory size and how deep its buckets are, in one walk
+    pub fn stats(&self) -> MapStats{
+        let mut stats = MapStats{memory: MemoryUsage{slots: size_of::<Self>(), ..MemoryUsage::default()}, ..MapStats::default()};
+        let mut entry_depths = 0;
+        stats.depth = self.stats_internal(&mut stats, &mut entry_depths, 1);
+        stats.average_depth = if stats.len == 0 {0.0} else {(entry_depths as f64) / (stats.len as f64)};
+        stats
+    }
+
+    //depth is this slot's, returns the same as depth() here. entry_depths adds up the depth of every entry's bucket
+    fn stats_internal(&self, stats: &mut MapStats, entry_depths: &mut usize, depth: usize) -> usize{
+        let internal = self.0.read();
+        Self::add_node_memory_usage(&internal, &mut stats.memory);
+        match &*internal{
+            MapInternal::Bucket(bucket) => {
+                stats.buckets += 1;
+                stats.len += bucket.len();
+                *entry_depths += depth * bucket.len();
+                if stats.bucket_depths.len() <= depth {stats.bucket_depths.resize(depth + 1, 0)}
+                stats.bucket_depths[depth] += 1;
+                1
+            }
+            MapInternal::List(list) => {
+                stats.lists += 1;
+                let children = list.children.len();
+                if stats.list_occupancy.len() <= children {stats.list_occupancy.resize(children + 1, 0)}
+                stats.list_occupancy[children] += 1;
+                1 + list.children.slots().iter().map(|x| x.stats_internal(stats, entry_depths, depth + 1)).max().unwrap()
+            }
+            MapInternal::Empty => {
+                stats.empty_slots += 1;
+                0
+            }
+        }
+    }
+
+    //checks the map is in the shape inserts and removes leave it in, returning where it first isn't
+    //other threads changing the map while it runs can leave lists briefly out of shape so it is for when they are done
+    pub fn validate(&self) -> Result<(), ValidationError<N>>{
+        let mut path = Vec::new();
+        self.validate_internal(&mut path, [0; N], 0, 0, true).map_err(|violation| ValidationError{path, violation})?;
+        //a list at each digit and a bucket at the bottom
+        let depth = self.depth();
+        if depth > (((N * 8) / BITS) + 1) {return Err(ValidationError{path: Vec::new(), violation: Violation::TooDeep(depth)})}
+        Ok(())
+    }
+
+    //the tree as a graphviz graph, lists labelled with their prefix in hex and how many bits of it are fixed, and
+    //buckets with their keys in hex. only nodes at most max_depth below the root and the first max_nodes are written
+    pub fn to_dot(&self, max_depth: usize, max_nodes: usize) -> String{
+        let mut dump = Dump::new(true, max_depth, max_nodes);
+        self.dump_internal(&mut dump, None, [0; N]);
+        dump.finish()
+    }
+
+    //the same as to_dot as indented text, a line for each node under its parent
+    pub fn dump_tree(&self, max_depth: usize, max_nodes: usize) -> String{
+        let mut dump = Dump::new(false, max_depth, max_nodes);
+        self.dump_internal(&mut dump, None, [0; N]);
+        dump.finish()
+    }
+
+    //prefix fills in the key bytes a compact bucket here leaves out
+    fn dump_internal(&self, dump: &mut Dump, parent: Option<(usize, usize)>, prefix: [u8; N]){
+        match &*self.0.read(){
+            MapInternal::Bucket(bucket) => dump.bucket(parent, bucket.iter(prefix).map(|x| x.0)),
+            MapInternal::List(list) => {
+                let Some(id) = dump.list(parent, &list.prefix, list.depth * BITS, list.children.len(), list.sharded) else {return};
+                for (digit, child) in list.children.iter().filter(|x| !x.1.is_vacant()){
+                    child.dump_internal(dump, Some((id, digit)), get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit));
+                }
+                dump.end(id);
+            }
+            MapInternal::Empty => dump.empty(parent)
+        }
+    }
+
+    //prefix has the bits of the first fixed digits that every key below here shares, skip is how many key bytes a
+    //compact bucket here can leave out and shardable is whether a sharded list can be here. path is left at the first
+    //violation
+    fn validate_internal(&self, path: &mut Vec<usize>, prefix: [u8; N], fixed: usize, skip: usize, shardable: bool) -> Result<(), Violation<N>>{
+        match &*self.0.read(){
+            MapInternal::Bucket(bucket) => {
+                if bucket.skip() > skip {return Err(Violation::SkipTooLong(bucket.skip()))}
+                if bucket.len() > B {return Err(Violation::OverfullBucket(bucket.len()))}
+                if let Some(key) = bucket.iter(prefix).map(|x| x.0).find(|x| get_split_depth::<N, BITS>(*x, prefix, fixed).is_some()) {return Err(Violation::KeyOffPath(key))}
+                if !bucket.iter(prefix).zip(bucket.iter(prefix).skip(1)).all(|(x, y)| x.0 < y.0) {return Err(Violation::UnsortedBucket)}
+            }
+            MapInternal::List(list) => {
+                if (list.depth < fixed) || (list.depth >= ((N * 8) / BITS)) {return Err(Violation::ListDepth(list.depth))}
+                if get_split_depth::<N, BITS>(list.prefix, prefix, fixed).is_some() || (get_prefix::<N, BITS>(list.prefix, list.depth) != list.prefix) {return Err(Violation::PrefixOffPath(list.prefix))}
+                if list.sharded{
+                    if !shardable || (list.children.slots().len() != (1 << BITS)) {return Err(Violation::Shard)}
+                }
+                else{
+                    if list.children.len() <= 1 {return Err(Violation::Uncollapsed)}
+                    if list.children.capacity_without_vacant().is_some() {return Err(Violation::VacantSlots)}
+                    let entries = list.children.iter().try_fold(0, |len, (_, child)| match &*child.0.read(){
+                        MapInternal::Bucket(bucket) => Some(len + bucket.len()),
+                        MapInternal::Empty => Some(len),
+                        MapInternal::List(_) => None
+                    });
+                    if let Some(entries) = entries.filter(|x| *x <= B) {return Err(Violation::Unmerged(entries))}
+                }
+                for (digit, child) in list.children.iter(){
+                    path.push(digit);
+                    child.validate_internal(path, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit), list.depth + 1, list.get_child_skip(), list.sharded)?;
+                    path.pop();
                 }
             }
             MapInternal::Empty => ()
         }
-        (result.0, false)
+        Ok(())
+    }
+
+    //a read locked list borrowed for as long as the map rather than its guard, so the guard can go in a Vec along with
+    //the guards of what is below it. the list mustn't be used once the guard is dropped, until then it stays where it
+    //is as it is on the heap (the guard holds a copy of the pointer to it, so moving the guard doesn't move it) and it is
+    //only changed or freed under a write lock of its map, which can't be taken while the guard is held
+    unsafe fn borrow_list<'a>(list: &List<N, V, BITS, B, COMPACT, ARENA>) -> &'a List<N, V, BITS, B, COMPACT, ARENA>{
+        unsafe{&*(list as *const List<N, V, BITS, B, COMPACT, ARENA>)}
+    }
+
+    //whether a read locked list has vacant slots to drop, one child left or buckets that fit in one
+    fn can_shrink(list: &List<N, V, BITS, B, COMPACT, ARENA>) -> bool{
+        list.children.capacity_without_vacant().is_some() || (list.children.len() <= 1) ||
+            list.children.iter().try_fold(0, |len, (_, child)| match &*child.0.read(){
+                MapInternal::Bucket(bucket) => Some(len + bucket.len()),
+                MapInternal::Empty => Some(len),
+                MapInternal::List(_) => None
+            }).is_some_and(|x| x <= B)
+    }
+}
+
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const COUNTED: bool> Map<N, V, BITS, B, COMPACT, true, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    //gives the nodes freed into the arena back to the global allocator, returning how many bytes that was
+    //the arena is shared by every map with ARENA, and other threads keep a few free nodes each until they exit
+    pub fn shrink_to_fit(&self) -> usize{
+        arena::shrink()
+    }
+}
+
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Map<N, V, BITS, B, COMPACT, ARENA, true> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    //the count kept of entries, which can miss inserts and removes still going on in other threads where len would
+    //wait for them, but doesn't take a lock
+    pub fn len_approx(&self) -> usize{
+        self.1.0.iter().map(|x| x.0.load(Ordering::Relaxed)).sum::<isize>().max(0) as usize
+    }
+}
+
+//the reads and writes of a transaction, writes are only made on the map once the transaction's closure returns Ok
+pub struct Transaction<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    map: &'a Map<N, V, BITS, B, COMPACT, ARENA, COUNTED>,
+    //the value each key had when first read, checked again once the keys are locked
+    reads: BTreeMap<[u8; N], Option<V>>,
+    //None to remove
+    writes: BTreeMap<[u8; N], Option<V>>
+}
+
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> Transaction<'_, N, V, BITS, B, COMPACT, ARENA, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    //the value written earlier in the transaction, or else the one in the map
+    pub fn get(&mut self, key: [u8; N]) -> Option<V>{
+        if let Some(value) = self.writes.get(&key) {return *value}
+        *self.reads.entry(key).or_insert_with(|| self.map.get(key))
+    }
+
+    pub fn insert_or_update(&mut self, key: [u8; N], value: V) -> InsertOrUpdateResult{
+        let result = if self.get(key).is_some() {InsertOrUpdateResult::Updated} else {InsertOrUpdateResult::Inserted};
+        self.writes.insert(key, Some(value));
+        result
+    }
+
+    pub fn remove(&mut self, key: [u8; N]) -> Option<V>{
+        let value = self.get(key);
+        self.writes.insert(key, None);
+        value
+    }
+}
+
+//the map as it was when snapshot_handle was called, taken without copying or locking anything. while any snapshot is
+//held writers leave what they replace in their locks as versions, and a snapshot reads the versions that were there
+//when it was taken, see version. what any thread replaces while a snapshot is held isn't freed until it is dropped
+#[cfg(feature = "optimistic")]
+pub struct Snapshot<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    map: &'a Map<N, V, BITS, B, COMPACT, ARENA, COUNTED>,
+    held: version::Snapshot
+}
+
+#[cfg(feature = "optimistic")]
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool, const COUNTED: bool> Snapshot<'_, N, V, BITS, B, COMPACT, ARENA, COUNTED> where BitsPerLevel<BITS>: SupportedBitsPerLevel, Count<COUNTED>: Counting{
+    pub fn get(&self, key: [u8; N]) -> Option<V>{
+        self.map.get_optimistic(key, &self.held.view())
+    }
+
+    pub fn len(&self) -> usize{
+        self.map.len_optimistic(&self.held.view())
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.get_min().is_none()
+    }
+
+    pub fn get_min(&self) -> Option<([u8; N], V)>{
+        self.map.query_at(self.held.at(), |guards| self.map.get_min_held(guards))
+    }
+
+    pub fn get_max(&self) -> Option<([u8; N], V)>{
+        self.map.query_at(self.held.at(), |guards| self.map.get_max_held(guards))
+    }
+
+    pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
+        self.map.query_at(self.held.at(), |guards| self.map.get_or_closest_by_key_loop_held(key, include_key, loop_around, guards))
+    }
+
+    pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
+        self.map.query_at(self.held.at(), |guards| self.map.get_or_closest_by_key_leading_zeroes_held(key, include_key, guards))
+    }
+
+    //calls f with every entry in key order, f can use the map
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        self.map.for_each_optimistic(&mut f, [0; N], &self.held.view());
+    }
+
+    pub fn to_non_concurrent(&self) -> non_concurrent::Map<N, V, BITS, B, COMPACT, ARENA>{
+        let mut map = non_concurrent::Map::new();
+        self.for_each(|key, value| _ = map.insert_or_update(key, value));
+        map
+    }
+}
+
+//the read locks a query holds, or with optimistic reads the words it read without locking, see query
+#[cfg(not(feature = "optimistic"))]
+type Guards<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> = Vec<ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>>;
+#[cfg(feature = "optimistic")]
+type Guards<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> = Reads<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>;
+
+//a query found a lock it tried to take held or wanted by a writer
+struct Conflict;
+
+//the closest entry found, whether there were any entries to the left of key and to the right of it
+type Closest<const N: usize, V> = (Option<([u8; N], V)>, bool, bool);
+
+//what pop_internal found at the edge of the map
+enum Pop<const N: usize, V>{
+    Nothing,
+    //should_pop said not to
+    Declined,
+    Popped(([u8; N], V)),
+    //a map passed over on the way to the edge had something put in it, so the pop has to start again
+    Retry
+}
+
+enum Lock<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    Read(ReadGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>),
+    //with how many leading key bytes a compact bucket here can leave out
+    Write(WriteGuard<'a, MapInternal<N, V, BITS, B, COMPACT, ARENA>>, usize)
+}
+
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Lock<'_, N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn get(&self) -> &MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        match self{
+            Lock::Read(guard) => guard,
+            Lock::Write(guard, _) => guard
+        }
+    }
+}
+
+//the locks a transaction holds, let go of children first (also if a value's eq panics) as in to_non_concurrent
+struct Locks<'a, const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool>(Vec<Lock<'a, N, V, BITS, B, COMPACT, ARENA>>) where BitsPerLevel<BITS>: SupportedBitsPerLevel;
+
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Drop for Locks<'_, N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn drop(&mut self){
+        #[cfg(feature = "optimistic")]
+        lock::publish_together(self.0.iter_mut().filter_map(|x| match x {Lock::Write(guard, _) => Some(guard), Lock::Read(_) => None}));
+        while self.0.pop().is_some() {}
     }
 }
 
-pub type Set<const N: usize> = Map<N, ()>;
\ No newline at end of file
+pub type Set<const N: usize, const BITS: usize = 2, const B: usize = 1, const COMPACT: bool = false, const ARENA: bool = false, const COUNTED: bool = false> = Map<N, (), BITS, B, COMPACT, ARENA, COUNTED>;
@@ -0,0 +1,75 @@
+use std::fmt::Write;
+
+//writes a map's tree for debugging, as a graphviz graph or as indented text, one node at a time as the map walks down
+//nodes past max_depth levels or after max_nodes of them are left out and counted under their parent instead
+pub(crate) struct Dump{
+    dot: bool,
+    max_depth: usize,
+    max_nodes: usize,
+    out: String,
+    //the level of each node written and how many of its children were left out
+    nodes: Vec<(usize, usize)>
+}
+
+impl Dump{
+    pub(crate) fn new(dot: bool, max_depth: usize, max_nodes: usize) -> Self{
+        Self{dot, max_depth, max_nodes, out: if dot {String::from("digraph{\n")} else {String::new()}, nodes: Vec::new()}
+    }
+
+    //a list under the child at digit of parent, or the root if there is no parent, returning its id for its children
+    //None if it was left out
+    pub(crate) fn list(&mut self, parent: Option<(usize, usize)>, prefix: &[u8], bits: usize, children: usize, sharded: bool) -> Option<usize>{
+        let bytes = bits.div_ceil(8);
+        let label = format!("{}/{}{} {} children", hex(&prefix[..bytes]), bits, if sharded {" sharded"} else {""}, children);
+        self.node(parent, "box", label)
+    }
+
+    pub(crate) fn bucket<const N: usize>(&mut self, parent: Option<(usize, usize)>, keys: impl Iterator<Item = [u8; N]>){
+        let keys = keys.map(|x| hex(&x)).collect::<Vec<_>>();
+        let label = if self.dot {keys.join("\\n")} else {format!("bucket {}", keys.join(", "))};
+        self.node(parent, "ellipse", label);
+    }
+
+    pub(crate) fn empty(&mut self, parent: Option<(usize, usize)>){
+        self.node(parent, "plaintext", String::from("empty"));
+    }
+
+    fn node(&mut self, parent: Option<(usize, usize)>, shape: &str, label: String) -> Option<usize>{
+        let level = parent.map_or(0, |x| self.nodes[x.0].0 + 1);
+        if (level > self.max_depth) || (self.nodes.len() >= self.max_nodes){
+            if let Some((parent, _)) = parent {self.nodes[parent].1 += 1}
+            return None
+        }
+        let id = self.nodes.len();
+        self.nodes.push((level, 0));
+        if self.dot{
+            _ = writeln!(self.out, "    n{} [label=\"{}\" shape={}]", id, label, shape);
+            if let Some((parent, digit)) = parent {_ = writeln!(self.out, "    n{} -> n{} [label=\"{}\"]", parent, id, digit)}
+        }
+        else{
+            let digit = parent.map_or(String::new(), |x| format!("{}: ", x.1));
+            _ = writeln!(self.out, "{}{}{}", "  ".repeat(level), digit, label);
+        }
+        Some(id)
+    }
+
+    //called once a list's children have been written, to note the ones left out
+    pub(crate) fn end(&mut self, id: usize){
+        let (level, left_out) = self.nodes[id];
+        if left_out == 0 {return}
+        if self.dot{
+            _ = writeln!(self.out, "    n{}_more [label=\"{} more\" shape=plaintext]", id, left_out);
+            _ = writeln!(self.out, "    n{} -> n{}_more", id, id);
+        }
+        else {_ = writeln!(self.out, "{}{} more", "  ".repeat(level + 1), left_out)}
+    }
+
+    pub(crate) fn finish(mut self) -> String{
+        if self.dot {self.out.push_str("}\n")}
+        self.out
+    }
+}
+
+fn hex(bytes: &[u8]) -> String{
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
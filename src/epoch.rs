@@ -0,0 +1,225 @@
+use std::alloc::Layout;
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::ptr::{null_mut, NonNull};
+use std::sync::atomic::Ordering;
+#[cfg(not(rust_map_loom))]
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+#[cfg(not(rust_map_loom))]
+use parking_lot::{const_mutex, Mutex};
+#[cfg(rust_map_loom)]
+use loom::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize};
+#[cfg(rust_map_loom)]
+use loom::thread_local;
+#[cfg(rust_map_loom)]
+use crate::model::Mutex;
+
+//what concurrent maps with optimistic reads free is only freed once no thread can still be reading it. a pinned thread announces the global epoch
+//in its own slot, and what a thread retires goes on its own list tagged with the epoch at the time. the epoch only moves
+//on once every pinned thread has announced it, so anything retired two epochs back is unreachable. pinning only writes
+//the thread's own slot, and garbage is only shared when a thread exits
+const PINNED: usize = 1;
+//under loom every retire collects, so a scenario small enough to model frees what it retires
+const COLLECT_EVERY: usize = if cfg!(rust_map_loom) {1} else {64};
+
+#[derive(Clone, Copy)]
+struct Garbage{
+    ptr: NonNull<u8>,
+    layout: Layout,
+    free: fn(NonNull<u8>, Layout)
+}
+
+unsafe impl Send for Garbage{}
+
+//slots are never freed, a thread takes over the slot of one that exited
+#[repr(align(128))]
+struct Slot{
+    epoch: AtomicUsize,
+    in_use: AtomicBool,
+    next: *const Slot
+}
+
+#[cfg(not(rust_map_loom))]
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(rust_map_loom))]
+static SLOTS: AtomicPtr<Slot> = AtomicPtr::new(null_mut());
+//garbage of exited threads, freed by whichever thread collects next
+#[cfg(not(rust_map_loom))]
+static ORPHANS: Mutex<Vec<(usize, Garbage)>> = const_mutex(Vec::new());
+#[cfg(rust_map_loom)]
+loom::lazy_static!{
+    static ref EPOCH: AtomicUsize = AtomicUsize::new(0);
+    static ref SLOTS: AtomicPtr<Slot> = AtomicPtr::new(null_mut());
+    static ref ORPHANS: Mutex<Vec<(usize, Garbage)>> = Mutex::new(Vec::new());
+}
+
+//under loom the statics are made afresh for each run of a model, so what they still hold is freed at its end. the other
+//threads have exited, but their thread locals may not have given back their slots and garbage yet
+#[cfg(rust_map_loom)]
+pub(crate) fn free_all(){
+    while slots().any(|x| x.in_use.load(Ordering::Acquire)) {loom::thread::yield_now()}
+    for (_, garbage) in std::mem::take(&mut *ORPHANS.lock()){
+        (garbage.free)(garbage.ptr, garbage.layout)
+    }
+    let mut slot = SLOTS.swap(null_mut(), Ordering::Acquire);
+    while !slot.is_null(){
+        let next = unsafe{(*slot).next};
+        drop(unsafe{Box::from_raw(slot)});
+        slot = next.cast_mut();
+    }
+}
+
+fn slots() -> impl Iterator<Item = &'static Slot>{
+    std::iter::successors(unsafe {SLOTS.load(Ordering::Acquire).as_ref()}, |x| unsafe {x.next.as_ref()})
+}
+
+fn acquire_slot() -> &'static Slot{
+    if let Some(slot) = slots().find(|x| x.in_use.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()) {return slot}
+    let slot = Box::leak(Box::new(Slot{epoch: AtomicUsize::new(0), in_use: AtomicBool::new(true), next: null_mut()}));
+    let mut head = SLOTS.load(Ordering::Relaxed);
+    loop{
+        slot.next = head;
+        match SLOTS.compare_exchange_weak(head, slot, Ordering::Release, Ordering::Relaxed){
+            Ok(_) => return slot,
+            Err(x) => head = x
+        }
+    }
+}
+
+fn release_slot(slot: &Slot){
+    slot.epoch.store(0, Ordering::Release);
+    slot.in_use.store(false, Ordering::Release);
+}
+
+struct Local{
+    slot: &'static Slot,
+    pins: Cell<usize>,
+    retired: Cell<usize>,
+    //in the order retired, so also in epoch order
+    garbage: RefCell<Vec<(usize, Garbage)>>,
+    //write locks held, what is retired while there are any waits in pending until they are released
+    writing: Cell<usize>,
+    pending: RefCell<Vec<Garbage>>
+}
+
+impl Drop for Local{
+    fn drop(&mut self){
+        fence(Ordering::SeqCst);
+        let epoch = EPOCH.load(Ordering::Relaxed);
+        let pending = self.pending.get_mut().drain(..).map(|x| (epoch, x));
+        ORPHANS.lock().extend(self.garbage.get_mut().drain(..).chain(pending));
+        release_slot(self.slot);
+    }
+}
+
+thread_local!{
+    static LOCAL: Local = Local{slot: acquire_slot(), pins: Cell::new(0), retired: Cell::new(0), garbage: RefCell::new(Vec::new()),
+        writing: Cell::new(0), pending: RefCell::new(Vec::new())};
+}
+
+//holds the thread pinned, pins nest. a map used from a thread local's destructor after LOCAL is gone pins a slot of its own
+pub(crate) struct Guard(Option<&'static Slot>, PhantomData<*const ()>);
+
+impl Drop for Guard{
+    fn drop(&mut self){
+        match self.0{
+            Some(slot) => release_slot(slot),
+            None => {
+                let _ = LOCAL.try_with(|local| {
+                    local.pins.set(local.pins.get() - 1);
+                    if local.pins.get() == 0 {local.slot.epoch.store(0, Ordering::Release)}
+                });
+            }
+        }
+    }
+}
+
+fn announce(slot: &Slot){
+    slot.epoch.store((EPOCH.load(Ordering::Relaxed) << 1) | PINNED, Ordering::Relaxed);
+    //the announcement has to be visible to anyone advancing the epoch before this thread reads any node
+    fence(Ordering::SeqCst);
+}
+
+pub(crate) fn pin() -> Guard{
+    let pinned = LOCAL.try_with(|local| {
+        if local.pins.get() == 0 {announce(local.slot)}
+        local.pins.set(local.pins.get() + 1);
+    });
+    match pinned{
+        Ok(()) => Guard(None, PhantomData),
+        Err(_) => {
+            let slot = acquire_slot();
+            announce(slot);
+            Guard(Some(slot), PhantomData)
+        }
+    }
+}
+
+//ptr must already be unlinked so that only threads pinned now can reach it, free is called with it once none of them are
+//a thread writing a locked value frees what it replaces before the new value is published, so until it is what it frees
+//can still be reached through the old one and is only retired once the thread has no write locks left
+pub(crate) unsafe fn retire(ptr: NonNull<u8>, layout: Layout, free: fn(NonNull<u8>, Layout)){
+    let garbage = Garbage{ptr, layout, free};
+    let retired = LOCAL.try_with(|local| {
+        if local.writing.get() == 0 {retire_in(local, garbage)}
+        else {local.pending.borrow_mut().push(garbage)}
+    });
+    if retired.is_err(){
+        fence(Ordering::SeqCst);
+        ORPHANS.lock().push((EPOCH.load(Ordering::Relaxed), garbage))
+    }
+}
+
+fn retire_in(local: &Local, garbage: Garbage){
+    //the unlink has to come before reading the epoch, or a reader pinned at the next one could still find ptr
+    fence(Ordering::SeqCst);
+    local.garbage.borrow_mut().push((EPOCH.load(Ordering::Relaxed), garbage));
+    local.retired.set(local.retired.get() + 1);
+    if local.retired.get().is_multiple_of(COLLECT_EVERY) {collect(local)}
+}
+
+//called around holding a write lock, locks are taken and released on the same thread
+pub(crate) fn start_writing(){
+    let _ = LOCAL.try_with(|local| local.writing.set(local.writing.get() + 1));
+}
+
+pub(crate) fn stop_writing(){
+    let _ = LOCAL.try_with(|local| {
+        local.writing.set(local.writing.get().saturating_sub(1));
+        if local.writing.get() == 0{
+            let pending = std::mem::take(&mut *local.pending.borrow_mut());
+            pending.into_iter().for_each(|x| retire_in(local, x));
+        }
+    });
+}
+
+//moves the epoch on if every pinned thread has announced it, returns the epoch afterwards
+fn try_advance() -> usize{
+    let epoch = EPOCH.load(Ordering::Relaxed);
+    fence(Ordering::SeqCst);
+    let behind = slots().any(|x| {
+        let announced = x.epoch.load(Ordering::Relaxed);
+        ((announced & PINNED) != 0) && ((announced >> 1) != epoch)
+    });
+    if behind {return epoch}
+    fence(Ordering::Acquire);
+    match EPOCH.compare_exchange(epoch, epoch + 1, Ordering::Release, Ordering::Relaxed){
+        Ok(_) => epoch + 1,
+        Err(x) => x
+    }
+}
+
+fn collect(local: &Local){
+    let epoch = try_advance();
+    let ready = {
+        let mut garbage = local.garbage.borrow_mut();
+        let count = garbage.partition_point(|x| (x.0 + 2) <= epoch);
+        garbage.drain(..count).collect::<Vec<_>>()
+    };
+    let orphans = ORPHANS.try_lock().map(|mut orphans| {
+        let (ready, kept) = std::mem::take(&mut *orphans).into_iter().partition::<Vec<_>, _>(|x| (x.0 + 2) <= epoch);
+        *orphans = kept;
+        ready
+    }).unwrap_or_default();
+    ready.into_iter().chain(orphans).for_each(|(_, x)| (x.free)(x.ptr, x.layout));
+}
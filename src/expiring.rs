@@ -0,0 +1,223 @@
+use std::cell::Cell;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use parking_lot::{Condvar, Mutex};
+use crate::concurrent::Map;
+use crate::InsertOrUpdateResult;
+
+//where an ExpiringMap gets the time from, so tests can move it on by hand
+pub trait Clock: Send + Sync{
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock{
+    fn now(&self) -> Instant{
+        Instant::now()
+    }
+}
+
+//a clock that stays where it was made until advanced
+#[derive(Debug)]
+pub struct ManualClock{
+    start: Instant,
+    nanos: AtomicU64
+}
+
+impl Default for ManualClock{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl ManualClock{
+    pub fn new() -> Self{
+        Self{start: Instant::now(), nanos: AtomicU64::new(0)}
+    }
+
+    pub fn advance(&self, by: Duration){
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock{
+    fn now(&self) -> Instant{
+        self.start + Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+//a concurrent map whose entries each have a deadline, at and after which they are treated as gone. they stay in the
+//map until sweep removes them or a closest key search finds them and removes them on its way to a live one. an entry
+//whose ttl goes past what an Instant can hold has no deadline and never expires
+pub struct ExpiringMap<const N: usize, V, C: Clock = SystemClock>{
+    map: Map<N, (V, Option<Instant>)>,
+    clock: C
+}
+
+fn expired(deadline: Option<Instant>, now: Instant) -> bool{
+    deadline.is_some_and(|x| x <= now)
+}
+
+impl<const N: usize, V: Copy> Default for ExpiringMap<N, V>{
+    fn default() -> Self{
+        Self::new(SystemClock)
+    }
+}
+
+impl<const N: usize, V: Copy, C: Clock> ExpiringMap<N, V, C>{
+    pub fn new(clock: C) -> Self{
+        Self{map: Map::new(), clock}
+    }
+
+    pub fn clock(&self) -> &C{
+        &self.clock
+    }
+
+    //live entries, found by walking the map
+    pub fn len(&self) -> usize{
+        let mut len = 0;
+        self.for_each(|_, _| len += 1);
+        len
+    }
+
+    //entries in the map, including expired ones that haven't been removed yet
+    pub fn len_with_expired(&self) -> usize{
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.get_min().is_none()
+    }
+
+    pub fn clear(&self){
+        self.map.clear();
+    }
+
+    pub fn get(&self, key: [u8; N]) -> Option<V>{
+        let now = self.clock.now();
+        self.map.get(key).filter(|x| !expired(x.1, now)).map(|x| x.0)
+    }
+
+    //the deadline of a live entry, None for one that never expires
+    pub fn get_deadline(&self, key: [u8; N]) -> Option<Instant>{
+        let now = self.clock.now();
+        self.map.get(key).filter(|x| !expired(x.1, now)).and_then(|x| x.1)
+    }
+
+    pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
+        self.get_live(|| self.map.get_or_closest_by_key(key, include_key, loop_around))
+    }
+
+    pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
+        self.get_live(|| self.map.get_or_closest_by_key_leading_zeroes(key, include_key))
+    }
+
+    pub fn get_min(&self) -> Option<([u8; N], V)>{
+        self.get_live(|| self.map.get_min())
+    }
+
+    pub fn get_max(&self) -> Option<([u8; N], V)>{
+        self.get_live(|| self.map.get_max())
+    }
+
+    //searches until it finds a live entry or nothing, removing each expired one it finds so the next search doesn't
+    //find it again
+    fn get_live(&self, search: impl Fn() -> Option<([u8; N], (V, Option<Instant>))>) -> Option<([u8; N], V)>{
+        let now = self.clock.now();
+        loop{
+            let (key, (value, deadline)) = search()?;
+            if !expired(deadline, now) {return Some((key, value))}
+            self.map.remove_if(key, &|x| expired(x.1, now));
+        }
+    }
+
+    //calls f with every live entry in key order, f mustn't use the map
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        let now = self.clock.now();
+        self.map.for_each(|key, value| if !expired(value.1, now) {f(key, value.0)});
+    }
+
+    //the entry expires ttl after now, an expired entry at key counts as not there
+    pub fn insert_or_update(&self, key: [u8; N], value: V, ttl: Duration) -> InsertOrUpdateResult{
+        self.insert_or_update_if(key, value, ttl, &|_, _| true)
+    }
+
+    pub fn insert_or_update_if(&self, key: [u8; N], value: V, ttl: Duration, should_update: &impl Fn(&V, &V) -> bool) -> InsertOrUpdateResult{
+        let now = self.clock.now();
+        let replaced_expired = Cell::new(false);
+        let result = self.map.insert_or_update_if(key, (value, now.checked_add(ttl)), &|old, new| {
+            replaced_expired.set(expired(old.1, now));
+            replaced_expired.get() || should_update(&old.0, &new.0)
+        });
+        if (result == InsertOrUpdateResult::Updated) && replaced_expired.get() {InsertOrUpdateResult::Inserted} else {result}
+    }
+
+    pub fn remove(&self, key: [u8; N]){
+        self.map.remove(key);
+    }
+
+    //an expired entry at key is removed whatever should_remove says, returns whether a live entry was removed
+    pub fn remove_if(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> bool{
+        let now = self.clock.now();
+        let removed_live = Cell::new(false);
+        self.map.remove_if(key, &|x| {
+            removed_live.set(!expired(x.1, now) && should_remove(&x.0));
+            expired(x.1, now) || removed_live.get()
+        });
+        removed_live.get()
+    }
+
+    //removes every entry expired by now, returning how many. entries are found in one walk of the map and removed after
+    //it, an entry given a new deadline in between is left
+    pub fn sweep(&self, now: Instant) -> usize{
+        let mut keys = Vec::new();
+        self.map.for_each(|key, value| if expired(value.1, now) {keys.push(key)});
+        keys.into_iter().filter(|x| self.map.remove_if(*x, &|y| expired(y.1, now))).count()
+    }
+}
+
+impl<const N: usize, V: Copy + Send + Sync + 'static, C: Clock + 'static> ExpiringMap<N, V, C>{
+    //a thread sweeping the map at its clock's time every interval until the sweeper is dropped or the map is
+    pub fn start_sweeper(self: &Arc<Self>, interval: Duration) -> Sweeper{
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let map = Arc::downgrade(self);
+        let thread = std::thread::spawn({
+            let stop = stop.clone();
+            move || Self::run_sweeper(map, interval, &stop)
+        });
+        Sweeper{stop, thread: Some(thread)}
+    }
+
+    fn run_sweeper(map: Weak<Self>, interval: Duration, stop: &(Mutex<bool>, Condvar)){
+        let mut stopped = stop.0.lock();
+        loop{
+            //an interval too long for an Instant never comes round, the thread just waits to be stopped
+            match Instant::now().checked_add(interval){
+                Some(deadline) => while !*stopped && (Instant::now() < deadline) {stop.1.wait_until(&mut stopped, deadline);},
+                None => while !*stopped {stop.1.wait(&mut stopped)}
+            }
+            if *stopped {return}
+            let Some(map) = map.upgrade() else {return};
+            //let go of while sweeping so stopping isn't held up behind it
+            parking_lot::MutexGuard::unlocked(&mut stopped, || _ = map.sweep(map.clock.now()));
+        }
+    }
+}
+
+//stops its thread when dropped, waiting for a sweep in progress to finish
+pub struct Sweeper{
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Drop for Sweeper{
+    fn drop(&mut self){
+        *self.stop.0.lock() = true;
+        self.stop.1.notify_all();
+        if let Some(thread) = self.thread.take() {_ = thread.join()}
+    }
+}
@@ -1,5 +1,31 @@
+pub mod bounded;
 pub mod concurrent;
+pub mod expiring;
 pub mod non_concurrent;
+pub mod persistent;
+mod arena;
+mod dump;
+#[cfg(feature = "optimistic")]
+mod epoch;
+mod lock;
+#[cfg(rust_map_loom)]
+mod model;
+mod node;
+#[cfg(feature = "optimistic")]
+mod version;
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+//for the loom models, frees what the epoch and the arena still hold once a model's other threads have exited, as loom
+//makes their statics afresh for each run
+#[cfg(rust_map_loom)]
+#[doc(hidden)]
+pub fn free_all(){
+    #[cfg(feature = "optimistic")]
+    epoch::free_all();
+    arena::shrink();
+}
 
 #[allow(non_snake_case)]
 pub const fn ALWAYS_UPDATE<T>(_: &T, _: &T) -> bool {true}
@@ -14,6 +40,137 @@ pub enum InsertOrUpdateResult{
     Neither
 }
 
-const fn get_index<const N: usize>(key: [u8; N], depth: usize) -> usize{
-    ((key[depth/4] >> (6-((depth % 4) * 2))) & 0b00000011) as usize
-}
\ No newline at end of file
+//where validate found a map out of the shape its inserts and removes leave it in, path is the digits of the children
+//taken from the root down to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError<const N: usize>{
+    pub path: Vec<usize>,
+    pub violation: Violation<N>
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation<const N: usize>{
+    //a key in a bucket that doesn't have the bits of the lists and digits above it
+    KeyOffPath([u8; N]),
+    UnsortedBucket,
+    OverfullBucket(usize),
+    //a compact bucket leaving out key bytes that its position doesn't imply
+    SkipTooLong(usize),
+    //a list that isn't below its parent's depth or is past the last digit
+    ListDepth(usize),
+    //a list whose prefix doesn't have the bits of the lists and digits above it, or has bits after its depth
+    PrefixOffPath([u8; N]),
+    //a list with one child or none, which should have been replaced by its child
+    Uncollapsed,
+    //a list whose children are buckets holding no more than B entries between them, which should have been merged
+    Unmerged(usize),
+    //a list with vacant slots or in a bigger kind of node than it needs
+    VacantSlots,
+    //a sharded list below one that isn't sharded or without a child for every digit
+    Shard,
+    TooDeep(usize)
+}
+
+impl<const N: usize> Display for ValidationError<N>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        write!(f, "{:?} at {:?}", self.violation, self.path)
+    }
+}
+
+impl<const N: usize> Error for ValidationError<N>{}
+
+//bytes a map uses, each allocation counted at the size asked of the allocator (a whole size class with ARENA)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage{
+    pub buckets: usize,
+    //lists and their child nodes apart from the slots in them
+    pub lists: usize,
+    //the map's own word and every child slot lists have room for, each a tagged pointer that in the concurrent map is
+    //also its lock. the map's own word is only on the heap if the map is
+    pub slots: usize
+}
+
+impl MemoryUsage{
+    pub fn total(&self) -> usize{
+        self.buckets + self.lists + self.slots
+    }
+}
+
+//what stats found in one walk of a map, for seeing how keys spread through the tree
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MapStats{
+    pub len: usize,
+    //slots, the map itself and each child slot of a list, holding a bucket, a list or nothing
+    pub buckets: usize,
+    pub lists: usize,
+    pub empty_slots: usize,
+    //how many buckets are at each depth, with a bucket at the root at depth 1
+    pub bucket_depths: Vec<usize>,
+    //how many lists have each number of children that aren't empty
+    pub list_occupancy: Vec<usize>,
+    //the same as get_memory_usage
+    pub memory: MemoryUsage,
+    pub depth: usize,
+    //the depth of an entry's bucket averaged over the entries
+    pub average_depth: f64
+}
+
+//number of key bits consumed per trie level, fan out is 2^BITS
+pub struct BitsPerLevel<const BITS: usize>;
+
+pub trait SupportedBitsPerLevel{
+    type Array<T>: AsRef<[T]> + AsMut<[T]>;
+    fn new_array<T>(f: impl FnMut(usize) -> T) -> Self::Array<T>;
+}
+
+macro_rules! supported_bits_per_level{
+    ($($bits:literal),*) => {$(
+        impl SupportedBitsPerLevel for BitsPerLevel<$bits>{
+            type Array<T> = [T; 1 << $bits];
+            fn new_array<T>(f: impl FnMut(usize) -> T) -> Self::Array<T> {std::array::from_fn(f)}
+        }
+    )*}
+}
+
+supported_bits_per_level!(1, 2, 4, 8);
+
+const fn get_index<const N: usize, const BITS: usize>(key: [u8; N], depth: usize) -> usize{
+    let bit = depth * BITS;
+    ((key[bit / 8] >> (8 - BITS - (bit % 8))) & (((1u16 << BITS) - 1) as u8)) as usize
+}
+
+//the index itself first, then the other indexes sharing the most leading bits with it, nearest first
+fn leading_zeroes_order<const BITS: usize>(index: usize) -> impl Iterator<Item = usize>{
+    std::iter::once(index).chain((0..BITS).flat_map(move |bit| {
+        let start = ((index >> bit) ^ 1) << bit;
+        (0..(1 << bit)).map(move |i| if start > index {start + i} else {start + (1 << bit) - 1 - i})
+    }))
+}
+
+//first digit at which the two keys differ, None if they are equal
+fn get_first_different_digit<const N: usize, const BITS: usize>(key_1: [u8; N], key_2: [u8; N]) -> Option<usize>{
+    (0..N).find(|i| key_1[*i] != key_2[*i])
+        .map(|i| ((i * 8) + ((key_1[i] ^ key_2[i]).leading_zeros() as usize)) / BITS)
+}
+
+//the key with every bit from the digit at depth onwards cleared
+fn get_prefix<const N: usize, const BITS: usize>(key: [u8; N], depth: usize) -> [u8; N]{
+    let bit = depth * BITS;
+    let mut prefix = [0; N];
+    prefix[..(bit / 8)].copy_from_slice(&key[..(bit / 8)]);
+    if !bit.is_multiple_of(8) {prefix[bit / 8] = key[bit / 8] & !(u8::MAX >> (bit % 8))}
+    prefix
+}
+
+//digit at which the key leaves a list's skipped prefix, None if it carries on down the list
+fn get_split_depth<const N: usize, const BITS: usize>(key: [u8; N], prefix: [u8; N], depth: usize) -> Option<usize>{
+    get_first_different_digit::<N, BITS>(key, prefix).filter(|x| *x < depth)
+}
+
+//prefix with the digit at depth set, prefix must have every bit from there onwards cleared
+fn get_prefix_with_digit<const N: usize, const BITS: usize>(prefix: [u8; N], depth: usize, digit: usize) -> [u8; N]{
+    let bit = depth * BITS;
+    let mut prefix = prefix;
+    prefix[bit / 8] |= (digit as u8) << (8 - BITS - (bit % 8));
+    prefix
+}
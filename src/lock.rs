@@ -0,0 +1,468 @@
+use std::alloc::Layout;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::Ordering;
+#[cfg(not(rust_map_loom))]
+use std::sync::atomic::AtomicPtr;
+#[cfg(rust_map_loom)]
+use crate::model::AtomicPtr;
+use crate::arena::Alloc;
+use crate::node::Packed;
+use state::{State, READER, READERS};
+#[cfg(feature = "optimistic")]
+use crate::node::CopyOnWrite;
+#[cfg(feature = "optimistic")]
+use crate::epoch;
+#[cfg(feature = "optimistic")]
+use crate::version::{self, Version, View};
+
+//a reader writer lock's word holds the number of readers, a bit for a writer waiting for the readers to leave and a bit
+//for a writer holding it, new readers waiting while a writer is waiting so a steady stream of readers can't keep a writer
+//out. where it can the value it guards is packed into the same word, see State
+const WRITER_WAITING: usize = 1 << (usize::BITS - 2);
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+//on 64 bit platforms user space pointers leave their top 16 bits clear, so the value's word is the lock's word with the
+//lock in those 16 bits and a slot in a list is 8 bytes. android's allocator tags the top byte of heap pointers, so
+//there and on smaller platforms the lock has a word of its own beside the value's
+#[cfg(all(target_pointer_width = "64", not(target_os = "android")))]
+mod state{
+    use std::ptr::{null_mut, NonNull};
+    use std::sync::atomic::Ordering;
+    use super::{AtomicPtr, WRITER_WAITING};
+
+    const VALUE: usize = (1 << 48) - 1;
+    pub(super) const READER: usize = 1 << 48;
+    pub(super) const READERS: usize = ((1 << 14) - 1) << 48;
+
+    pub(super) struct State(AtomicPtr<u8>);
+
+    impl State{
+        pub(super) const fn empty() -> Self{
+            Self(AtomicPtr::new(null_mut()))
+        }
+
+        pub(super) fn lock(&self) -> &AtomicPtr<u8>{
+            &self.0
+        }
+
+        //the value's word, from the lock's word as it was when the lock was taken
+        pub(super) fn value(&self, locked: *mut u8) -> *mut u8{
+            locked.map_addr(|x| x & VALUE)
+        }
+
+        pub(super) fn set(&self, word: *mut u8){
+            debug_assert_eq!(word.addr() & !VALUE, 0, "pointers must fit in 48 bits");
+            self.0.store(word, Ordering::Relaxed)
+        }
+
+        //every node's pointer was checked to fit when it was allocated, see Lockable, so an unlock can't panic here
+        pub(super) fn unlock_write(&self, word: *mut u8){
+            debug_assert_eq!(word.addr() & !VALUE, 0, "pointers must fit in 48 bits");
+            //keeps a waiting writer's bit, which it may have set since the lock was taken
+            let _ = self.0.fetch_update(Ordering::Release, Ordering::Relaxed, |x| Some(word.map_addr(|y| y | (x.addr() & WRITER_WAITING))));
+        }
+
+        //puts a version in while the lock is write locked, keeping the lock's bits
+        #[cfg(feature = "optimistic")]
+        pub(super) fn propose(&self, word: *mut u8){
+            let _ = self.0.fetch_update(Ordering::Release, Ordering::Relaxed, |x| Some(word.map_addr(|y| y | (x.addr() & !VALUE))));
+        }
+
+        #[cfg(feature = "optimistic")]
+        pub(super) fn peek(&self) -> *mut u8{
+            self.0.load(Ordering::Acquire).map_addr(|x| x & VALUE)
+        }
+
+        #[cfg(all(rust_map_loom, feature = "optimistic"))]
+        pub(super) fn moved(&mut self){
+            self.0.moved()
+        }
+    }
+
+    pub(super) fn check_fits(ptr: NonNull<u8>){
+        assert_eq!(ptr.addr().get() & !VALUE, 0, "pointers must fit in 48 bits");
+    }
+}
+
+//the lock's word is an AtomicPtr like the packed one so the lock's code (and loom's model of it) is the same for both
+#[cfg(not(all(target_pointer_width = "64", not(target_os = "android"))))]
+mod state{
+    use std::ptr::{null_mut, NonNull};
+    use std::sync::atomic::Ordering;
+    use super::{AtomicPtr, WRITER};
+
+    pub(super) const READER: usize = 1;
+    pub(super) const READERS: usize = (1 << (usize::BITS - 2)) - 1;
+
+    pub(super) struct State{
+        lock: AtomicPtr<u8>,
+        //only changed under a write lock
+        value: AtomicPtr<u8>
+    }
+
+    impl State{
+        pub(super) const fn empty() -> Self{
+            Self{lock: AtomicPtr::new(null_mut()), value: AtomicPtr::new(null_mut())}
+        }
+
+        pub(super) fn lock(&self) -> &AtomicPtr<u8>{
+            &self.lock
+        }
+
+        pub(super) fn value(&self, _: *mut u8) -> *mut u8{
+            self.value.load(Ordering::Relaxed)
+        }
+
+        pub(super) fn set(&self, word: *mut u8){
+            self.value.store(word, Ordering::Relaxed)
+        }
+
+        pub(super) fn unlock_write(&self, word: *mut u8){
+            self.value.store(word, Ordering::Release);
+            let _ = self.lock.fetch_update(Ordering::Release, Ordering::Relaxed, |x| Some(x.map_addr(|y| y & !WRITER)));
+        }
+
+        #[cfg(feature = "optimistic")]
+        pub(super) fn propose(&self, word: *mut u8){
+            self.value.store(word, Ordering::Release)
+        }
+
+        #[cfg(feature = "optimistic")]
+        pub(super) fn peek(&self) -> *mut u8{
+            self.value.load(Ordering::Acquire)
+        }
+
+        #[cfg(all(rust_map_loom, feature = "optimistic"))]
+        pub(super) fn moved(&mut self){
+            self.lock.moved();
+            self.value.moved();
+        }
+    }
+
+    pub(super) fn check_fits(_: NonNull<u8>){}
+}
+
+//what a lock can hold, with optimistic reads a writer changes a copy of it, see CopyOnWrite
+#[cfg(not(feature = "optimistic"))]
+pub(crate) trait Value: Packed{}
+#[cfg(not(feature = "optimistic"))]
+impl<T: Packed> Value for T{}
+#[cfg(feature = "optimistic")]
+pub(crate) trait Value: Packed + CopyOnWrite{}
+#[cfg(feature = "optimistic")]
+impl<T: Packed + CopyOnWrite> Value for T{}
+
+pub(crate) struct RwLock<T: Value>{
+    state: State,
+    _marker: PhantomData<T>
+}
+
+unsafe impl<T: Value + Send> Send for RwLock<T>{}
+unsafe impl<T: Value + Send + Sync> Sync for RwLock<T>{}
+
+//a decoded copy of the value, shared with the other readers
+pub(crate) struct ReadGuard<'a, T: Value>{
+    lock: &'a RwLock<T>,
+    value: ManuallyDrop<T>
+}
+
+//the decoded value, packed back into the lock when the guard is dropped
+pub(crate) struct WriteGuard<'a, T: Value>{
+    lock: &'a RwLock<T>,
+    value: ManuallyDrop<T>,
+    //the value as it was locked once value has become a copy of it
+    #[cfg(feature = "optimistic")]
+    copied: Option<ManuallyDrop<T>>,
+    //the word the lock held, which may hold versions, and the version of the copy once it is in the lock
+    #[cfg(feature = "optimistic")]
+    locked: *mut u8,
+    #[cfg(feature = "optimistic")]
+    version: Option<NonNull<Version>>
+}
+
+//the value read without taking the lock, which stays readable for as long as the thread is pinned, and the word it was
+//read from, of the version the reader's view reads
+#[cfg(feature = "optimistic")]
+pub(crate) struct Peek<'a, T: Value>{
+    lock: &'a RwLock<T>,
+    word: *mut u8,
+    value: ManuallyDrop<T>
+}
+
+//the words an optimistic reader read, kept readable by its view's pin. a lock is never given a word it held before while
+//a reader is pinned (see Packed), so if each is still what its lock holds once the reader is done, each stayed there
+//from when it was read until it was checked again and together they are what the locks held at the moment the checking
+//started. what a snapshot's view reads never changes
+#[cfg(feature = "optimistic")]
+pub(crate) struct Reads<'a, T: Value>{
+    read: Vec<(&'a RwLock<T>, *mut u8)>,
+    view: View
+}
+
+impl<T: Value> RwLock<T>{
+    pub(crate) const fn empty() -> Self{
+        Self{state: State::empty(), _marker: PhantomData}
+    }
+
+    pub(crate) fn new(value: T) -> Self{
+        let lock = Self::empty();
+        lock.state.set(value.into_word());
+        lock
+    }
+
+    fn unpack(word: *mut u8) -> ManuallyDrop<T>{
+        ManuallyDrop::new(unsafe{T::from_word(word)})
+    }
+
+    //the value in a word taken from the lock while no writer is changing it, with optimistic reads its newest version
+    fn unpack_latest(word: *mut u8) -> ManuallyDrop<T>{
+        #[cfg(feature = "optimistic")]
+        return Self::unpack(version::latest(word));
+        #[cfg(not(feature = "optimistic"))]
+        Self::unpack(word)
+    }
+
+    pub(crate) fn read(&self) -> ReadGuard<'_, T>{
+        let mut backoff = Backoff::new();
+        loop{
+            if let Some(guard) = self.try_read() {return guard}
+            backoff.snooze();
+        }
+    }
+
+    //None if a writer has the lock or is waiting for it, or there are as many readers as it can count
+    pub(crate) fn try_read(&self) -> Option<ReadGuard<'_, T>>{
+        loop{
+            let state = self.state.lock().load(Ordering::Relaxed);
+            if ((state.addr() & (WRITER | WRITER_WAITING)) != 0) || ((state.addr() & READERS) == READERS) {return None}
+            if self.state.lock().compare_exchange_weak(state, state.map_addr(|x| x + READER), Ordering::Acquire, Ordering::Relaxed).is_ok(){
+                return Some(ReadGuard{lock: self, value: Self::unpack_latest(self.state.value(state))})
+            }
+        }
+    }
+
+    pub(crate) fn write(&self) -> WriteGuard<'_, T>{
+        let mut backoff = Backoff::new();
+        loop{
+            let state = self.state.lock().load(Ordering::Relaxed);
+            if (state.addr() & (WRITER | READERS)) == 0{
+                if self.state.lock().compare_exchange_weak(state, state.map_addr(|x| (x & !WRITER_WAITING) | WRITER), Ordering::Acquire, Ordering::Relaxed).is_ok(){
+                    return WriteGuard::new(self, self.state.value(state))
+                }
+            }
+            else if (state.addr() & WRITER_WAITING) == 0{
+                self.state.lock().fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            backoff.snooze();
+        }
+    }
+
+    //no lock is needed with exclusive access
+    pub(crate) fn get_mut(&mut self) -> WriteGuard<'_, T>{
+        let word = self.state.value(self.state.lock().load(Ordering::Relaxed));
+        WriteGuard::new(self, word)
+    }
+
+    //under loom, for a lock moved bitwise into a copy of its list, see model
+    #[cfg(all(rust_map_loom, feature = "optimistic"))]
+    pub(crate) fn moved(&mut self){
+        self.state.moved()
+    }
+
+    //writers publish a value once they are done with it, and don't free the one it replaces while view is held
+    #[cfg(feature = "optimistic")]
+    pub(crate) fn peek<'a>(&'a self, view: &'a View) -> Peek<'a, T>{
+        let word = view.resolve(self.state.peek());
+        Peek{lock: self, word, value: Self::unpack(word)}
+    }
+}
+
+#[cfg(feature = "optimistic")]
+impl<'a, T: Value> Reads<'a, T>{
+    //reading the versions at the generation at, see version
+    pub(crate) fn new(at: usize) -> Self{
+        Self{read: Vec::new(), view: View::at(at)}
+    }
+
+    //the value in lock, which stays readable for as long as self is kept
+    pub(crate) fn peek(&self, lock: &'a RwLock<T>) -> Peek<'a, T>{
+        let word = self.view.resolve(lock.state.peek());
+        Peek{lock, word, value: RwLock::unpack(word)}
+    }
+
+    //the peek is one of the words to check
+    pub(crate) fn push(&mut self, peek: Peek<'a, T>){
+        self.read.push((peek.lock, peek.word));
+    }
+
+    //whether every word read is still what its lock holds
+    pub(crate) fn validate(&self) -> bool{
+        self.read.iter().all(|x| self.view.resolve(x.0.state.peek()) == x.1)
+    }
+}
+
+impl<'a, T: Value> WriteGuard<'a, T>{
+    fn new(lock: &'a RwLock<T>, word: *mut u8) -> Self{
+        #[cfg(feature = "optimistic")]
+        {
+            epoch::start_writing();
+            WriteGuard{lock, value: RwLock::unpack_latest(word), copied: None, locked: word, version: None}
+        }
+        #[cfg(not(feature = "optimistic"))]
+        WriteGuard{lock, value: RwLock::unpack(word)}
+    }
+}
+
+impl<T: Value> Drop for RwLock<T>{
+    fn drop(&mut self){
+        let word = self.state.value(self.state.lock().load(Ordering::Relaxed));
+        drop(ManuallyDrop::into_inner(Self::unpack_latest(word)));
+        #[cfg(feature = "optimistic")]
+        version::retire(word);
+    }
+}
+
+impl<T: Value> Deref for ReadGuard<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.value
+    }
+}
+
+impl<T: Value> Drop for ReadGuard<'_, T>{
+    fn drop(&mut self){
+        self.lock.state.lock().fetch_byte_sub(READER, Ordering::Release);
+    }
+}
+
+impl<T: Value> Deref for WriteGuard<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.value
+    }
+}
+
+impl<T: Value> DerefMut for WriteGuard<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        #[cfg(feature = "optimistic")]
+        if self.copied.is_none(){
+            let copy = ManuallyDrop::new(unsafe{self.value.copy()});
+            self.copied = Some(std::mem::replace(&mut self.value, copy));
+        }
+        &mut self.value
+    }
+}
+
+impl<T: Value> Drop for WriteGuard<'_, T>{
+    #[cfg(not(feature = "optimistic"))]
+    fn drop(&mut self){
+        self.lock.state.unlock_write(unsafe{ManuallyDrop::take(&mut self.value)}.into_word());
+    }
+
+    //the copy goes in as a version of what was locked, which is kept in the lock if a snapshot may need it
+    #[cfg(feature = "optimistic")]
+    fn drop(&mut self){
+        let version = self.version.or_else(|| {
+            let version = self.propose(None)?;
+            version::publish(&[version]);
+            Some(version)
+        });
+        match (version, self.copied.take()){
+            (Some(version), Some(copied)) => {
+                self.lock.state.unlock_write(version::finish(version));
+                unsafe{ManuallyDrop::into_inner(copied).free_copied()}
+            }
+            _ => self.lock.state.unlock_write(self.locked)
+        }
+        epoch::stop_writing();
+    }
+}
+
+#[cfg(feature = "optimistic")]
+impl<T: Value> WriteGuard<'_, T>{
+    //puts the copy in the lock as a version of what was locked, None if nothing was copied. the guard's value goes into
+    //the version, so the guard can only be dropped after
+    fn propose(&mut self, leader: Option<NonNull<Version>>) -> Option<NonNull<Version>>{
+        self.copied.as_ref()?;
+        let new = unsafe{ManuallyDrop::take(&mut self.value)}.into_word();
+        let version = version::prepare(self.locked, new, leader);
+        state::check_fits(version.cast());
+        self.lock.state.propose(version::word(version));
+        self.version = Some(version);
+        self.version
+    }
+}
+
+//the writes under guards are published at once, so a snapshot sees all of them or none
+#[cfg(feature = "optimistic")]
+pub(crate) fn publish_together<'g, 'a: 'g, T: Value + 'a>(guards: impl IntoIterator<Item = &'g mut WriteGuard<'a, T>>){
+    let mut versions = Vec::new();
+    for guard in guards{
+        if let Some(version) = guard.propose(versions.first().copied()) {versions.push(version)}
+    }
+    version::publish(&versions);
+}
+
+#[cfg(feature = "optimistic")]
+impl<T: Value> Deref for Peek<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.value
+    }
+}
+
+impl<T: Value + Debug> Debug for RwLock<T>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("RwLock").field("data", &*self.read()).finish()
+    }
+}
+
+//allocates the nodes whose pointers go in a lock's word, refusing one that overlaps the lock's bits when it is allocated
+//rather than when its lock is let go of. the concurrent map's nodes are allocated through it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub(crate) struct Lockable<A>(PhantomData<A>);
+
+impl<A: Alloc> Alloc for Lockable<A>{
+    fn alloc(layout: Layout) -> NonNull<u8>{
+        let ptr = A::alloc(layout);
+        state::check_fits(ptr);
+        ptr
+    }
+
+    fn dealloc(ptr: NonNull<u8>, layout: Layout){
+        A::dealloc(ptr, layout)
+    }
+
+    fn allocated_size(layout: Layout) -> usize{
+        A::allocated_size(layout)
+    }
+}
+
+//spins for a while then yields to other threads
+#[cfg_attr(rust_map_loom, allow(dead_code))]
+pub(crate) struct Backoff(u32);
+
+impl Backoff{
+    pub(crate) fn new() -> Self{
+        Self(0)
+    }
+
+    //under loom a spinning thread has to yield for the others to be scheduled
+    #[cfg(rust_map_loom)]
+    pub(crate) fn snooze(&mut self){
+        loom::thread::yield_now()
+    }
+
+    #[cfg(not(rust_map_loom))]
+    pub(crate) fn snooze(&mut self){
+        if self.0 < 6{
+            (0..(1 << self.0)).for_each(|_| std::hint::spin_loop());
+            self.0 += 1;
+        }
+        else {std::thread::yield_now()}
+    }
+}
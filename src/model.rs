@@ -0,0 +1,71 @@
+//with --cfg rust_map_loom the crate's shared state is loom's, so loom's scheduler chooses which thread goes at each
+//access to it and tests can run every interleaving of a small scenario. the arena's and the epoch's statics and thread
+//locals are loom's lazy_static! and thread_local!, which start afresh for each run
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+
+//loom atomics can only be made inside a model run, so a lock's word is made on first use to keep Map::new const. a lock
+//moved bitwise keeps its atomic, see moved for when the one it was moved from can still be read
+pub(crate) struct AtomicPtr<T>(OnceLock<loom::sync::atomic::AtomicPtr<T>>);
+
+impl<T> AtomicPtr<T>{
+    pub(crate) const fn new(value: *mut T) -> Self{
+        assert!(value.is_null());
+        Self(OnceLock::new())
+    }
+
+    fn get(&self) -> &loom::sync::atomic::AtomicPtr<T>{
+        self.0.get_or_init(|| loom::sync::atomic::AtomicPtr::new(std::ptr::null_mut()))
+    }
+
+    //gives a lock copied bitwise an atomic of its own holding the value it had, so changes to the copy aren't seen
+    //through the original. called by the thread that made the copy before anyone else can reach it, while nothing
+    //changes the original
+    #[cfg(feature = "optimistic")]
+    pub(crate) fn moved(&mut self){
+        let value = self.0.get().map_or(std::ptr::null_mut(), |x| unsafe{x.unsync_load()});
+        self.0 = OnceLock::from(loom::sync::atomic::AtomicPtr::new(value));
+    }
+
+    pub(crate) fn load(&self, order: Ordering) -> *mut T{
+        self.get().load(order)
+    }
+
+    pub(crate) fn store(&self, value: *mut T, order: Ordering){
+        self.get().store(value, order)
+    }
+
+    pub(crate) fn compare_exchange_weak(&self, current: *mut T, new: *mut T, success: Ordering, failure: Ordering) -> Result<*mut T, *mut T>{
+        self.get().compare_exchange_weak(current, new, success, failure)
+    }
+
+    pub(crate) fn fetch_update(&self, set: Ordering, fetch: Ordering, f: impl FnMut(*mut T) -> Option<*mut T>) -> Result<*mut T, *mut T>{
+        self.get().fetch_update(set, fetch, f)
+    }
+
+    pub(crate) fn fetch_or(&self, value: usize, order: Ordering) -> *mut T{
+        self.fetch_update(order, Ordering::Relaxed, |x| Some(x.map_addr(|y| y | value))).unwrap()
+    }
+
+    pub(crate) fn fetch_byte_sub(&self, value: usize, order: Ordering) -> *mut T{
+        self.fetch_update(order, Ordering::Relaxed, |x| Some(x.map_addr(|y| y - value))).unwrap()
+    }
+}
+
+//a loom mutex used like parking_lot's, which doesn't poison
+pub(crate) struct Mutex<T>(loom::sync::Mutex<T>);
+
+impl<T> Mutex<T>{
+    pub(crate) fn new(value: T) -> Self{
+        Self(loom::sync::Mutex::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> loom::sync::MutexGuard<'_, T>{
+        self.0.lock().unwrap()
+    }
+
+    #[cfg(feature = "optimistic")]
+    pub(crate) fn try_lock(&self) -> Option<loom::sync::MutexGuard<'_, T>>{
+        self.0.try_lock().ok()
+    }
+}
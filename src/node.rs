@@ -0,0 +1,874 @@
+use std::alloc::Layout;
+use std::cmp::Ordering;
+use std::fmt::{Debug, Formatter};
+use std::hash::{Hash, Hasher};
+use std::iter::{Enumerate, Zip};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut, Range};
+use std::ptr::{null_mut, NonNull};
+use std::slice;
+#[cfg(feature = "optimistic")]
+use std::cell::Cell;
+#[cfg(feature = "optimistic")]
+use std::sync::atomic::{self, AtomicUsize};
+use crate::arena::Alloc;
+use crate::{get_first_different_digit, get_index, BitsPerLevel, SupportedBitsPerLevel};
+
+//fixed size child array of a list, one entry per possible digit
+pub(crate) struct Children<T, const BITS: usize>(<BitsPerLevel<BITS> as SupportedBitsPerLevel>::Array<T>) where BitsPerLevel<BITS>: SupportedBitsPerLevel;
+
+impl<T, const BITS: usize> Children<T, BITS> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    pub(crate) fn new(f: impl FnMut(usize) -> T) -> Self{
+        Self(BitsPerLevel::<BITS>::new_array(f))
+    }
+}
+
+impl<T, const BITS: usize> Deref for Children<T, BITS> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    type Target = [T];
+    fn deref(&self) -> &[T]{
+        self.0.as_ref()
+    }
+}
+
+impl<T, const BITS: usize> DerefMut for Children<T, BITS> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn deref_mut(&mut self) -> &mut [T]{
+        self.0.as_mut()
+    }
+}
+
+//a child slot of a list, vacant slots are ones holding an empty map
+pub(crate) trait Slot: Default{
+    fn is_vacant(&self) -> bool;
+
+    //under loom, for a slot moved bitwise into a copy of its list
+    #[cfg(all(rust_map_loom, feature = "optimistic"))]
+    fn moved(&mut self){}
+}
+
+const NODE_4: usize = 4;
+const NODE_16: usize = 16;
+const NODE_48: usize = 48;
+
+//children of a list sized to how many there are, like the adaptive radix tree
+//Node4 and Node16 keep their digits sorted, Node48 maps a digit to a slot, Full has a slot for every digit
+//with a fan out of 4 or less every list is a Node4 so there is no second allocation
+pub(crate) enum Nodes<T, const BITS: usize, A: Alloc> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    Node4(Sorted<T, NODE_4>),
+    Node16(NodeBox<Sorted<T, NODE_16>, A>),
+    Node48(NodeBox<Indexed<T>, A>),
+    Full(NodeBox<Children<T, BITS>, A>)
+}
+
+pub(crate) struct Sorted<T, const C: usize>{
+    len: u8,
+    digits: [u8; C],
+    children: [T; C]
+}
+
+pub(crate) struct Indexed<T>{
+    len: u8,
+    //slot + 1 for each digit, 0 if it has no child
+    slots: [u8; 256],
+    children: [T; NODE_48]
+}
+
+impl<T, const C: usize> Sorted<T, C>{
+    fn len(&self) -> usize{
+        self.len as usize
+    }
+}
+
+impl<T: Slot, const C: usize> Sorted<T, C>{
+
+    fn new() -> Self{
+        Self{len: 0, digits: [0; C], children: std::array::from_fn(|_| T::default())}
+    }
+
+    fn position(&self, digit: usize) -> Result<usize, usize>{
+        self.digits[..self.len()].binary_search(&(digit as u8))
+    }
+
+    fn insert(&mut self, digit: usize, child: T){
+        let Err(position) = self.position(digit) else {unreachable!()};
+        let len = self.len();
+        self.digits.copy_within(position..len, position + 1);
+        self.children[position..=len].rotate_right(1);
+        self.digits[position] = digit as u8;
+        self.children[position] = child;
+        self.len += 1;
+    }
+}
+
+impl<T> Indexed<T>{
+    fn len(&self) -> usize{
+        self.len as usize
+    }
+}
+
+impl<T: Slot> Indexed<T>{
+
+    fn new() -> Self{
+        Self{len: 0, slots: [0; 256], children: std::array::from_fn(|_| T::default())}
+    }
+
+    fn insert(&mut self, digit: usize, child: T){
+        self.children[self.len()] = child;
+        self.len += 1;
+        self.slots[digit] = self.len;
+    }
+}
+
+impl<T: Slot, const BITS: usize, A: Alloc> Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+
+    const FAN_OUT: usize = 1 << BITS;
+
+    //smallest kind of node that fits len children, Node16 and Node48 are only used if they are smaller than Full
+    fn capacity_for(len: usize) -> usize{
+        if len <= NODE_4 {NODE_4}
+        else if (Self::FAN_OUT > NODE_16) && (len <= NODE_16) {NODE_16}
+        else if (Self::FAN_OUT > NODE_48) && (len <= NODE_48) {NODE_48}
+        else {Self::FAN_OUT}
+    }
+
+    fn capacity(&self) -> usize{
+        match self{
+            Self::Node4(_) => NODE_4,
+            Self::Node16(_) => NODE_16,
+            Self::Node48(_) => NODE_48,
+            Self::Full(_) => Self::FAN_OUT
+        }
+    }
+
+    pub(crate) fn from_children(children: Vec<(usize, T)>) -> Self{
+        Self::with_capacity(Self::capacity_for(children.len()), children)
+    }
+
+    fn with_capacity(capacity: usize, children: Vec<(usize, T)>) -> Self{
+        match capacity{
+            NODE_4 => {
+                let mut node = Sorted::new();
+                children.into_iter().for_each(|(digit, child)| node.insert(digit, child));
+                Self::Node4(node)
+            }
+            NODE_16 => {
+                let mut node = NodeBox::new(Sorted::new());
+                children.into_iter().for_each(|(digit, child)| node.insert(digit, child));
+                Self::Node16(node)
+            }
+            NODE_48 => {
+                let mut node = NodeBox::new(Indexed::new());
+                children.into_iter().for_each(|(digit, child)| node.insert(digit, child));
+                Self::Node48(node)
+            }
+            _ => {
+                let mut node = NodeBox::new(Children::new(|_| T::default()));
+                children.into_iter().for_each(|(digit, child)| node[digit] = child);
+                Self::Full(node)
+            }
+        }
+    }
+
+    //children that are not vacant
+    fn into_children(self) -> Vec<(usize, T)>{
+        let children: Vec<(usize, T)> = match self{
+            Self::Node4(node) => node.digits.into_iter().map(|x| x as usize).zip(node.children).take(node.len as usize).collect(),
+            Self::Node16(node) => {
+                let node = node.into_inner();
+                node.digits.into_iter().map(|x| x as usize).zip(node.children).take(node.len as usize).collect()
+            }
+            Self::Node48(node) => {
+                let Indexed{len, slots, children} = node.into_inner();
+                let mut children = children.into_iter().take(len as usize).map(Some).collect::<Vec<_>>();
+                (0..256).filter(|x| slots[*x] != 0).map(|x| (x, children[(slots[x] - 1) as usize].take().unwrap())).collect()
+            }
+            Self::Full(mut node) => node.iter_mut().enumerate().map(|(digit, x)| (digit, std::mem::take(x))).collect()
+        };
+        children.into_iter().filter(|(_, x)| !x.is_vacant()).collect()
+    }
+
+    pub(crate) fn new_pair(digit_1: usize, child_1: T, digit_2: usize, child_2: T) -> Self{
+        Self::from_children(vec![(digit_1, child_1), (digit_2, child_2)])
+    }
+
+    pub(crate) fn get(&self, digit: usize) -> Option<&T>{
+        match self{
+            Self::Node4(node) => node.position(digit).ok().map(|x| &node.children[x]),
+            Self::Node16(node) => node.position(digit).ok().map(|x| &node.children[x]),
+            Self::Node48(node) => node.slots[digit].checked_sub(1).map(|x| &node.children[x as usize]),
+            Self::Full(node) => Some(&node[digit])
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, digit: usize) -> Option<&mut T>{
+        match self{
+            Self::Node4(node) => node.position(digit).ok().map(|x| &mut node.children[x]),
+            Self::Node16(node) => node.position(digit).ok().map(|x| &mut node.children[x]),
+            Self::Node48(node) => node.slots[digit].checked_sub(1).map(|x| &mut node.children[x as usize]),
+            Self::Full(node) => Some(&mut node[digit])
+        }
+    }
+
+    //slots in use, in digit order except for Node48
+    pub(crate) fn slots(&self) -> &[T]{
+        match self{
+            Self::Node4(node) => &node.children[..node.len()],
+            Self::Node16(node) => &node.children[..node.len()],
+            Self::Node48(node) => &node.children[..node.len()],
+            Self::Full(node) => node
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize{
+        self.slots().iter().filter(|x| !x.is_vacant()).count()
+    }
+
+    //the digit must not already have a child unless it is vacant
+    pub(crate) fn insert(&mut self, digit: usize, child: T){
+        match self{
+            Self::Node4(node) if node.len() < NODE_4 => node.insert(digit, child),
+            Self::Node16(node) if node.len() < NODE_16 => node.insert(digit, child),
+            Self::Node48(node) if node.len() < NODE_48 => node.insert(digit, child),
+            Self::Full(node) => node[digit] = child,
+            _ => {
+                //full so grow into the next kind of node
+                let mut children = std::mem::replace(self, Self::Node4(Sorted::new())).into_children();
+                children.push((digit, child));
+                *self = Self::from_children(children);
+            }
+        }
+    }
+
+    //drops vacant slots, shrinking into a smaller kind of node once that would be no more than three quarters full
+    pub(crate) fn remove_vacant(&mut self){
+        if let Some(capacity) = self.capacity_without_vacant(){
+            let children = std::mem::replace(self, Self::Node4(Sorted::new())).into_children();
+            *self = Self::with_capacity(capacity, children);
+        }
+    }
+
+    //capacity remove_vacant would rebuild the node with, None if it would leave it as it is
+    pub(crate) fn capacity_without_vacant(&self) -> Option<usize>{
+        let len = self.len();
+        let capacity = Self::capacity_for(len);
+        let shrink = (capacity < self.capacity()) && (len <= ((capacity * 3) / 4));
+        let has_vacant = !matches!(self, Self::Full(_)) && (len != self.slots().len());
+        if shrink {Some(capacity)} else if has_vacant {Some(self.capacity())} else {None}
+    }
+
+    //bytes allocated for the node outside the list holding it, none for a Node4 which is inside it
+    pub(crate) fn get_memory_size(&self) -> usize{
+        match self{
+            Self::Node4(_) => 0,
+            Self::Node16(node) => node.allocated_size(),
+            Self::Node48(node) => node.allocated_size(),
+            Self::Full(node) => node.allocated_size()
+        }
+    }
+
+    //bytes of the slots the node has room for, whether they are used or not
+    pub(crate) fn get_slots_size(&self) -> usize{
+        self.capacity() * size_of::<T>()
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_, T, BITS>{
+        match self{
+            Self::Node4(node) => Iter::Sorted(node.digits[..node.len()].iter().zip(node.children[..node.len()].iter())),
+            Self::Node16(node) => Iter::Sorted(node.digits[..node.len()].iter().zip(node.children[..node.len()].iter())),
+            Self::Node48(node) => Iter::Indexed(node, 0..Self::FAN_OUT),
+            Self::Full(node) => Iter::Full(node.iter().enumerate())
+        }
+    }
+}
+
+//(digit, child) in digit order
+pub(crate) enum Iter<'a, T, const BITS: usize>{
+    Sorted(Zip<slice::Iter<'a, u8>, slice::Iter<'a, T>>),
+    Indexed(&'a Indexed<T>, Range<usize>),
+    Full(Enumerate<slice::Iter<'a, T>>)
+}
+
+impl<'a, T, const BITS: usize> Iterator for Iter<'a, T, BITS>{
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item>{
+        match self{
+            Self::Sorted(iter) => iter.next().map(|(digit, child)| (*digit as usize, child)),
+            Self::Indexed(node, digits) => digits.find(|x| node.slots[*x] != 0).map(|x| (x, &node.children[(node.slots[x] - 1) as usize])),
+            Self::Full(iter) => iter.next()
+        }
+    }
+}
+
+impl<T, const BITS: usize> DoubleEndedIterator for Iter<'_, T, BITS>{
+    fn next_back(&mut self) -> Option<Self::Item>{
+        match self{
+            Self::Sorted(iter) => iter.next_back().map(|(digit, child)| (*digit as usize, child)),
+            Self::Indexed(node, digits) => digits.rfind(|x| node.slots[*x] != 0).map(|x| (x, &node.children[(node.slots[x] - 1) as usize])),
+            Self::Full(iter) => iter.next_back()
+        }
+    }
+}
+
+impl<T: Slot + Debug, const BITS: usize, A: Alloc> Debug for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+//under loom the copy's children are given atomics of their own before anyone else can reach them, see model
+#[cfg(all(rust_map_loom, feature = "optimistic"))]
+impl<T: Slot, const BITS: usize, A: Alloc> Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn moved(mut self) -> Self{
+        let children: &mut [T] = match &mut self{
+            Self::Node4(node) => &mut node.children,
+            Self::Node16(node) => &mut node.children,
+            Self::Node48(node) => &mut node.children,
+            Self::Full(node) => &mut node[..]
+        };
+        children.iter_mut().for_each(T::moved);
+        self
+    }
+}
+
+//the children are moved bitwise, every node holding them is new
+#[cfg(feature = "optimistic")]
+impl<T: Slot, const BITS: usize, A: Alloc> CopyOnWrite for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    unsafe fn copy(&self) -> Self{
+        let copy = unsafe{
+            match self{
+                Self::Node4(node) => Self::Node4(std::ptr::read(node)),
+                Self::Node16(node) => Self::Node16(NodeBox::new(std::ptr::read(&**node))),
+                Self::Node48(node) => Self::Node48(NodeBox::new(std::ptr::read(&**node))),
+                Self::Full(node) => Self::Full(NodeBox::new(std::ptr::read(&**node)))
+            }
+        };
+        #[cfg(rust_map_loom)]
+        let copy = copy.moved();
+        copy
+    }
+
+    unsafe fn free_copied(self){
+        match self{
+            Self::Node4(node) => std::mem::forget(node),
+            Self::Node16(node) => std::mem::forget(node.into_inner()),
+            Self::Node48(node) => std::mem::forget(node.into_inner()),
+            Self::Full(node) => std::mem::forget(node.into_inner())
+        }
+    }
+}
+
+impl<T: Slot + Clone, const BITS: usize, A: Alloc> Clone for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn clone(&self) -> Self{
+        Self::from_children(self.iter().filter(|(_, x)| !x.is_vacant()).map(|(digit, x)| (digit, x.clone())).collect())
+    }
+}
+
+//compared by contents, two lists holding the same children can be different kinds of node
+impl<T: Slot + PartialEq, const BITS: usize, A: Alloc> PartialEq for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn eq(&self, other: &Self) -> bool{
+        self.iter().filter(|(_, x)| !x.is_vacant()).eq(other.iter().filter(|(_, x)| !x.is_vacant()))
+    }
+}
+
+impl<T: Slot + Eq, const BITS: usize, A: Alloc> Eq for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{}
+
+impl<T: Slot + Hash, const BITS: usize, A: Alloc> Hash for Nodes<T, BITS, A> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        self.iter().filter(|(_, x)| !x.is_vacant()).for_each(|x| x.hash(state))
+    }
+}
+
+//the contents of a map, stored packed into one word, see Packed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum MapInternal<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc, L>{
+    Bucket(Bucket<N, V, B, COMPACT, A>),
+    List(NodeBox<L, A>),
+    Empty
+}
+
+//a list's pointer has its lowest bit set, which buckets and lists being at least 2 aligned leaves free
+const LIST_TAG: usize = 1;
+#[cfg(not(feature = "optimistic"))]
+const WORD_ALIGN: usize = 2;
+//with optimistic reads an empty word is numbered rather than null and has the next bit set, see empty_word, and a lock's
+//word with both set is a version of its value, see version
+#[cfg(feature = "optimistic")]
+const EMPTY_TAG: usize = 2;
+#[cfg(feature = "optimistic")]
+const WORD_ALIGN: usize = 4;
+
+//a value that packs into one word without its top 16 bits, a null word being its empty value. with optimistic reads
+//a lock is never given a word it held before while a reader is pinned, which a reader checking that what it read hasn't
+//changed relies on. a pointer can't come round again as what it pointed to isn't freed while the reader is pinned, and
+//a node moves between locks only up the map after being copied on its way down, so only empty words need telling apart
+pub(crate) trait Packed{
+    fn into_word(self) -> *mut u8;
+    //the word must have come from into_word, and it takes back ownership
+    unsafe fn from_word(word: *mut u8) -> Self;
+}
+
+impl<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc, L> Packed for MapInternal<N, V, B, COMPACT, A, L>{
+    fn into_word(self) -> *mut u8{
+        const {assert!(align_of::<L>() >= WORD_ALIGN)}
+        match self{
+            Self::Bucket(bucket) => ManuallyDrop::new(bucket).ptr.as_ptr(),
+            Self::List(list) => ManuallyDrop::new(list).0.as_ptr().cast::<u8>().map_addr(|x| x | LIST_TAG),
+            #[cfg(not(feature = "optimistic"))]
+            Self::Empty => null_mut(),
+            #[cfg(feature = "optimistic")]
+            Self::Empty => empty_word()
+        }
+    }
+
+    unsafe fn from_word(word: *mut u8) -> Self{
+        #[cfg(feature = "optimistic")]
+        if (word.addr() & EMPTY_TAG) != 0 {return Self::Empty}
+        if word.is_null() {Self::Empty}
+        else if (word.addr() & LIST_TAG) != 0 {Self::List(NodeBox(unsafe{NonNull::new_unchecked(word.map_addr(|x| x & !LIST_TAG).cast())}, PhantomData))}
+        else {Self::Bucket(Bucket{ptr: unsafe{NonNull::new_unchecked(word)}, _marker: PhantomData})}
+    }
+}
+
+//a number no empty word has had yet, shifted above the tags and wrapping within the 48 bits the lock leaves long after any
+//reader that could have seen the last one like it is done. threads take numbers a block at a time
+#[cfg(feature = "optimistic")]
+fn empty_word() -> *mut u8{
+    const BLOCK: usize = 1024;
+    const ADDRESS: usize = ((1u64 << 48) - 1) as usize;
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    thread_local!{
+        //the thread's next number and the end of its block
+        static NUMBERS: Cell<(usize, usize)> = const {Cell::new((0, 0))};
+    }
+    let number = NUMBERS.try_with(|x| {
+        let (mut next, mut end) = x.get();
+        if next == end{
+            next = NEXT.fetch_add(BLOCK, atomic::Ordering::Relaxed);
+            end = next.wrapping_add(BLOCK);
+        }
+        x.set((next.wrapping_add(1), end));
+        next
+    }).unwrap_or_else(|_| NEXT.fetch_add(1, atomic::Ordering::Relaxed));
+    std::ptr::without_provenance_mut(((number << 2) | EMPTY_TAG) & ADDRESS)
+}
+
+//with optimistic reads, readers go through a locked value without taking the lock so it can't be changed in place. the
+//writer changes a shallow copy instead, a node of its own holding what the value held, and once the copy has replaced
+//it the value's own nodes are freed without dropping what they held
+#[cfg(feature = "optimistic")]
+pub(crate) trait CopyOnWrite{
+    //the copy owns what self does, so self must only be freed with free_copied
+    unsafe fn copy(&self) -> Self;
+    unsafe fn free_copied(self);
+}
+
+#[cfg(feature = "optimistic")]
+impl<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc, L: CopyOnWrite> CopyOnWrite for MapInternal<N, V, B, COMPACT, A, L>{
+    unsafe fn copy(&self) -> Self{
+        match self{
+            Self::Bucket(bucket) => Self::Bucket(unsafe{bucket.copy()}),
+            Self::List(list) => Self::List(unsafe{list.copy()}),
+            Self::Empty => Self::Empty
+        }
+    }
+
+    unsafe fn free_copied(self){
+        match self{
+            Self::Bucket(bucket) => unsafe{bucket.free_copied()},
+            Self::List(list) => unsafe{list.free_copied()},
+            Self::Empty => ()
+        }
+    }
+}
+
+//an owned node like a Box, allocated by A, and without a Box's claim to be the only pointer to it so the copies of a
+//list handed out while it is packed into a word can be read at the same time
+pub(crate) struct NodeBox<T, A: Alloc>(NonNull<T>, PhantomData<A>);
+
+unsafe impl<T: Send, A: Alloc> Send for NodeBox<T, A>{}
+unsafe impl<T: Sync, A: Alloc> Sync for NodeBox<T, A>{}
+
+impl<T, A: Alloc> NodeBox<T, A>{
+    pub(crate) fn new(value: T) -> Self{
+        let ptr = A::alloc(Layout::new::<T>()).cast::<T>();
+        unsafe{ptr.write(value)}
+        Self(ptr, PhantomData)
+    }
+
+    pub(crate) fn allocated_size(&self) -> usize{
+        A::allocated_size(Layout::new::<T>())
+    }
+
+    pub(crate) fn into_inner(self) -> T{
+        let this = ManuallyDrop::new(self);
+        let value = unsafe{this.0.read()};
+        A::dealloc(this.0.cast(), Layout::new::<T>());
+        value
+    }
+}
+
+impl<T, A: Alloc> Deref for NodeBox<T, A>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        unsafe{self.0.as_ref()}
+    }
+}
+
+impl<T, A: Alloc> DerefMut for NodeBox<T, A>{
+    fn deref_mut(&mut self) -> &mut T{
+        unsafe{self.0.as_mut()}
+    }
+}
+
+impl<T, A: Alloc> Drop for NodeBox<T, A>{
+    fn drop(&mut self){
+        unsafe{std::ptr::drop_in_place(self.0.as_ptr())}
+        A::dealloc(self.0.cast(), Layout::new::<T>());
+    }
+}
+
+#[cfg(feature = "optimistic")]
+impl<T: CopyOnWrite, A: Alloc> CopyOnWrite for NodeBox<T, A>{
+    unsafe fn copy(&self) -> Self{
+        Self::new(unsafe{(**self).copy()})
+    }
+
+    unsafe fn free_copied(self){
+        unsafe{self.into_inner().free_copied()}
+    }
+}
+
+impl<T: Clone, A: Alloc> Clone for NodeBox<T, A>{
+    fn clone(&self) -> Self{
+        Self::new((**self).clone())
+    }
+}
+
+impl<T: Debug, A: Alloc> Debug for NodeBox<T, A>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        (**self).fmt(f)
+    }
+}
+
+impl<T: PartialEq, A: Alloc> PartialEq for NodeBox<T, A>{
+    fn eq(&self, other: &Self) -> bool{
+        **self == **other
+    }
+}
+
+impl<T: Eq, A: Alloc> Eq for NodeBox<T, A>{}
+
+impl<T: Hash, A: Alloc> Hash for NodeBox<T, A>{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        (**self).hash(state)
+    }
+}
+
+//a packed value in a single word, read through a decoded copy and written back when the copy is done with
+pub(crate) struct Word<T: Packed>{
+    word: *mut u8,
+    _marker: PhantomData<T>
+}
+
+unsafe impl<T: Packed + Send> Send for Word<T>{}
+unsafe impl<T: Packed + Sync> Sync for Word<T>{}
+
+pub(crate) struct Ref<'a, T>{
+    value: ManuallyDrop<T>,
+    _marker: PhantomData<&'a T>
+}
+
+pub(crate) struct RefMut<'a, T: Packed>{
+    word: &'a mut *mut u8,
+    value: ManuallyDrop<T>
+}
+
+impl<T: Packed> Word<T>{
+    pub(crate) const fn empty() -> Self{
+        Self{word: null_mut(), _marker: PhantomData}
+    }
+
+    pub(crate) fn new(value: T) -> Self{
+        Self{word: value.into_word(), _marker: PhantomData}
+    }
+
+    pub(crate) fn get(&self) -> Ref<'_, T>{
+        Ref{value: ManuallyDrop::new(unsafe{T::from_word(self.word)}), _marker: PhantomData}
+    }
+
+    pub(crate) fn get_mut(&mut self) -> RefMut<'_, T>{
+        let value = ManuallyDrop::new(unsafe{T::from_word(self.word)});
+        RefMut{word: &mut self.word, value}
+    }
+}
+
+impl<T: Packed> Drop for Word<T>{
+    fn drop(&mut self){
+        drop(unsafe{T::from_word(self.word)})
+    }
+}
+
+impl<T> Deref for Ref<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.value
+    }
+}
+
+impl<T: Packed> Deref for RefMut<'_, T>{
+    type Target = T;
+    fn deref(&self) -> &T{
+        &self.value
+    }
+}
+
+impl<T: Packed> DerefMut for RefMut<'_, T>{
+    fn deref_mut(&mut self) -> &mut T{
+        &mut self.value
+    }
+}
+
+impl<T: Packed> Drop for RefMut<'_, T>{
+    fn drop(&mut self){
+        *self.word = unsafe{ManuallyDrop::take(&mut self.value)}.into_word();
+    }
+}
+
+impl<T: Packed + Debug> Debug for Word<T>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        (*self.get()).fmt(f)
+    }
+}
+
+impl<T: Packed + Clone> Clone for Word<T>{
+    fn clone(&self) -> Self{
+        Self::new((*self.get()).clone())
+    }
+}
+
+impl<T: Packed + PartialEq> PartialEq for Word<T>{
+    fn eq(&self, other: &Self) -> bool{
+        *self.get() == *other.get()
+    }
+}
+
+impl<T: Packed + Eq> Eq for Word<T>{}
+
+impl<T: Packed + Hash> Hash for Word<T>{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        (*self.get()).hash(state)
+    }
+}
+
+//entries of a leaf sorted by key, in one allocation sized to exactly the number of entries so a bucket is a single thin
+//pointer. the allocation starts with the number of entries unless B is 1, then if COMPACT the number of leading key
+//bytes skipped, which are implied by where the bucket is in the trie so only the rest of each key is stored
+pub(crate) struct Bucket<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc>{
+    ptr: NonNull<u8>,
+    _marker: PhantomData<([u8; N], V, A)>
+}
+
+unsafe impl<const N: usize, V: Send, const B: usize, const COMPACT: bool, A: Alloc> Send for Bucket<N, V, B, COMPACT, A>{}
+unsafe impl<const N: usize, V: Sync, const B: usize, const COMPACT: bool, A: Alloc> Sync for Bucket<N, V, B, COMPACT, A>{}
+
+impl<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc> Bucket<N, V, B, COMPACT, A>{
+
+    const HEADER: usize = ((B != 1) as usize) + (COMPACT as usize);
+
+    //key bytes a bucket in a list at depth can skip, the ones covered by the list's prefix and the bucket's digit
+    pub(crate) fn skip_in_list<const BITS: usize>(depth: usize) -> usize{
+        if COMPACT {(((depth + 1) * BITS) / 8).min(N).min(u8::MAX as usize)} else {0}
+    }
+
+    //layout of one entry, the key suffix then the value, and the offset of the value
+    fn entry_layout(skip: usize) -> (Layout, usize){
+        let (layout, offset) = Layout::array::<u8>(N - skip).unwrap().extend(Layout::new::<V>()).unwrap();
+        (layout.pad_to_align(), offset)
+    }
+
+    //layout of a bucket of len entries and the offset of the first entry, padded so it is what the allocator is asked for
+    fn layout(len: usize, skip: usize) -> (Layout, usize){
+        let entry = Self::entry_layout(skip).0;
+        let entries = Layout::from_size_align(entry.size() * len, entry.align()).unwrap();
+        let (layout, offset) = Layout::from_size_align(Self::HEADER, WORD_ALIGN).unwrap().extend(entries).unwrap();
+        (layout.pad_to_align(), offset)
+    }
+
+    fn from_entries(skip: usize, len: usize, entries: impl Iterator<Item = ([u8; N], V)>) -> Self{
+        let (layout, offset) = Self::layout(len, skip);
+        let (entry, value_offset) = Self::entry_layout(skip);
+        let ptr = A::alloc(layout);
+        let mut written = 0;
+        for (key, value) in entries.take(len){
+            unsafe{
+                let entry_ptr = ptr.as_ptr().add(offset + (written * entry.size()));
+                entry_ptr.copy_from_nonoverlapping(key[skip..].as_ptr(), N - skip);
+                entry_ptr.add(value_offset).cast::<V>().write(value);
+            }
+            written += 1;
+        }
+        assert_eq!(written, len);
+        if B != 1 {unsafe{ptr.as_ptr().write(len as u8)}}
+        if COMPACT {unsafe{ptr.as_ptr().add(Self::HEADER - 1).write(skip as u8)}}
+        Self{ptr, _marker: PhantomData}
+    }
+
+    pub(crate) fn len(&self) -> usize{
+        if B == 1 {1} else {unsafe{*self.ptr.as_ptr() as usize}}
+    }
+
+    pub(crate) fn skip(&self) -> usize{
+        if COMPACT {unsafe{*self.ptr.as_ptr().add(Self::HEADER - 1) as usize}} else {0}
+    }
+
+    fn entry_ptr(&self, i: usize) -> *mut u8{
+        let skip = self.skip();
+        unsafe{self.ptr.as_ptr().add(Self::layout(0, skip).1 + (i * Self::entry_layout(skip).0.size()))}
+    }
+
+    fn suffix(&self, i: usize) -> &[u8]{
+        unsafe{slice::from_raw_parts(self.entry_ptr(i), N - self.skip())}
+    }
+
+    fn value_ptr(&self, i: usize) -> *mut V{
+        unsafe{self.entry_ptr(i).add(Self::entry_layout(self.skip()).1).cast()}
+    }
+
+    //the skipped bytes are taken from prefix
+    pub(crate) fn key(&self, i: usize, prefix: [u8; N]) -> [u8; N]{
+        let skip = self.skip();
+        let mut key = prefix;
+        key[skip..].copy_from_slice(self.suffix(i));
+        key
+    }
+
+    pub(crate) fn value(&self, i: usize) -> &V{
+        unsafe{&*self.value_ptr(i)}
+    }
+
+    //the key's skipped bytes are assumed to match
+    pub(crate) fn search(&self, key: [u8; N]) -> Result<usize, usize>{
+        let skip = self.skip();
+        let (mut low, mut high) = (0, self.len());
+        while low < high{
+            let middle = (low + high) / 2;
+            match self.suffix(middle).cmp(&key[skip..]){
+                Ordering::Less => low = middle + 1,
+                Ordering::Greater => high = middle,
+                Ordering::Equal => return Ok(middle)
+            }
+        }
+        Err(low)
+    }
+
+    pub(crate) fn get_memory_size(&self) -> usize{
+        A::allocated_size(Self::layout(self.len(), self.skip()).0)
+    }
+}
+
+impl<const N: usize, V: Copy, const B: usize, const COMPACT: bool, A: Alloc> Bucket<N, V, B, COMPACT, A>{
+
+    //entries must be sorted, with at least one and at most B of them
+    pub(crate) fn new(skip: usize, entries: &[([u8; N], V)]) -> Self{
+        Self::from_entries(skip, entries.len(), entries.iter().copied())
+    }
+
+    pub(crate) fn entry(&self, i: usize, prefix: [u8; N]) -> ([u8; N], V){
+        (self.key(i, prefix), *self.value(i))
+    }
+
+    pub(crate) fn iter(&self, prefix: [u8; N]) -> impl DoubleEndedIterator<Item = ([u8; N], V)> + '_{
+        (0..self.len()).map(move |i| self.entry(i, prefix))
+    }
+
+    pub(crate) fn get(&self, key: [u8; N]) -> Option<V>{
+        self.search(key).ok().map(|x| *self.value(x))
+    }
+
+    pub(crate) fn update(&mut self, position: usize, value: V){
+        unsafe{self.value_ptr(position).write(value)}
+    }
+
+    //the bucket must not be full
+    pub(crate) fn insert(&mut self, position: usize, entry: ([u8; N], V)){
+        let (len, skip) = (self.len(), self.skip());
+        *self = Self::from_entries(skip, len + 1, self.iter(entry.0).take(position).chain(std::iter::once(entry)).chain(self.iter(entry.0).skip(position)));
+    }
+
+    //the bucket must have more than one entry
+    pub(crate) fn remove(&mut self, position: usize){
+        let (len, skip) = (self.len(), self.skip());
+        *self = Self::from_entries(skip, len - 1, self.iter([0; N]).enumerate().filter(|x| x.0 != position).map(|x| x.1));
+    }
+
+    //the same entries skipping a different number of bytes, prefix supplies any that were skipped before but aren't now
+    pub(crate) fn with_skip(&self, skip: usize, prefix: [u8; N]) -> Self{
+        Self::from_entries(skip, self.len(), self.iter(prefix))
+    }
+
+    //the entries with one more added, as a list's depth and a bucket for each of its children
+    //the new entry's key supplies the skipped bytes, which every entry has in common
+    pub(crate) fn split<const BITS: usize>(&self, position: usize, entry: ([u8; N], V)) -> (usize, Vec<(usize, Self)>){
+        let mut entries = self.iter(entry.0).collect::<Vec<_>>();
+        entries.insert(position, entry);
+        let depth = get_first_different_digit::<N, BITS>(entries[0].0, entries[entries.len() - 1].0).unwrap();
+        let groups = entries.chunk_by(|x, y| get_index::<N, BITS>(x.0, depth) == get_index::<N, BITS>(y.0, depth))
+            .map(|x| (get_index::<N, BITS>(x[0].0, depth), Self::new(Self::skip_in_list::<BITS>(depth), x)))
+            .collect();
+        (depth, groups)
+    }
+}
+
+impl<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc> Drop for Bucket<N, V, B, COMPACT, A>{
+    fn drop(&mut self){
+        let (len, skip) = (self.len(), self.skip());
+        unsafe{
+            (0..len).for_each(|i| std::ptr::drop_in_place(self.value_ptr(i)));
+            A::dealloc(self.ptr, Self::layout(len, skip).0);
+        }
+    }
+}
+
+//the values are moved bitwise with the rest of the allocation
+#[cfg(feature = "optimistic")]
+impl<const N: usize, V, const B: usize, const COMPACT: bool, A: Alloc> CopyOnWrite for Bucket<N, V, B, COMPACT, A>{
+    unsafe fn copy(&self) -> Self{
+        let layout = Self::layout(self.len(), self.skip()).0;
+        let ptr = A::alloc(layout);
+        unsafe{ptr.copy_from_nonoverlapping(self.ptr, layout.size())}
+        Self{ptr, _marker: PhantomData}
+    }
+
+    unsafe fn free_copied(self){
+        let layout = Self::layout(self.len(), self.skip()).0;
+        A::dealloc(ManuallyDrop::new(self).ptr, layout);
+    }
+}
+
+impl<const N: usize, V: Clone, const B: usize, const COMPACT: bool, A: Alloc> Clone for Bucket<N, V, B, COMPACT, A>{
+    fn clone(&self) -> Self{
+        Self::from_entries(self.skip(), self.len(), (0..self.len()).map(|i| (self.key(i, [0; N]), self.value(i).clone())))
+    }
+}
+
+//skipped key bytes show as 0
+impl<const N: usize, V: Debug, const B: usize, const COMPACT: bool, A: Alloc> Debug for Bucket<N, V, B, COMPACT, A>{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        f.debug_list().entries((0..self.len()).map(|i| (self.key(i, [0; N]), self.value(i)))).finish()
+    }
+}
+
+impl<const N: usize, V: PartialEq, const B: usize, const COMPACT: bool, A: Alloc> PartialEq for Bucket<N, V, B, COMPACT, A>{
+    fn eq(&self, other: &Self) -> bool{
+        (self.len() == other.len()) && (0..self.len()).all(|i| (self.key(i, [0; N]) == other.key(i, [0; N])) && (self.value(i) == other.value(i)))
+    }
+}
+
+impl<const N: usize, V: Eq, const B: usize, const COMPACT: bool, A: Alloc> Eq for Bucket<N, V, B, COMPACT, A>{}
+
+impl<const N: usize, V: Hash, const B: usize, const COMPACT: bool, A: Alloc> Hash for Bucket<N, V, B, COMPACT, A>{
+    fn hash<H: Hasher>(&self, state: &mut H){
+        (0..self.len()).for_each(|i| (self.key(i, [0; N]), self.value(i)).hash(state))
+    }
+}
@@ -1,107 +1,172 @@
-use crate::{get_index, InsertOrUpdateResult};
+use std::cmp::Reverse;
+use crate::{arena, get_first_different_digit, get_index, get_prefix, get_prefix_with_digit, get_split_depth, leading_zeroes_order, BitsPerLevel, InsertOrUpdateResult, MapStats, MemoryUsage, SupportedBitsPerLevel, ValidationError, Violation};
+use crate::dump::Dump;
+use crate::arena::Heap;
+use crate::node::{self, Slot, Word};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct Map<const N: usize, V>(MapInternal<N, V>);
+pub struct Map<const N: usize, V, const BITS: usize = 2, const B: usize = 1, const COMPACT: bool = false, const ARENA: bool = false>(Word<MapInternal<N, V, BITS, B, COMPACT, ARENA>>) where BitsPerLevel<BITS>: SupportedBitsPerLevel;
 
+type MapInternal<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> = node::MapInternal<N, V, B, COMPACT, Heap<ARENA>, List<N, V, BITS, B, COMPACT, ARENA>>;
+type Bucket<const N: usize, V, const B: usize, const COMPACT: bool, const ARENA: bool> = node::Bucket<N, V, B, COMPACT, Heap<ARENA>>;
+type NodeBox<T, const ARENA: bool> = node::NodeBox<T, Heap<ARENA>>;
+type Nodes<T, const BITS: usize, const ARENA: bool> = node::Nodes<T, BITS, Heap<ARENA>>;
+
+//the digits between the parent list's depth and this list's depth are the same for every key below it so are skipped,
+//prefix holds those bits (and everything above them) so a key can be checked against them
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum MapInternal<const N: usize, V>{
-    Item(Box<([u8; N], V)>),
-    List(Box<[Map<N, V>; 4]>),
-    Empty
+struct List<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    depth: usize,
+    prefix: [u8; N],
+    children: Nodes<Map<N, V, BITS, B, COMPACT, ARENA>, BITS, ARENA>
+}
+
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> List<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn get_min(&self) -> Option<([u8; N], V)>{
+        self.children.iter().find_map(|x| x.1.get_min().map(|y| self.restore(x.0, y)))
+    }
+
+    fn get_max(&self) -> Option<([u8; N], V)>{
+        self.children.iter().rev().find_map(|x| x.1.get_max().map(|y| self.restore(x.0, y)))
+    }
+
+    fn get_child_skip(&self) -> usize{
+        Bucket::<N, V, B, COMPACT, ARENA>::skip_in_list::<BITS>(self.depth)
+    }
+
+    //puts back the key bytes a compact bucket below the child at digit skipped, which it reads as 0
+    fn restore(&self, digit: usize, entry: ([u8; N], V)) -> ([u8; N], V){
+        if !COMPACT {return entry}
+        let prefix = get_prefix_with_digit::<N, BITS>(self.prefix, self.depth, digit);
+        (std::array::from_fn(|i| entry.0[i] | prefix[i]), entry.1)
+    }
+}
+
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Default for Map<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn default() -> Self{
+        Self(Word::empty())
+    }
 }
 
-impl<const N: usize, V: Copy> MapInternal<N, V> {
-    fn new_item(key: [u8; N], value: V) -> Self{
-        Self::Item(Box::new((key, value)))
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Slot for Map<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    fn is_vacant(&self) -> bool{
+        matches!(*self.0.get(), MapInternal::Empty)
     }
 }
 
-impl<const N: usize, V: Copy> Map<N, V>{
+impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> Map<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
 
     pub fn get_used_percent(&self) -> f64{
         (((size_of::<[u8; N]>() + size_of::<V>()) * self.len()) as f64) / (self.get_memory_size() as f64)
     }
 
     pub fn get_memory_size(&self) -> usize{
-        size_of::<Self>() +
-            match &self.0 {
-                MapInternal::Item(_) => size_of::<[u8; N]>() + size_of::<V>(),
-                MapInternal::List(list) => list.iter().map(|x| x.get_memory_size()).sum(),
-                MapInternal::Empty => 0
+        self.get_memory_usage().total()
+    }
+
+    //bytes of buckets, lists and slots, as allocated
+    pub fn get_memory_usage(&self) -> MemoryUsage{
+        let mut usage = MemoryUsage{slots: size_of::<Self>(), ..MemoryUsage::default()};
+        self.add_memory_usage(&mut usage);
+        usage
+    }
+
+    fn add_memory_usage(&self, usage: &mut MemoryUsage){
+        let internal = self.0.get();
+        Self::add_node_memory_usage(&internal, usage);
+        if let MapInternal::List(list) = &*internal {list.children.slots().iter().for_each(|x| x.add_memory_usage(usage))}
+    }
+
+    //what the bucket or list here allocated, the slots of a list's children but not what is below them
+    fn add_node_memory_usage(internal: &MapInternal<N, V, BITS, B, COMPACT, ARENA>, usage: &mut MemoryUsage){
+        match internal{
+            MapInternal::Bucket(bucket) => usage.buckets += bucket.get_memory_size(),
+            MapInternal::List(list) => {
+                let slots = list.children.get_slots_size();
+                usage.lists += (list.allocated_size() + list.children.get_memory_size()) - slots;
+                usage.slots += slots;
             }
+            MapInternal::Empty => ()
+        }
     }
 
     pub fn is_empty(&self) -> bool{
-        match &self.0{
-            MapInternal::Item(_) => false,
-            MapInternal::List(list) => list.iter().all(|x| x.is_empty()),
+        match &*self.0.get(){
+            MapInternal::Bucket(_) => false,
+            MapInternal::List(list) => list.children.slots().iter().all(|x| x.is_empty()),
             MapInternal::Empty => true
         }
     }
 
     pub fn depth(&self) -> usize{
-        match &self.0{
-            MapInternal::Item(_) => 1,
-            MapInternal::List(list) => 1 + list.iter().map(|x| x.depth()).max().unwrap(),
+        match &*self.0.get(){
+            MapInternal::Bucket(_) => 1,
+            MapInternal::List(list) => 1 + list.children.slots().iter().map(|x| x.depth()).max().unwrap(),
             MapInternal::Empty => 0
         }
     }
 
     pub fn len(&self) -> usize{
-        match &self.0{
-            MapInternal::Item(_) => 1,
-            MapInternal::List(list) => list.iter().map(|x| x.len()).sum(),
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => bucket.len(),
+            MapInternal::List(list) => list.children.slots().iter().map(|x| x.len()).sum(),
             MapInternal::Empty => 0
         }
     }
 
     pub const fn new() -> Self{
-        Self(MapInternal::Empty)
+        const {assert!((B > 0) && (B <= u8::MAX as usize), "buckets must hold between 1 and 255 entries")}
+        Self(Word::empty())
     }
 
-    pub fn clear(&mut self){ 
-        self.0 = MapInternal::Empty;
+    pub fn clear(&mut self){
+        *self.0.get_mut() = MapInternal::Empty;
     }
 
     pub fn get(&self, key: [u8; N]) -> Option<V>{
-        self.get_internal(key, 0)
-    }
-
-    fn get_internal(&self, key: [u8; N], depth: usize) -> Option<V>{
-        match &self.0{
-            MapInternal::Item(item) => if item.0 == key {Some(item.1)} else {None},
-            MapInternal::List(list) => list[get_index(key, depth)].get_internal(key, depth + 1),
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => bucket.get(key),
+            MapInternal::List(list) => {
+                //a compact bucket doesn't store the bytes the list's prefix covers so they are checked here
+                if COMPACT && get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return None}
+                list.children.get(get_index::<N, BITS>(key, list.depth)).and_then(|x| x.get(key))
+            }
             MapInternal::Empty => None
         }
     }
 
     pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
-        self.get_or_closest_by_key_leading_zeroes_internal(key, include_key, 0)
-    }
-    
-    fn get_or_closest_by_key_leading_zeroes_internal(&self, key: [u8; N], include_key: bool, depth: usize) -> Option<([u8; N], V)>{
-        match &self.0{
-            MapInternal::Item(item_key_value) => {
-                if (item_key_value.0 != key) || include_key {Some((item_key_value.0, item_key_value.1))} else {None}
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => {
+                //most leading bits in common, then numerically nearest without looping around, as the keys with the most
+                //in common are all on one side of key that is the lowest above it or the highest below it
+                bucket.iter(key).filter(|x| (x.0 != key) || include_key)
+                    .min_by_key(|x| (Reverse(get_first_different_digit::<N, 1>(key, x.0).unwrap_or(N * 8)), if x.0 > key {x.0} else {x.0.map(|y| !y)}))
             }
             MapInternal::List(list) => {
-                let index = get_index(key, depth);
-                list[index].get_or_closest_by_key_leading_zeroes_internal(key, include_key, depth + 1)
-                .or(
-                    match index{
-                        0 => [1,2,3],
-                        1 => [0,2,3],
-                        2 => [3,1,0],
-                        _ => [2,1,0]
-                    }.iter().find_map(|i| list[*i].get_max())
-                )
+                match get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+                    //every key in the list has the same leading bits in common with key so take the numerically nearest
+                    Some(split_depth) => {
+                        if get_index::<N, BITS>(key, split_depth) < get_index::<N, BITS>(list.prefix, split_depth) {list.get_min()}
+                        else {list.get_max()}
+                    }
+                    None => {
+                        let index = get_index::<N, BITS>(key, list.depth);
+                        leading_zeroes_order::<BITS>(index).find_map(|i| {
+                            let child = list.children.get(i)?;
+                            if i == index {child.get_or_closest_by_key_leading_zeroes(key, include_key)}
+                            else if i < index {child.get_max().map(|x| list.restore(i, x))}
+                            else {child.get_min().map(|x| list.restore(i, x))}
+                        })
+                    }
+                }
             }
             MapInternal::Empty => None
         }
     }
 
     pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
-        let (result, found_left, found_right) = self.get_or_closest_by_key_internal(key, include_key, 0, None);
+        let (result, found_left, found_right) = self.get_or_closest_by_key_internal(key, include_key);
         if !loop_around || (found_left == found_right) {result}
         else if !found_left{
             [result, self.get_max()].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
@@ -111,46 +176,60 @@ impl<const N: usize, V: Copy> Map<N, V>{
         }
     }
 
-    fn get_or_closest_by_key_internal(&self, key: [u8; N], include_key: bool, depth: usize, closest: Option<([u8; N], V)>) -> (Option<([u8; N], V)>, bool, bool){
+    fn get_or_closest_by_key_internal(&self, key: [u8; N], include_key: bool) -> (Option<([u8; N], V)>, bool, bool){
         //go down to where key would be
         //if key is there and include key is true, return
         //if above is false then go up and down the right hand side of left index in list and left hand side of right index
         //if no left or right index then need to go up until there is one
-        match &self.0{
-            MapInternal::Item(item_key_value) => {
-                (if (item_key_value.0 != key) || include_key {Some((item_key_value.0, item_key_value.1))} else {None}, false, false)
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => {
+                //the bucket is on key's path so key has the bytes it skipped
+                let (exact, position) = match bucket.search(key){
+                    Ok(position) => (Some(bucket.entry(position, key)), position),
+                    Err(position) => (None, position)
+                };
+                let left = position.checked_sub(1).map(|x| bucket.entry(x, key));
+                let right = Some(position + (exact.is_some() as usize)).filter(|x| *x < bucket.len()).map(|x| bucket.entry(x, key));
+                let min = [
+                    if include_key {exact} else {None},
+                    left,
+                    right
+                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
+                (min, left.is_some(), right.is_some())
             }
             MapInternal::List(list) => {
-                let index = get_index(key, depth);
-                let (mut min, mut left, mut right) = list[index].get_or_closest_by_key_internal(key, include_key, depth + 1, closest);
+                if let Some(split_depth) = get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+                    //key is outside the skipped prefix so the whole list is to one side of it
+                    return if get_index::<N, BITS>(key, split_depth) < get_index::<N, BITS>(list.prefix, split_depth){
+                        let min = list.get_min();
+                        (min, false, min.is_some())
+                    }
+                    else{
+                        let max = list.get_max();
+                        (max, max.is_some(), false)
+                    }
+                }
+                let index = get_index::<N, BITS>(key, list.depth);
+                let (mut min, mut left, mut right) = match list.children.get(index){
+                    Some(child) => child.get_or_closest_by_key_internal(key, include_key),
+                    None => (None, false, false)
+                };
                 if !left{
-                    for i in (0..index).rev(){
-                        match list[i].get_max(){
-                            None => (),
-                            Some(left_item_key_value) => {
-                                left = true;
-                                min = [
-                                    min,
-                                    Some(left_item_key_value)
-                                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
-                                break;
-                            }
-                        }
+                    if let Some(left_item_key_value) = list.children.iter().rev().skip_while(|x| x.0 >= index).find_map(|x| x.1.get_max().map(|y| list.restore(x.0, y))){
+                        left = true;
+                        min = [
+                            min,
+                            Some(left_item_key_value)
+                        ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
                     }
                 }
                 if !right{
-                    for i in (index+1)..list.len(){
-                        match list[i].get_min(){
-                            None => (),
-                            Some(right_item_key_value) => {
-                                right = true;
-                                min = [
-                                    min,
-                                    Some(right_item_key_value)
-                                ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
-                                break;
-                            }
-                        }
+                    if let Some(right_item_key_value) = list.children.iter().skip_while(|x| x.0 <= index).find_map(|x| x.1.get_min().map(|y| list.restore(x.0, y))){
+                        right = true;
+                        min = [
+                            min,
+                            Some(right_item_key_value)
+                        ].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
                     }
                 }
                 (min, left, right)
@@ -190,21 +269,35 @@ impl<const N: usize, V: Copy> Map<N, V>{
     }
 
     pub fn get_min(&self) -> Option<([u8; N], V)>{
-        match &self.0{
-            MapInternal::Item(item_key_value) => Some((item_key_value.0, item_key_value.1)),
-            MapInternal::List(list) => list.iter().find_map(|x| x.get_min()),
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => bucket.iter([0; N]).next(),
+            MapInternal::List(list) => list.get_min(),
             MapInternal::Empty => None
         }
     }
 
     pub fn get_max(&self) -> Option<([u8; N], V)>{
-        match &self.0{
-            MapInternal::Item(item_key_value) => Some((item_key_value.0, item_key_value.1)),
-            MapInternal::List(list) => list.iter().rev().find_map(|x| x.get_max()),
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => bucket.iter([0; N]).next_back(),
+            MapInternal::List(list) => list.get_max(),
             MapInternal::Empty => None
         }
     }
 
+    //calls f with every entry in key order
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        self.for_each_internal(&mut f, [0; N]);
+    }
+
+    //prefix fills in the key bytes a compact bucket here leaves out
+    fn for_each_internal(&self, f: &mut impl FnMut([u8; N], V), prefix: [u8; N]){
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => bucket.iter(prefix).for_each(|x| f(x.0, x.1)),
+            MapInternal::List(list) => list.children.iter().for_each(|x| x.1.for_each_internal(f, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, x.0))),
+            MapInternal::Empty => ()
+        }
+    }
+
     pub fn insert_or_update(&mut self, key: [u8; N], value: V) -> InsertOrUpdateResult{
         self.insert_or_update_if(key, value, &|_,_| true)
     }
@@ -213,43 +306,87 @@ impl<const N: usize, V: Copy> Map<N, V>{
         self.insert_or_update_if_internal(key, value, should_update, 0)
     }
 
-    fn insert_or_update_if_internal(&mut self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, depth: usize) -> InsertOrUpdateResult{
-        match &mut self.0{
-            MapInternal::Item(item_key_value) => {
-                if item_key_value.0 == key{
-                    //update
-                    if should_update(&item_key_value.1, &value){
-                        item_key_value.1 = value;
-                        InsertOrUpdateResult::Updated
+    //skip is how many leading key bytes a compact bucket here can leave out
+    fn insert_or_update_if_internal(&mut self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, skip: usize) -> InsertOrUpdateResult{
+        let mut internal = self.0.get_mut();
+        match &mut *internal{
+            MapInternal::Bucket(bucket) => {
+                match bucket.search(key){
+                    Ok(position) => {
+                        //update
+                        if should_update(bucket.value(position), &value){
+                            bucket.update(position, value);
+                            InsertOrUpdateResult::Updated
+                        }
+                        else {InsertOrUpdateResult::Neither}
+                    }
+                    Err(position) => {
+                        //insert, restructuring into a list if the bucket is full
+                        if bucket.len() < B {bucket.insert(position, (key, value))}
+                        else {*internal = Self::split_bucket(bucket, position, (key, value))}
+                        InsertOrUpdateResult::Inserted
                     }
-                    else {InsertOrUpdateResult::Neither}
                 }
-                else{
-                    //insert and restructure
-                    self.0 = Self::deepen_tree((item_key_value.0, item_key_value.1), (key, value), depth);
-                    InsertOrUpdateResult::Inserted
+            }
+            MapInternal::List(list) => {
+                match get_split_depth::<N, BITS>(key, list.prefix, list.depth){
+                    Some(split_depth) => {
+                        //key leaves the skipped prefix so a new list goes in above this one
+                        let prefix = list.prefix;
+                        Self::split(&mut internal, prefix, key, value, split_depth);
+                        InsertOrUpdateResult::Inserted
+                    }
+                    None => {
+                        let index = get_index::<N, BITS>(key, list.depth);
+                        let child_skip = list.get_child_skip();
+                        match list.children.get_mut(index){
+                            Some(child) => child.insert_or_update_if_internal(key, value, should_update, child_skip),
+                            None => {
+                                list.children.insert(index, Self(Word::new(Self::new_item(child_skip, key, value))));
+                                InsertOrUpdateResult::Inserted
+                            }
+                        }
+                    }
                 }
             }
-            MapInternal::List(list) => list[get_index(key, depth)].insert_or_update_if_internal(key, value, should_update, depth + 1),
             MapInternal::Empty => {
-                self.0 = MapInternal::new_item(key, value);
+                *internal = Self::new_item(skip, key, value);
                 InsertOrUpdateResult::Inserted
             }
         }
     }
 
-    fn deepen_tree(item_1: ([u8; N], V), item_2: ([u8; N], V), depth: usize) -> MapInternal<N, V> {
-        let item_1_index = get_index(item_1.0, depth);
-        let item_2_index = get_index(item_2.0, depth);
-        let mut new_list = [const {Self::new()}; 4];
-        if item_1_index == item_2_index {
-            new_list[item_1_index].0 = Self::deepen_tree(item_1, item_2, depth + 1);
-        }
-        else{
-            new_list[item_1_index].0 = MapInternal::new_item(item_1.0, item_1.1);
-            new_list[item_2_index].0 = MapInternal::new_item(item_2.0, item_2.1);
+    fn new_item(skip: usize, key: [u8; N], value: V) -> MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        MapInternal::Bucket(Bucket::new(skip, &[(key, value)]))
+    }
+
+    //list at the first digit the entries differ at with a bucket for each digit
+    fn split_bucket(bucket: &Bucket<N, V, B, COMPACT, ARENA>, position: usize, entry: ([u8; N], V)) -> MapInternal<N, V, BITS, B, COMPACT, ARENA>{
+        let (depth, groups) = bucket.split::<BITS>(position, entry);
+        let children = Nodes::from_children(groups.into_iter().map(|(digit, x)| (digit, Self(Word::new(MapInternal::Bucket(x))))).collect());
+        MapInternal::List(NodeBox::new(List{depth, prefix: get_prefix::<N, BITS>(entry.0, depth), children}))
+    }
+
+    //a list whose children are all buckets with no more than B entries between them can go back to being a bucket
+    fn merge_list(list: &List<N, V, BITS, B, COMPACT, ARENA>, skip: usize) -> Option<Bucket<N, V, B, COMPACT, ARENA>>{
+        let mut entries = Vec::new();
+        for (digit, child) in list.children.iter(){
+            match &*child.0.get(){
+                MapInternal::Bucket(bucket) if (entries.len() + bucket.len()) <= B => entries.extend(bucket.iter(get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit))),
+                MapInternal::Empty => (),
+                _ => return None
+            }
         }
-        MapInternal::List(Box::new(new_list))
+        Some(Bucket::new(skip, &entries))
+    }
+
+    //replaces internal with a list at split_depth holding what was there and the new item
+    fn split(internal: &mut MapInternal<N, V, BITS, B, COMPACT, ARENA>, existing_key: [u8; N], key: [u8; N], value: V, split_depth: usize){
+        let children = Nodes::new_pair(
+            get_index::<N, BITS>(existing_key, split_depth), Self(Word::new(std::mem::replace(internal, MapInternal::Empty))),
+            get_index::<N, BITS>(key, split_depth), Self(Word::new(Self::new_item(Bucket::<N, V, B, COMPACT, ARENA>::skip_in_list::<BITS>(split_depth), key, value)))
+        );
+        *internal = MapInternal::List(NodeBox::new(List{depth: split_depth, prefix: get_prefix::<N, BITS>(key, split_depth), children}));
     }
 
     pub fn remove(&mut self, key: [u8; N]){
@@ -260,41 +397,164 @@ impl<const N: usize, V: Copy> Map<N, V>{
         self.remove_if_internal(key, should_remove, 0)
     }
 
-    fn remove_if_internal(&mut self, key: [u8; N], should_remove: &impl Fn(&V) -> bool, depth: usize) -> bool{
-        match &mut self.0{
-            MapInternal::Item(item_key_value) => {
-                if (item_key_value.0 == key) && should_remove(&item_key_value.1) {
-                    self.0 = MapInternal::Empty;
-                    true
+    //skip is how many leading key bytes a compact bucket here can leave out
+    fn remove_if_internal(&mut self, key: [u8; N], should_remove: &impl Fn(&V) -> bool, skip: usize) -> bool{
+        let mut internal = self.0.get_mut();
+        match &mut *internal{
+            MapInternal::Bucket(bucket) => {
+                match bucket.search(key){
+                    Ok(position) if should_remove(bucket.value(position)) => {
+                        if bucket.len() == 1 {*internal = MapInternal::Empty} else {bucket.remove(position)}
+                        true
+                    }
+                    _ => false
                 }
-                else {false}
             }
             MapInternal::List(list) => {
-                let removed = list[get_index(key, depth)].remove_if_internal(key, should_remove, depth + 1);
-                let mut item_count = 0;
-                if list.iter().all(|x| {
-                    match x.0{
-                        MapInternal::Item(_) => {
-                            item_count += 1;
-                            true
-                        },
-                        MapInternal::List(_) => false,
-                        MapInternal::Empty => true
+                let list = &mut **list; //a plain reference so its fields can be borrowed separately
+                if get_split_depth::<N, BITS>(key, list.prefix, list.depth).is_some() {return false}
+                let child_skip = list.get_child_skip();
+                let removed = list.children.get_mut(get_index::<N, BITS>(key, list.depth)).is_some_and(|x| x.remove_if_internal(key, should_remove, child_skip));
+                if removed{
+                    list.children.remove_vacant();
+                    //a list left with one child is replaced by that child, whether it is an item or another list
+                    if list.children.len() <= 1{
+                        *internal = match list.children.iter().find(|x| !x.1.is_vacant()).map(|x| x.0){
+                            Some(digit) => match std::mem::replace(&mut *list.children.get_mut(digit).unwrap().0.get_mut(), MapInternal::Empty){
+                                //a bucket moving up has fewer bytes implied by its position
+                                MapInternal::Bucket(bucket) if bucket.skip() > skip => {
+                                    MapInternal::Bucket(bucket.with_skip(skip, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit)))
+                                }
+                                child => child
+                            },
+                            None => MapInternal::Empty
+                        };
+                    }
+                    else if let Some(bucket) = Self::merge_list(list, skip){
+                        *internal = MapInternal::Bucket(bucket);
                     }
-                }) && (item_count <= 1){
-                    self.0 = list.iter().find_map(|x| {
-                        match &x.0 {
-                            MapInternal::Item(item_key_value) => Some(MapInternal::new_item(item_key_value.0, item_key_value.1)),
-                            MapInternal::List(_) => None,
-                            MapInternal::Empty => None
-                        }
-                    }).unwrap_or(MapInternal::Empty)
                 }
                 removed
             },
             MapInternal::Empty => false
         }
     }
+
+    //counts of what is in the map, its memory size and how deep its buckets are, in one walk
+    pub fn stats(&self) -> MapStats{
+        let mut stats = MapStats{memory: MemoryUsage{slots: size_of::<Self>(), ..MemoryUsage::default()}, ..MapStats::default()};
+        let mut entry_depths = 0;
+        stats.depth = self.stats_internal(&mut stats, &mut entry_depths, 1);
+        stats.average_depth = if stats.len == 0 {0.0} else {(entry_depths as f64) / (stats.len as f64)};
+        stats
+    }
+
+    //depth is this slot's, returns the same as depth() here. entry_depths adds up the depth of every entry's bucket
+    fn stats_internal(&self, stats: &mut MapStats, entry_depths: &mut usize, depth: usize) -> usize{
+        let internal = self.0.get();
+        Self::add_node_memory_usage(&internal, &mut stats.memory);
+        match &*internal{
+            MapInternal::Bucket(bucket) => {
+                stats.buckets += 1;
+                stats.len += bucket.len();
+                *entry_depths += depth * bucket.len();
+                if stats.bucket_depths.len() <= depth {stats.bucket_depths.resize(depth + 1, 0)}
+                stats.bucket_depths[depth] += 1;
+                1
+            }
+            MapInternal::List(list) => {
+                stats.lists += 1;
+                let children = list.children.len();
+                if stats.list_occupancy.len() <= children {stats.list_occupancy.resize(children + 1, 0)}
+                stats.list_occupancy[children] += 1;
+                1 + list.children.slots().iter().map(|x| x.stats_internal(stats, entry_depths, depth + 1)).max().unwrap()
+            }
+            MapInternal::Empty => {
+                stats.empty_slots += 1;
+                0
+            }
+        }
+    }
+
+    //checks the map is in the shape inserts and removes leave it in, returning where it first isn't
+    pub fn validate(&self) -> Result<(), ValidationError<N>>{
+        let mut path = Vec::new();
+        self.validate_internal(&mut path, [0; N], 0, 0).map_err(|violation| ValidationError{path, violation})?;
+        //a list at each digit and a bucket at the bottom
+        let depth = self.depth();
+        if depth > (((N * 8) / BITS) + 1) {return Err(ValidationError{path: Vec::new(), violation: Violation::TooDeep(depth)})}
+        Ok(())
+    }
+
+    //the tree as a graphviz graph, lists labelled with their prefix in hex and how many bits of it are fixed, and
+    //buckets with their keys in hex. only nodes at most max_depth below the root and the first max_nodes are written
+    pub fn to_dot(&self, max_depth: usize, max_nodes: usize) -> String{
+        let mut dump = Dump::new(true, max_depth, max_nodes);
+        self.dump_internal(&mut dump, None, [0; N]);
+        dump.finish()
+    }
+
+    //the same as to_dot as indented text, a line for each node under its parent
+    pub fn dump_tree(&self, max_depth: usize, max_nodes: usize) -> String{
+        let mut dump = Dump::new(false, max_depth, max_nodes);
+        self.dump_internal(&mut dump, None, [0; N]);
+        dump.finish()
+    }
+
+    //prefix fills in the key bytes a compact bucket here leaves out
+    fn dump_internal(&self, dump: &mut Dump, parent: Option<(usize, usize)>, prefix: [u8; N]){
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => dump.bucket(parent, bucket.iter(prefix).map(|x| x.0)),
+            MapInternal::List(list) => {
+                let Some(id) = dump.list(parent, &list.prefix, list.depth * BITS, list.children.len(), false) else {return};
+                for (digit, child) in list.children.iter().filter(|x| !x.1.is_vacant()){
+                    child.dump_internal(dump, Some((id, digit)), get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit));
+                }
+                dump.end(id);
+            }
+            MapInternal::Empty => dump.empty(parent)
+        }
+    }
+
+    //prefix has the bits of the first fixed digits that every key below here shares, skip is how many key bytes a
+    //compact bucket here can leave out. path is left at the first violation
+    fn validate_internal(&self, path: &mut Vec<usize>, prefix: [u8; N], fixed: usize, skip: usize) -> Result<(), Violation<N>>{
+        match &*self.0.get(){
+            MapInternal::Bucket(bucket) => {
+                if bucket.skip() > skip {return Err(Violation::SkipTooLong(bucket.skip()))}
+                if bucket.len() > B {return Err(Violation::OverfullBucket(bucket.len()))}
+                if let Some(key) = bucket.iter(prefix).map(|x| x.0).find(|x| get_split_depth::<N, BITS>(*x, prefix, fixed).is_some()) {return Err(Violation::KeyOffPath(key))}
+                if !bucket.iter(prefix).zip(bucket.iter(prefix).skip(1)).all(|(x, y)| x.0 < y.0) {return Err(Violation::UnsortedBucket)}
+            }
+            MapInternal::List(list) => {
+                if (list.depth < fixed) || (list.depth >= ((N * 8) / BITS)) {return Err(Violation::ListDepth(list.depth))}
+                if get_split_depth::<N, BITS>(list.prefix, prefix, fixed).is_some() || (get_prefix::<N, BITS>(list.prefix, list.depth) != list.prefix) {return Err(Violation::PrefixOffPath(list.prefix))}
+                if list.children.len() <= 1 {return Err(Violation::Uncollapsed)}
+                if list.children.capacity_without_vacant().is_some() {return Err(Violation::VacantSlots)}
+                let entries = list.children.iter().try_fold(0, |len, (_, child)| match &*child.0.get(){
+                    MapInternal::Bucket(bucket) => Some(len + bucket.len()),
+                    MapInternal::Empty => Some(len),
+                    MapInternal::List(_) => None
+                });
+                if let Some(entries) = entries.filter(|x| *x <= B) {return Err(Violation::Unmerged(entries))}
+                for (digit, child) in list.children.iter(){
+                    path.push(digit);
+                    child.validate_internal(path, get_prefix_with_digit::<N, BITS>(list.prefix, list.depth, digit), list.depth + 1, list.get_child_skip())?;
+                    path.pop();
+                }
+            }
+            MapInternal::Empty => ()
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize, V, const BITS: usize, const B: usize, const COMPACT: bool> Map<N, V, BITS, B, COMPACT, true> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    //gives the nodes freed into the arena back to the global allocator, returning how many bytes that was
+    //the arena is shared by every map with ARENA, and other threads keep a few free nodes each until they exit
+    pub fn shrink_to_fit(&self) -> usize{
+        arena::shrink()
+    }
 }
 
-pub type Set<const N: usize> = Map<N, ()>;
\ No newline at end of file
+pub type Set<const N: usize, const BITS: usize = 2, const B: usize = 1, const COMPACT: bool = false, const ARENA: bool = false> = Map<N, (), BITS, B, COMPACT, ARENA>;
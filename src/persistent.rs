@@ -0,0 +1,346 @@
+use std::sync::Arc;
+use crate::{get_index, leading_zeroes_order, InsertOrUpdateResult};
+
+//a list is shared by every version of the map that hasn't changed anything below it, so a write copies only the lists
+//on the way down to its key and the new version shares the rest with the old
+type List<const N: usize, V> = [Node<N, V>; 4];
+
+//(closest, found left, found right)
+type Closest<const N: usize, V> = (Option<([u8; N], V)>, bool, bool);
+
+#[derive(Debug, Clone)]
+enum Node<const N: usize, V>{
+    Item([u8; N], V),
+    List(Arc<List<N, V>>),
+    Empty
+}
+
+//an immutable map, insert_or_update and remove return a new map and leave this one as it was. clone is a reference
+//count increment so a clone is a snapshot, and versions can be diffed skipping the lists they share
+#[derive(Debug, Clone)]
+pub struct Map<const N: usize, V>(Node<N, V>);
+
+impl<const N: usize, V: Copy> Default for Map<N, V>{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl<const N: usize, V: Copy> Map<N, V>{
+
+    pub const fn new() -> Self{
+        Self(Node::Empty)
+    }
+
+    pub fn get_used_percent(&self) -> f64{
+        (((size_of::<[u8; N]>() + size_of::<V>()) * self.len()) as f64) / (self.get_memory_size() as f64)
+    }
+
+    //counts lists shared with other versions as if this version had them to itself
+    pub fn get_memory_size(&self) -> usize{
+        size_of::<Self>() + Self::get_memory_size_internal(&self.0)
+    }
+
+    fn get_memory_size_internal(node: &Node<N, V>) -> usize{
+        match node{
+            //the two reference counts before the list in its allocation
+            Node::List(list) => (2 * size_of::<usize>()) + size_of::<List<N, V>>() + list.iter().map(Self::get_memory_size_internal).sum::<usize>(),
+            _ => 0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool{
+        matches!(self.0, Node::Empty)
+    }
+
+    pub fn depth(&self) -> usize{
+        Self::depth_internal(&self.0)
+    }
+
+    fn depth_internal(node: &Node<N, V>) -> usize{
+        match node{
+            Node::Item(..) => 1,
+            Node::List(list) => 1 + list.iter().map(Self::depth_internal).max().unwrap(),
+            Node::Empty => 0
+        }
+    }
+
+    pub fn len(&self) -> usize{
+        Self::len_internal(&self.0)
+    }
+
+    fn len_internal(node: &Node<N, V>) -> usize{
+        match node{
+            Node::Item(..) => 1,
+            Node::List(list) => list.iter().map(Self::len_internal).sum(),
+            Node::Empty => 0
+        }
+    }
+
+    //compares only the roots, a list by pointer so maps whose roots share a list are the same version or copies of it,
+    //without looking below it. a map of one entry holds it inline rather than behind an Arc so that entry is compared by
+    //value, and two maps that each inserted the same single entry are shallow_eq though neither came from the other
+    pub fn shallow_eq(&self, other: &Self) -> bool where V: PartialEq{
+        match (&self.0, &other.0){
+            (Node::List(list_1), Node::List(list_2)) => Arc::ptr_eq(list_1, list_2),
+            (Node::Item(key_1, value_1), Node::Item(key_2, value_2)) => (key_1 == key_2) && (value_1 == value_2),
+            (Node::Empty, Node::Empty) => true,
+            _ => false
+        }
+    }
+
+    pub fn get(&self, key: [u8; N]) -> Option<V>{
+        let mut node = &self.0;
+        let mut depth = 0;
+        loop{
+            match node{
+                Node::Item(item_key, value) => return if *item_key == key {Some(*value)} else {None},
+                Node::List(list) => node = &list[get_index::<N, 2>(key, depth)],
+                Node::Empty => return None
+            }
+            depth += 1;
+        }
+    }
+
+    pub fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>{
+        Self::get_or_closest_by_key_leading_zeroes_internal(&self.0, key, include_key, 0)
+    }
+
+    fn get_or_closest_by_key_leading_zeroes_internal(node: &Node<N, V>, key: [u8; N], include_key: bool, depth: usize) -> Option<([u8; N], V)>{
+        match node{
+            Node::Item(item_key, value) => if (*item_key != key) || include_key {Some((*item_key, *value))} else {None},
+            Node::List(list) => {
+                let index = get_index::<N, 2>(key, depth);
+                leading_zeroes_order::<2>(index).find_map(|i| {
+                    if i == index {Self::get_or_closest_by_key_leading_zeroes_internal(&list[i], key, include_key, depth + 1)}
+                    else if i < index {Self::get_max_internal(&list[i])}
+                    else {Self::get_min_internal(&list[i])}
+                })
+            }
+            Node::Empty => None
+        }
+    }
+
+    pub fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>{
+        let (result, found_left, found_right) = Self::get_or_closest_by_key_internal(&self.0, key, include_key, 0);
+        if !loop_around || (found_left == found_right) {result}
+        else if !found_left{
+            [result, self.get_max()].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
+        }
+        else{
+            [result, self.get_min()].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0))
+        }
+    }
+
+    fn get_or_closest_by_key_internal(node: &Node<N, V>, key: [u8; N], include_key: bool, depth: usize) -> Closest<N, V>{
+        match node{
+            Node::Item(item_key, value) => (if (*item_key != key) || include_key {Some((*item_key, *value))} else {None}, false, false),
+            Node::List(list) => {
+                let index = get_index::<N, 2>(key, depth);
+                let (mut min, mut left, mut right) = Self::get_or_closest_by_key_internal(&list[index], key, include_key, depth + 1);
+                if !left{
+                    if let Some(left_item_key_value) = list[..index].iter().rev().find_map(Self::get_max_internal){
+                        left = true;
+                        min = [min, Some(left_item_key_value)].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
+                    }
+                }
+                if !right{
+                    if let Some(right_item_key_value) = list[(index + 1)..].iter().find_map(Self::get_min_internal){
+                        right = true;
+                        min = [min, Some(right_item_key_value)].iter().filter_map(|x| *x).min_by_key(|x| Self::get_abs_diff(key, x.0));
+                    }
+                }
+                (min, left, right)
+            }
+            Node::Empty => (None, false, false)
+        }
+    }
+
+    const HALF_POINT: [u8; N] = {
+        let mut array = [0; N];
+        array[0] = 128;
+        array
+    };
+
+    fn get_abs_diff(item_1: [u8; N], item_2: [u8; N]) -> [u8; N]{
+        let inner_function = |item_1_inner: [u8; N], item_2_inner: [u8; N]| {
+            let mut result = [0; N];
+            let mut borrow = 0;
+            for i in (0..N).rev() {
+                if item_1_inner[i] > item_2_inner[i]{
+                    result[i] = item_1_inner[i] - item_2_inner[i] - borrow;
+                    borrow = 0;
+                }
+                else if item_1_inner[i] == item_2_inner[i]{
+                    if borrow == 1 {result[i] = u8::MAX}
+                    else {result[i] = 0}
+                }
+                else{
+                    result[i] = u8::MAX - (item_2_inner[i] - item_1_inner[i]) + 1 - borrow;
+                    borrow = 1;
+                }
+            }
+            result
+        };
+        let diff = inner_function(item_1, item_2);
+        if diff > Self::HALF_POINT {inner_function(item_2, item_1)} else {diff}
+    }
+
+    pub fn get_min(&self) -> Option<([u8; N], V)>{
+        Self::get_min_internal(&self.0)
+    }
+
+    fn get_min_internal(node: &Node<N, V>) -> Option<([u8; N], V)>{
+        match node{
+            Node::Item(key, value) => Some((*key, *value)),
+            Node::List(list) => list.iter().find_map(Self::get_min_internal),
+            Node::Empty => None
+        }
+    }
+
+    pub fn get_max(&self) -> Option<([u8; N], V)>{
+        Self::get_max_internal(&self.0)
+    }
+
+    fn get_max_internal(node: &Node<N, V>) -> Option<([u8; N], V)>{
+        match node{
+            Node::Item(key, value) => Some((*key, *value)),
+            Node::List(list) => list.iter().rev().find_map(Self::get_max_internal),
+            Node::Empty => None
+        }
+    }
+
+    //calls f with every entry in key order
+    pub fn for_each(&self, mut f: impl FnMut([u8; N], V)){
+        Self::for_each_internal(&self.0, &mut f);
+    }
+
+    fn for_each_internal(node: &Node<N, V>, f: &mut impl FnMut([u8; N], V)){
+        match node{
+            Node::Item(key, value) => f(*key, *value),
+            Node::List(list) => list.iter().for_each(|x| Self::for_each_internal(x, f)),
+            Node::Empty => ()
+        }
+    }
+
+    //calls f in key order with each key whose value differs between the two maps, with its value in this one and in
+    //other. lists the two share are skipped without being looked into, so diffing versions costs the size of what
+    //changed between them rather than the size of the maps
+    pub fn diff(&self, other: &Self, mut f: impl FnMut([u8; N], Option<V>, Option<V>)) where V: PartialEq{
+        Self::diff_internal(&self.0, &other.0, &mut f);
+    }
+
+    fn diff_internal(node_1: &Node<N, V>, node_2: &Node<N, V>, f: &mut impl FnMut([u8; N], Option<V>, Option<V>)) where V: PartialEq{
+        match (node_1, node_2){
+            (Node::List(list_1), Node::List(list_2)) => {
+                if !Arc::ptr_eq(list_1, list_2) {list_1.iter().zip(list_2.iter()).for_each(|x| Self::diff_internal(x.0, x.1, f))}
+            }
+            //a list against an item or nothing, at most one entry on the other side so its entries are merged with it
+            _ => {
+                let (mut entries_1, mut entries_2) = (Vec::new(), Vec::new());
+                Self::for_each_internal(node_1, &mut |key, value| entries_1.push((key, value)));
+                Self::for_each_internal(node_2, &mut |key, value| entries_2.push((key, value)));
+                let (mut i, mut j) = (0, 0);
+                while (i < entries_1.len()) || (j < entries_2.len()){
+                    match (entries_1.get(i), entries_2.get(j)){
+                        (Some(x), Some(y)) if x.0 == y.0 => {
+                            if x.1 != y.1 {f(x.0, Some(x.1), Some(y.1))}
+                            i += 1;
+                            j += 1;
+                        }
+                        (Some(x), y) if y.is_none_or(|y| x.0 < y.0) => {
+                            f(x.0, Some(x.1), None);
+                            i += 1;
+                        }
+                        (_, y) => {
+                            let y = y.unwrap();
+                            f(y.0, None, Some(y.1));
+                            j += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn insert_or_update(&self, key: [u8; N], value: V) -> (Self, InsertOrUpdateResult){
+        self.insert_or_update_if(key, value, &|_,_| true)
+    }
+
+    //the new map, which is a clone of this one if nothing was inserted or updated
+    pub fn insert_or_update_if(&self, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool) -> (Self, InsertOrUpdateResult){
+        match Self::insert_or_update_if_internal(&self.0, key, value, should_update, 0){
+            Some((node, result)) => (Self(node), result),
+            None => (self.clone(), InsertOrUpdateResult::Neither)
+        }
+    }
+
+    //the node replacing this one, None if nothing changed
+    fn insert_or_update_if_internal(node: &Node<N, V>, key: [u8; N], value: V, should_update: &impl Fn(&V, &V) -> bool, depth: usize) -> Option<(Node<N, V>, InsertOrUpdateResult)>{
+        match node{
+            Node::Item(item_key, item_value) => {
+                if *item_key != key {return Some((Self::deepen_tree((*item_key, *item_value), key, value, depth), InsertOrUpdateResult::Inserted))}
+                if should_update(item_value, &value) {Some((Node::Item(key, value), InsertOrUpdateResult::Updated))} else {None}
+            }
+            Node::List(list) => {
+                let index = get_index::<N, 2>(key, depth);
+                let (child, result) = Self::insert_or_update_if_internal(&list[index], key, value, should_update, depth + 1)?;
+                //the other children are shared with the old list
+                let mut list = (**list).clone();
+                list[index] = child;
+                Some((Node::List(Arc::new(list)), result))
+            }
+            Node::Empty => Some((Node::Item(key, value), InsertOrUpdateResult::Inserted))
+        }
+    }
+
+    fn deepen_tree(item_1: ([u8; N], V), key: [u8; N], value: V, depth: usize) -> Node<N, V>{
+        let item_1_index = get_index::<N, 2>(item_1.0, depth);
+        let item_2_index = get_index::<N, 2>(key, depth);
+        let mut list: List<N, V> = [const {Node::Empty}; 4];
+        if item_1_index == item_2_index {list[item_1_index] = Self::deepen_tree(item_1, key, value, depth + 1)}
+        else{
+            list[item_1_index] = Node::Item(item_1.0, item_1.1);
+            list[item_2_index] = Node::Item(key, value);
+        }
+        Node::List(Arc::new(list))
+    }
+
+    pub fn remove(&self, key: [u8; N]) -> Self{
+        self.remove_if(key, &|_| true).0
+    }
+
+    //the new map, a clone of this one if nothing was removed, and whether anything was
+    pub fn remove_if(&self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> (Self, bool){
+        match Self::remove_if_internal(&self.0, key, should_remove, 0){
+            Some(node) => (Self(node), true),
+            None => (self.clone(), false)
+        }
+    }
+
+    //the node replacing this one, None if nothing was removed
+    fn remove_if_internal(node: &Node<N, V>, key: [u8; N], should_remove: &impl Fn(&V) -> bool, depth: usize) -> Option<Node<N, V>>{
+        match node{
+            Node::Item(item_key, value) => if (*item_key == key) && should_remove(value) {Some(Node::Empty)} else {None},
+            Node::List(list) => {
+                let index = get_index::<N, 2>(key, depth);
+                let child = Self::remove_if_internal(&list[index], key, should_remove, depth + 1)?;
+                let mut list = (**list).clone();
+                list[index] = child;
+                Some(Self::collapse(list))
+            }
+            Node::Empty => None
+        }
+    }
+
+    //a list left with no lists below it and one item or none is replaced by that item, which carries on up the tree
+    //as the parent is collapsed in turn
+    fn collapse(list: List<N, V>) -> Node<N, V>{
+        if list.iter().any(|x| matches!(x, Node::List(_))) || (list.iter().filter(|x| matches!(x, Node::Item(..))).count() > 1){
+            return Node::List(Arc::new(list))
+        }
+        list.into_iter().find(|x| matches!(x, Node::Item(..))).unwrap_or(Node::Empty)
+    }
+}
+
+pub type Set<const N: usize> = Map<N, ()>;
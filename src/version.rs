@@ -0,0 +1,170 @@
+use std::alloc::Layout;
+use std::ptr::NonNull;
+use std::sync::atomic::Ordering;
+#[cfg(not(rust_map_loom))]
+use std::sync::atomic::AtomicUsize;
+#[cfg(rust_map_loom)]
+use loom::sync::atomic::AtomicUsize;
+use crate::epoch;
+
+//with optimistic reads a map can be read as it was at one moment, see concurrent::Snapshot. a writer puts what it wrote
+//in the lock as a version holding the word it replaces, stamped with the generation it was published in, and taking a
+//snapshot moves the generation on, so a reader at a generation goes back through a lock's versions to the newest one
+//stamped before it. a version is only left in the lock while a snapshot is held, otherwise the writer leaves the new
+//word and retires the version, so while there are none a write costs a version's allocation but nothing is kept
+//
+//a version is published in two steps so that a snapshot taken while it is being stamped agrees with every reader on
+//which side of the snapshot it is. until it is pending it reads as not there yet, then whoever reads it first stamps it
+//with the generation at that moment, readers helping rather than waiting for the writer. the versions of a transaction
+//take the stamp of the first, which only becomes pending once every one of them is in its lock, so a snapshot sees all
+//of them or none
+const PREPARING: usize = usize::MAX;
+const PENDING: usize = usize::MAX - 1;
+//the generation of a reader that reads the latest versions, after every stamp
+pub(crate) const LATEST: usize = PENDING;
+//a version's word has both tag bits set, which no bucket, list or empty word has, see node::Packed
+const TAG: usize = 3;
+
+#[cfg(not(rust_map_loom))]
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+//how many snapshots are held, while there are any writers leave versions in their locks
+#[cfg(not(rust_map_loom))]
+static SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(rust_map_loom)]
+loom::lazy_static!{
+    static ref GENERATION: AtomicUsize = AtomicUsize::new(0);
+    static ref SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+}
+
+//old is the word that was in the lock, which may be another version, and new the word written. what old holds was
+//retired when new was published, so is only read by snapshots taken before then, whose pins keep it
+pub(crate) struct Version{
+    stamp: AtomicUsize,
+    //the version whose stamp this one takes until it is given it, null if it is its own
+    leader: *const Version,
+    old: *mut u8,
+    new: *mut u8
+}
+
+impl Version{
+    //the generation it was published in, PREPARING if it isn't yet
+    fn stamp(&self) -> usize{
+        match self.stamp.load(Ordering::SeqCst){
+            PREPARING if !self.leader.is_null() => unsafe{&*self.leader}.stamp(),
+            PENDING => {
+                //read by writing, so a snapshot whose generation moves on after this one reads every lock word written
+                //before it was stamped, rather than one it could have been loaded before
+                let now = GENERATION.fetch_add(0, Ordering::SeqCst);
+                match self.stamp.compare_exchange(PENDING, now, Ordering::SeqCst, Ordering::SeqCst){
+                    Ok(_) => now,
+                    Err(stamp) => stamp
+                }
+            }
+            stamp => stamp
+        }
+    }
+}
+
+fn version<'a>(word: *mut u8) -> Option<&'a Version>{
+    if (word.addr() & TAG) != TAG {return None}
+    Some(unsafe{&*word.map_addr(|x| x & !TAG).cast::<Version>()})
+}
+
+//a version of new replacing old, to go in their lock while it is write locked. the versions of a transaction after the
+//first take the first as leader
+pub(crate) fn prepare(old: *mut u8, new: *mut u8, leader: Option<NonNull<Version>>) -> NonNull<Version>{
+    let leader = leader.map_or(std::ptr::null(), |x| x.as_ptr().cast_const());
+    NonNull::from(Box::leak(Box::new(Version{stamp: AtomicUsize::new(PREPARING), leader, old, new})))
+}
+
+pub(crate) fn word(version: NonNull<Version>) -> *mut u8{
+    version.as_ptr().cast::<u8>().map_addr(|x| x | TAG)
+}
+
+//stamps versions once every one of them is in its lock, the rest taking the first's stamp
+pub(crate) fn publish(versions: &[NonNull<Version>]){
+    let Some((leader, rest)) = versions.split_first() else {return};
+    let leader = unsafe{leader.as_ref()};
+    debug_assert!(leader.leader.is_null());
+    leader.stamp.store(PENDING, Ordering::SeqCst);
+    let stamp = leader.stamp();
+    rest.iter().for_each(|x| unsafe{x.as_ref()}.stamp.store(stamp, Ordering::SeqCst));
+}
+
+//the word to unlock a published version's lock with, the version itself if a snapshot may need what it replaced
+pub(crate) fn finish(version: NonNull<Version>) -> *mut u8{
+    debug_assert!(unsafe{version.as_ref()}.stamp() < LATEST);
+    //a snapshot counted after this has a generation after the stamp so reads new
+    if SNAPSHOTS.load(Ordering::SeqCst) != 0 {return word(version)}
+    let new = unsafe{version.as_ref()}.new;
+    retire(word(version));
+    new
+}
+
+//the newest word in a lock that isn't being written
+pub(crate) fn latest(word: *mut u8) -> *mut u8{
+    version(word).map_or(word, |x| x.new)
+}
+
+//retires the versions in a word being replaced or dropped, but not what they hold
+pub(crate) fn retire(mut word: *mut u8){
+    while let Some(version) = self::version(word){
+        word = version.old;
+        unsafe{epoch::retire(NonNull::from(version).cast(), Layout::new::<Version>(), free)}
+    }
+}
+
+fn free(ptr: NonNull<u8>, _: Layout){
+    drop(unsafe{Box::from_raw(ptr.cast::<Version>().as_ptr())})
+}
+
+//what an optimistic reader reads through, its pin keeping what it reads from being freed, and the generation it reads
+pub(crate) struct View{
+    _pin: epoch::Guard,
+    at: usize
+}
+
+impl View{
+    pub(crate) fn latest() -> Self{
+        Self::at(LATEST)
+    }
+
+    pub(crate) fn at(at: usize) -> Self{
+        Self{_pin: epoch::pin(), at}
+    }
+
+    //the word the lock held at the view's generation
+    pub(crate) fn resolve(&self, mut word: *mut u8) -> *mut u8{
+        while let Some(version) = version(word){
+            word = if version.stamp() < self.at {version.new} else {version.old};
+        }
+        word
+    }
+}
+
+//a generation held for a snapshot. its pin is taken before the generation moves on, so everything the maps held then
+//that is replaced after is kept until it is dropped
+pub(crate) struct Snapshot(View);
+
+impl Snapshot{
+    pub(crate) fn new() -> Self{
+        let pin = epoch::pin();
+        SNAPSHOTS.fetch_add(1, Ordering::SeqCst);
+        let at = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+        Self(View{_pin: pin, at})
+    }
+
+    pub(crate) fn at(&self) -> usize{
+        self.0.at
+    }
+
+    pub(crate) fn view(&self) -> View{
+        View::at(self.0.at)
+    }
+}
+
+impl Drop for Snapshot{
+    fn drop(&mut self){
+        SNAPSHOTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
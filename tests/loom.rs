@@ -0,0 +1,227 @@
+//run with RUSTFLAGS="--cfg rust_map_loom" cargo test --release --test loom, and with --features optimistic for the lock free reads
+//each test runs a small scenario under every interleaving of the threads' lock accesses with up to 3 preemptions,
+//LOOM_MAX_PREEMPTIONS changes that
+#![cfg(rust_map_loom)]
+use loom::sync::Arc;
+use loom::thread;
+use rust_map::concurrent::Map;
+use rust_map::InsertOrUpdateResult;
+
+//the scenario runs on a thread of its own, as loom drops the main thread's thread locals after its statics and the
+//epoch's thread local gives what it holds to the statics when dropped. the statics are made afresh for each run, so
+//once the scenario is done the main thread frees what they hold
+fn model(f: impl Fn() + Sync + Send + 'static){
+    let mut builder = loom::model::Builder::new();
+    builder.preemption_bound = builder.preemption_bound.or(Some(3));
+    let f = std::sync::Arc::new(f);
+    builder.check(move || {
+        let f = f.clone();
+        thread::spawn(move || f()).join().unwrap();
+        rust_map::free_all();
+    })
+}
+
+fn two_threads<M: Send + Sync + 'static>(map: M, a: impl Fn(&M) + Send + 'static, b: impl Fn(&M) + Send + 'static) -> M{
+    let map = Arc::new(map);
+    let other = thread::spawn({
+        let map = map.clone();
+        move || b(&map)
+    });
+    a(&map);
+    other.join().unwrap();
+    Arc::try_unwrap(map).ok().unwrap()
+}
+
+#[test]
+fn inserts_split_same_bucket_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        let map = two_threads(map,
+            |map| assert_eq!(map.insert_or_update([0, 1], 1), InsertOrUpdateResult::Inserted),
+            |map| assert_eq!(map.insert_or_update([0, 2], 2), InsertOrUpdateResult::Inserted));
+        assert_eq!(map.len(), 3);
+        (0..3).for_each(|x| assert_eq!(map.get([0, x as u8]), Some(x)));
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn inserts_split_full_bucket_test(){
+    model(|| {
+        let map = Map::<2, u64, 2, 2>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| {map.insert_or_update([0, 2], 2);},
+            |map| {map.insert_or_update([1, 0], 3);});
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get([0, 2]), Some(2));
+        assert_eq!(map.get([1, 0]), Some(3));
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn insert_races_remove_collapse_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| assert!(map.remove_if([0, 1], &|_| true)),
+            |map| assert_eq!(map.insert_or_update([0, 2], 2), InsertOrUpdateResult::Inserted));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get([0, 0]), Some(0));
+        assert_eq!(map.get([0, 1]), None);
+        assert_eq!(map.get([0, 2]), Some(2));
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn get_races_remove_collapse_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| map.remove([0, 1]),
+            |map| {
+                assert_eq!(map.get([0, 0]), Some(0));
+                assert_eq!(map.get_or_closest_by_key([0, 1], false, true), Some(([0, 0], 0)));
+            });
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.depth(), 1);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn removes_collapse_to_empty_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| map.remove([0, 0]),
+            |map| map.remove([0, 1]));
+        assert!(map.is_empty());
+        assert_eq!(map.depth(), 0);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn copy_races_writes_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([2, 0], 2);
+        let map = two_threads(map,
+            |map| {
+                //a copy is of before, between or after the other thread's two writes
+                let copy = map.to_non_concurrent();
+                let keys = [[0, 0], [1, 0], [2, 0]].map(|x| copy.get(x).is_some());
+                assert!([[true, false, true], [false, false, true], [false, true, true]].contains(&keys), "{:?}", keys);
+            },
+            |map| {
+                map.remove([0, 0]);
+                map.insert_or_update([1, 0], 1);
+            });
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn transaction_races_insert_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 5);
+        map.insert_or_update([2, 0], 2);
+        let map = two_threads(map,
+            |map| {
+                //moves [0, 0] to [1, 0], with whichever value it has when the move is made
+                let moved = map.transaction(|tx| {
+                    let value = tx.remove([0, 0]).ok_or(())?;
+                    tx.insert_or_update([1, 0], value);
+                    Ok::<_, ()>(value)
+                });
+                assert!([Ok(5), Ok(7)].contains(&moved), "{:?}", moved);
+            },
+            |map| {map.insert_or_update([0, 0], 7);});
+        let entries = [[0, 0], [1, 0], [2, 0]].map(|x| map.get(x));
+        assert!([[None, Some(7), Some(2)], [Some(7), Some(5), Some(2)]].contains(&entries), "{:?}", entries);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+#[test]
+fn pops_collapse_to_empty_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| assert!([Some(([0, 0], 0)), Some(([0, 1], 1))].contains(&map.pop_min())),
+            |map| assert!([Some(([0, 0], 0)), Some(([0, 1], 1))].contains(&map.pop_min())));
+        assert!(map.is_empty());
+        assert_eq!(map.depth(), 0);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+//under optimistic the get takes no locks, so this checks that what the remove replaces isn't given back to the arena's
+//free lists, and so written over by the insert afterwards, while the get can still be reading it
+#[cfg(feature = "optimistic")]
+#[test]
+fn get_races_remove_optimistic_test(){
+    model(|| {
+        let map = Map::<2, u64, 2, 1, false, true>::new();
+        map.insert_or_update([0, 0], 0);
+        map.insert_or_update([0, 1], 1);
+        let map = two_threads(map,
+            |map| {
+                assert_eq!(map.get([0, 0]), Some(0));
+                assert!([None, Some(1)].contains(&map.get([0, 1])));
+            },
+            |map| map.remove([0, 1]));
+        map.insert_or_update([0, 2], 2);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get([0, 0]), Some(0));
+        assert_eq!(map.get([0, 2]), Some(2));
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
+
+//the transaction's writes are to two buckets, and a snapshot sees both or neither, the same each time it reads
+#[cfg(feature = "optimistic")]
+#[test]
+fn snapshot_races_transaction_optimistic_test(){
+    model(|| {
+        let map = Map::<2, u64>::new();
+        map.insert_or_update([0, 0], 5);
+        map.insert_or_update([1, 0], 1);
+        let map = two_threads(map,
+            |map| {
+                let snapshot = map.snapshot_handle();
+                let entries = [[0, 0], [1, 0]].map(|x| snapshot.get(x));
+                assert!([[Some(5), Some(1)], [Some(3), Some(3)]].contains(&entries), "{:?}", entries);
+                let mut sum = 0;
+                snapshot.for_each(|_, value| sum += value);
+                assert_eq!(sum, 6);
+                assert_eq!([[0, 0], [1, 0]].map(|x| snapshot.get(x)), entries);
+            },
+            |map| {
+                _ = map.transaction(|tx| {
+                    let (from, to) = (tx.get([0, 0]).ok_or(())?, tx.get([1, 0]).ok_or(())?);
+                    tx.insert_or_update([0, 0], from - 2);
+                    tx.insert_or_update([1, 0], to + 2);
+                    Ok::<_, ()>(())
+                });
+            });
+        assert_eq!([[0, 0], [1, 0]].map(|x| map.get(x)), [Some(3), Some(3)]);
+        assert_eq!(map.validate(), Ok(()));
+    });
+}
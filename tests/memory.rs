@@ -0,0 +1,87 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use rand::random;
+use rust_map::{BitsPerLevel, SupportedBitsPerLevel};
+
+//counts the bytes each thread has allocated and not freed, so tests running at the same time don't see each other's
+struct Counting;
+
+thread_local!{
+    static ALLOCATED: Cell<isize> = const {Cell::new(0)};
+}
+
+unsafe impl GlobalAlloc for Counting{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8{
+        _ = ALLOCATED.try_with(|x| x.set(x.get() + layout.size() as isize));
+        unsafe{System.alloc(layout)}
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout){
+        _ = ALLOCATED.try_with(|x| x.set(x.get() - layout.size() as isize));
+        unsafe{System.dealloc(ptr, layout)}
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn allocated() -> isize{
+    ALLOCATED.with(|x| x.get())
+}
+
+//keys sharing prefixes of different lengths so there are lists of every kind and buckets moved up with skipped bytes
+fn get_keys<const N: usize>(length: usize) -> Vec<[u8; N]>{
+    (0..length).map(|_| {
+        let mut key = random::<[u8; N]>();
+        let (shared, group) = (random::<u8>() as usize % N, key[0] % 4);
+        key[..shared].fill(group);
+        key
+    }).collect()
+}
+
+fn check_non_concurrent<const N: usize, V: Copy + Default, const BITS: usize, const B: usize, const COMPACT: bool>() where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    let keys = get_keys::<N>(10000);
+    let before = allocated();
+    let mut map = rust_map::non_concurrent::Map::<N, V, BITS, B, COMPACT>::new();
+    keys.iter().for_each(|x| _ = map.insert_or_update(*x, V::default()));
+    //the map's own word is on the stack
+    assert_eq!((allocated() - before) as usize, map.get_memory_size() - size_of_val(&map));
+    keys.iter().step_by(3).for_each(|x| map.remove(*x));
+    assert_eq!((allocated() - before) as usize, map.get_memory_size() - size_of_val(&map));
+    drop(map);
+    assert_eq!(allocated(), before);
+}
+
+fn check_concurrent<const N: usize, V: Copy + Default, const BITS: usize, const B: usize, const COMPACT: bool>() where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+    let keys = get_keys::<N>(10000);
+    let before = allocated();
+    let map = rust_map::concurrent::Map::<N, V, BITS, B, COMPACT>::new();
+    keys.iter().for_each(|x| _ = map.insert_or_update(*x, V::default()));
+    assert_eq!((allocated() - before) as usize, map.get_memory_size() - size_of_val(&map));
+    keys.iter().step_by(3).for_each(|x| map.remove(*x));
+    assert_eq!((allocated() - before) as usize, map.get_memory_size() - size_of_val(&map));
+    let usage = map.stats().memory;
+    assert_eq!(usage.total(), map.get_memory_size());
+    assert_eq!(usage.slots % size_of_val(&map), 0);
+    drop(map);
+    assert_eq!(allocated(), before);
+}
+
+#[test]
+fn non_concurrent_memory_test(){
+    check_non_concurrent::<32, u64, 2, 1, false>();
+    check_non_concurrent::<32, [u8; 32], 8, 1, false>();
+    check_non_concurrent::<16, u8, 4, 4, true>();
+    check_non_concurrent::<64, [u8; 3], 8, 8, true>();
+    check_non_concurrent::<5, (), 1, 2, false>();
+}
+
+#[test]
+#[cfg_attr(feature = "optimistic", ignore = "with optimistic reads freed nodes wait for the readers that could be in them")]
+fn concurrent_memory_test(){
+    check_concurrent::<32, u64, 2, 1, false>();
+    check_concurrent::<32, [u8; 32], 8, 1, false>();
+    check_concurrent::<16, u8, 4, 4, true>();
+    check_concurrent::<64, [u8; 3], 8, 8, true>();
+    check_concurrent::<5, (), 1, 2, false>();
+}
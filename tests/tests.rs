@@ -1,26 +1,265 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{LazyLock, RwLock};
 use std::time::{Duration, SystemTime};
 use rand::random;
 use rand::distr::{Distribution, StandardUniform};
 use tokio::task::JoinHandle;
 use rand::random_range;
-use rust_map::InsertOrUpdateResult;
+use rust_map::{BitsPerLevel, InsertOrUpdateResult, MapStats, MemoryUsage, SupportedBitsPerLevel, ValidationError};
 
 fn should_update<T: Ord>(value_1: &T, value_2: &T) -> bool{
     value_2 > value_1
 }
 
+fn get_circular_distance(key_1: [u8; 4], key_2: [u8; 4]) -> u32{
+    let diff = u32::from_be_bytes(key_1).wrapping_sub(u32::from_be_bytes(key_2));
+    diff.min(diff.wrapping_neg())
+}
+
+fn get_leading_zeroes(key_1: [u8; 4], key_2: [u8; 4]) -> u32{
+    (u32::from_be_bytes(key_1) ^ u32::from_be_bytes(key_2)).leading_zeros()
+}
+
 fn get_vec_of_key_values<T>(length: usize) -> Vec<T> where StandardUniform: Distribution<T>{
     let mut to_return = Vec::<T>::new();
     for _ in 0..length {to_return.push(random())}
     to_return
 }
 
+fn time_threads(no_threads: usize, f: impl Fn(usize) + Sync) -> f64{
+    let start_time = SystemTime::now();
+    std::thread::scope(|scope| {
+        for i in 0..no_threads {
+            let f = &f;
+            scope.spawn(move || f(i));
+        }
+    });
+    SystemTime::now().duration_since(start_time).unwrap().as_secs_f64()
+}
+
+//what both maps have, so the tests of a behavior are one helper that both modules call. calls go through Self:: so
+//they reach the maps' own methods rather than these
+trait TestMap<const N: usize, V>: Default{
+    const BITS: usize;
+    fn insert_or_update(&mut self, key: [u8; N], value: V) -> InsertOrUpdateResult;
+    fn remove(&mut self, key: [u8; N]);
+    fn remove_if(&mut self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> bool;
+    fn get(&self, key: [u8; N]) -> Option<V>;
+    fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)>;
+    fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)>;
+    fn get_min(&self) -> Option<([u8; N], V)>;
+    fn get_max(&self) -> Option<([u8; N], V)>;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool;
+    fn depth(&self) -> usize;
+    fn get_used_percent(&self) -> f64;
+    fn get_memory_size(&self) -> usize;
+    fn get_memory_usage(&self) -> MemoryUsage;
+    fn stats(&self) -> MapStats;
+    fn validate(&self) -> Result<(), ValidationError<N>>;
+    fn dump_tree(&self, max_depth: usize, max_nodes: usize) -> String;
+    fn to_dot(&self, max_depth: usize, max_nodes: usize) -> String;
+}
+
+macro_rules! impl_test_map{
+    ($map: ident) => {
+        impl<const N: usize, V: Copy, const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool> TestMap<N, V> for rust_map::$map::Map<N, V, BITS, B, COMPACT, ARENA> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+            const BITS: usize = BITS;
+            fn insert_or_update(&mut self, key: [u8; N], value: V) -> InsertOrUpdateResult {Self::insert_or_update(self, key, value)}
+            fn remove(&mut self, key: [u8; N]) {Self::remove(self, key)}
+            fn remove_if(&mut self, key: [u8; N], should_remove: &impl Fn(&V) -> bool) -> bool {Self::remove_if(self, key, should_remove)}
+            fn get(&self, key: [u8; N]) -> Option<V> {Self::get(self, key)}
+            fn get_or_closest_by_key(&self, key: [u8; N], include_key: bool, loop_around: bool) -> Option<([u8; N], V)> {Self::get_or_closest_by_key(self, key, include_key, loop_around)}
+            fn get_or_closest_by_key_leading_zeroes(&self, key: [u8; N], include_key: bool) -> Option<([u8; N], V)> {Self::get_or_closest_by_key_leading_zeroes(self, key, include_key)}
+            fn get_min(&self) -> Option<([u8; N], V)> {Self::get_min(self)}
+            fn get_max(&self) -> Option<([u8; N], V)> {Self::get_max(self)}
+            fn len(&self) -> usize {Self::len(self)}
+            fn is_empty(&self) -> bool {Self::is_empty(self)}
+            fn depth(&self) -> usize {Self::depth(self)}
+            fn get_used_percent(&self) -> f64 {Self::get_used_percent(self)}
+            fn get_memory_size(&self) -> usize {Self::get_memory_size(self)}
+            fn get_memory_usage(&self) -> MemoryUsage {Self::get_memory_usage(self)}
+            fn stats(&self) -> MapStats {Self::stats(self)}
+            fn validate(&self) -> Result<(), ValidationError<N>> {Self::validate(self)}
+            fn dump_tree(&self, max_depth: usize, max_nodes: usize) -> String {Self::dump_tree(self, max_depth, max_nodes)}
+            fn to_dot(&self, max_depth: usize, max_nodes: usize) -> String {Self::to_dot(self, max_depth, max_nodes)}
+        }
+    };
+}
+
+impl_test_map!(concurrent);
+impl_test_map!(non_concurrent);
+
+fn check_shared_prefix<M: TestMap<4, u64>>(){
+    let mut map = M::default();
+    let to_insert = (0..200).map(|i: u32| {
+        let key = if i.is_multiple_of(2) {[7, 7, 7, random()]} else {[7, random_range(0..4), random(), random()]};
+        (key, i as u64)
+    }).collect::<Vec<_>>();
+    to_insert.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+    assert!(map.depth() <= 16);
+    for key in get_vec_of_key_values::<[u8; 4]>(200).into_iter().chain(to_insert.iter().map(|x| x.0)){
+        for include_key in [false, true]{
+            let expected = to_insert.iter().filter(|x| include_key || (x.0 != key));
+            let closest = map.get_or_closest_by_key(key, include_key, true).unwrap();
+            assert_eq!(get_circular_distance(key, closest.0), expected.clone().map(|x| get_circular_distance(key, x.0)).min().unwrap());
+            let closest = map.get_or_closest_by_key_leading_zeroes(key, include_key).unwrap();
+            assert_eq!(get_leading_zeroes(key, closest.0), expected.map(|x| get_leading_zeroes(key, x.0)).max().unwrap());
+        }
+    }
+    to_insert.iter().for_each(|x| map.remove(x.0));
+    assert_eq!(map.depth(), 0);
+}
+
+fn check_bits_per_level<M: TestMap<4, u64>>(){
+    let mut map = M::default();
+    //half share their first two bytes so there are long runs of digits in common
+    let mut to_insert = get_vec_of_key_values::<([u8; 4], u64)>(1000);
+    to_insert[500..].iter_mut().for_each(|x| x.0[..2].copy_from_slice(&[7, 7]));
+    to_insert.sort_by_key(|x| x.0);
+    to_insert.dedup_by_key(|x| x.0);
+    to_insert.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+    assert!(map.depth() <= (8 * 4 / M::BITS) + 1);
+    let check = |map: &M, to_insert: &[([u8; 4], u64)]| {
+        assert_eq!(map.len(), to_insert.len());
+        assert!(to_insert.iter().all(|x| map.get(x.0) == Some(x.1)));
+        let mut keys = get_vec_of_key_values::<[u8; 4]>(100);
+        keys[50..].iter_mut().for_each(|x| x[..2].copy_from_slice(&[7, 7]));
+        for key in keys{
+            let closest = map.get_or_closest_by_key(key, true, true).unwrap();
+            assert_eq!(get_circular_distance(key, closest.0), to_insert.iter().map(|x| get_circular_distance(key, x.0)).min().unwrap());
+            assert_eq!(map.get(closest.0), Some(closest.1));
+            let closest = map.get_or_closest_by_key_leading_zeroes(key, true).unwrap();
+            assert_eq!(get_leading_zeroes(key, closest.0), to_insert.iter().map(|x| get_leading_zeroes(key, x.0)).max().unwrap());
+            assert_eq!(map.get(closest.0), Some(closest.1));
+        }
+        assert_eq!(map.get_min().map(|x| x.0), to_insert.iter().map(|x| x.0).min());
+        assert_eq!(map.get_max().map(|x| x.0), to_insert.iter().map(|x| x.0).max());
+        assert_eq!(map.validate(), Ok(()));
+    };
+    check(&map, &to_insert);
+    //removing every other key collapses and merges lists
+    to_insert.iter().step_by(2).for_each(|x| map.remove(x.0));
+    let remaining = to_insert.iter().skip(1).step_by(2).copied().collect::<Vec<_>>();
+    check(&map, &remaining);
+    remaining.iter().for_each(|x| map.remove(x.0));
+    assert!(map.is_empty());
+    assert_eq!(map.depth(), 0);
+}
+
+//M has 8 bits per level
+fn check_adaptive_node<M: TestMap<4, u64>>(){
+    //every key branches at the same byte so one list grows through each kind of node and back
+    let mut map = M::default();
+    let mut sizes = Vec::new();
+    for i in 0..=u8::MAX{
+        assert_eq!(map.insert_or_update([1, i, 0, 0], i as u64), InsertOrUpdateResult::Inserted);
+        assert!((0..=i).all(|x| map.get([1, x, 0, 0]) == Some(x as u64)));
+        assert_eq!(map.get_or_closest_by_key([1, i, 0, 1], false, false).map(|x| x.0), Some([1, i, 0, 0]));
+        sizes.push(map.get_memory_size());
+    }
+    assert_eq!(map.depth(), 2);
+    //a list with few children is much smaller than one with a slot for every digit
+    assert!(sizes[3] * 16 < sizes[255]);
+    let mut small = M::default();
+    (0..3).for_each(|x| _ = small.insert_or_update([1, x, 0, 0], x as u64));
+    for i in (3..=u8::MAX).rev(){
+        map.remove([1, i, 0, 0]);
+        assert_eq!(map.len(), i as usize);
+        assert_eq!(map.get_max().map(|x| x.0), Some([1, i - 1, 0, 0]));
+        assert_eq!(map.get_or_closest_by_key([1, i, 0, 0], true, false).map(|x| x.0), Some([1, i - 1, 0, 0]));
+    }
+    assert!((0..3).all(|x| map.get([1, x, 0, 0]) == small.get([1, x, 0, 0])));
+    assert_eq!(map.get_memory_size(), small.get_memory_size());
+}
+
+//S has single entry buckets and M buckets of 8 with 2 bits per level
+fn check_buckets<S: TestMap<32, [u8; 32]>, M: TestMap<32, [u8; 32]>>(){
+    //fewer allocations and lists for the same entries
+    let to_insert = get_vec_of_key_values::<([u8; 32], [u8; 32])>(10000);
+    let mut single = S::default();
+    let mut bucketed = M::default();
+    to_insert.iter().for_each(|x| {
+        single.insert_or_update(x.0, x.1);
+        bucketed.insert_or_update(x.0, x.1);
+    });
+    assert!(bucketed.get_used_percent() > single.get_used_percent());
+    assert!(bucketed.depth() < single.depth());
+    //removing down to B entries goes back to a single bucket
+    to_insert[8..].iter().for_each(|x| bucketed.remove(x.0));
+    assert_eq!(bucketed.depth(), 1);
+    assert!(to_insert[..8].iter().all(|x| bucketed.get(x.0) == Some(x.1)));
+}
+
+//F and C only differ in C being compact
+fn check_compact<F: TestMap<64, u64>, C: TestMap<64, u64>>(){
+    //64 byte keys with long shared prefixes, compact leaves only store what their position doesn't imply
+    let mut to_insert = get_vec_of_key_values::<([u8; 64], u64)>(5000);
+    to_insert.iter_mut().for_each(|x| x.0 = std::array::from_fn(|i| if i < 40 {x.0[40] % 4} else {x.0[i]}));
+    let mut full = F::default();
+    let mut compact = C::default();
+    to_insert.iter().for_each(|x| {
+        full.insert_or_update(x.0, x.1);
+        compact.insert_or_update(x.0, x.1);
+    });
+    assert!(compact.get_memory_size() < full.get_memory_size());
+    assert!(to_insert.iter().all(|x| compact.get(x.0) == Some(x.1)));
+    for key in get_vec_of_key_values::<([u8; 64], u64)>(100).into_iter().map(|x| x.0).chain(to_insert.iter().take(100).map(|x| x.0)){
+        assert_eq!(compact.get(key), full.get(key));
+        assert_eq!(compact.get_or_closest_by_key(key, false, true), full.get_or_closest_by_key(key, false, true));
+        assert_eq!(compact.get_or_closest_by_key_leading_zeroes(key, false), full.get_or_closest_by_key_leading_zeroes(key, false));
+    }
+    assert_eq!(compact.get_min(), full.get_min());
+    assert_eq!(compact.get_max(), full.get_max());
+}
+
+//inserts then removes every key a few times, returns how long it took
+fn check_churn<M: TestMap<32, u64>>(map: &mut M, key_values: &[([u8; 32], u64)]) -> f64{
+    let start_time = SystemTime::now();
+    for _ in 0..4{
+        key_values.iter().for_each(|x| assert_eq!(map.insert_or_update(x.0, x.1), InsertOrUpdateResult::Inserted));
+        key_values.iter().for_each(|x| assert!(map.remove_if(x.0, &|_| true)));
+    }
+    assert!(map.is_empty());
+    assert_eq!(map.validate(), Ok(()));
+    SystemTime::now().duration_since(start_time).unwrap().as_secs_f64()
+}
+
+//the keys dump_test and stats_test lay out by hand
+const SMALL_TREE: [[u8; 4]; 5] = [[1, 2, 3, 4], [1, 2, 3, 5], [1, 2, 0x83, 0], [0x81, 0, 0, 0], [0x82, 0, 0, 0]];
+
+//returns the map with SMALL_TREE in it for checks of its own layout
+fn check_dump<M: TestMap<4, u64>>(mut map: M, tree: &[&str]) -> M{
+    SMALL_TREE.iter().for_each(|x| _ = map.insert_or_update(*x, 0));
+    assert_eq!(map.dump_tree(usize::MAX, usize::MAX), tree.join("\n"));
+    let dot = map.to_dot(usize::MAX, usize::MAX);
+    assert!(dot.starts_with("digraph{\n") && dot.ends_with("}\n"));
+    assert_eq!(M::default().dump_tree(usize::MAX, usize::MAX), "empty\n");
+    map
+}
+
+//expected is what stats finds with SMALL_TREE in map, L is any map for comparing stats to walking it for each on its own
+fn check_stats<M: TestMap<4, u64>, L: TestMap<32, u64>>(mut map: M, expected: MapStats){
+    SMALL_TREE.iter().for_each(|x| _ = map.insert_or_update(*x, 0));
+    assert_eq!(map.stats(), MapStats{memory: map.get_memory_usage(), ..expected});
+    let mut map = L::default();
+    get_vec_of_key_values::<([u8; 32], u64)>(10000).iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+    let stats = map.stats();
+    assert_eq!((stats.len, stats.depth, stats.memory.total()), (map.len(), map.depth(), map.get_memory_size()));
+    assert_eq!(stats.bucket_depths.iter().sum::<usize>(), stats.buckets);
+    assert_eq!(stats.list_occupancy.iter().sum::<usize>(), stats.lists);
+    //every bucket and list but the root is a child of a list
+    assert_eq!(stats.list_occupancy.iter().enumerate().map(|x| x.0 * x.1).sum::<usize>(), stats.buckets + stats.lists - 1);
+}
+
 mod concurrent_tests{
     use rust_map::concurrent::Map;
     use super::*;
 
+    //a pointer with its lock packed into the top bits, or where they can't be relied on a pointer and a lock word
+    const SLOT: usize = if cfg!(all(target_pointer_width = "64", not(target_os = "android"))) {8} else {2 * size_of::<usize>()};
+
     #[test]
     fn length_test(){
         let expected = 10000;
@@ -32,7 +271,7 @@ mod concurrent_tests{
 
     #[test]
     fn get_size(){
-        assert_eq!(size_of::<Map<32, u64>>(), 24);
+        assert_eq!(size_of::<Map<32, u64>>(), SLOT);
     }
 
     #[test]
@@ -43,6 +282,7 @@ mod concurrent_tests{
         array[array.len()-1] = 1;
         map.insert_or_update(array, 0);
         println!("{}", map.depth());
+        assert_eq!(map.depth(), 2);
     }
     
     #[test]
@@ -98,16 +338,574 @@ mod concurrent_tests{
         assert_eq!(true_count, total);
     }
 
+    #[test]
+    fn shared_prefix_test(){
+        check_shared_prefix::<Map<4, u64>>();
+    }
+
+    #[test]
+    fn adaptive_node_test(){
+        check_adaptive_node::<Map<4, u64, 8>>();
+    }
+
+    #[test]
+    fn arena_test(){
+        check_bits_per_level::<Map<4, u64, 2, 1, false, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 4, true, true>>();
+        check_churn(&mut Map::<32, u64, 2, 1, false, true>::new(), &get_vec_of_key_values(10000));
+        //churn from every thread at once
+        let no_threads = num_cpus::get().max(4);
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(no_threads * 2000);
+        let arena = Map::<32, u64, 2, 1, false, true>::new();
+        time_threads(no_threads, |i| (0..4).for_each(|_| {
+            key_values.iter().skip(i).step_by(no_threads).for_each(|x| assert_eq!(arena.insert_or_update(x.0, x.1), InsertOrUpdateResult::Inserted));
+            key_values.iter().skip(i).step_by(no_threads).for_each(|x| assert!(arena.remove_if(x.0, &|_| true)));
+        }));
+        assert!(arena.is_empty());
+        assert_eq!(arena.validate(), Ok(()));
+    }
+
+    #[test]
+    #[ignore = "prints timings, run with --ignored"]
+    fn bench_arena(){
+        //insert and remove churn from every thread, against the global allocator
+        let no_threads = num_cpus::get().max(4);
+        let total_per_thread = 10000;
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(no_threads * total_per_thread);
+        let thread_range = |i: usize| (i * total_per_thread)..((i + 1) * total_per_thread);
+        let global = Map::<32, u64>::new();
+        let arena = Map::<32, u64, 2, 1, false, true>::new();
+        let global_churn = time_threads(no_threads, |i| (0..4).for_each(|_| {
+            key_values[thread_range(i)].iter().for_each(|x| _ = global.insert_or_update(x.0, x.1));
+            key_values[thread_range(i)].iter().for_each(|x| global.remove(x.0));
+        }));
+        let arena_churn = time_threads(no_threads, |i| (0..4).for_each(|_| {
+            key_values[thread_range(i)].iter().for_each(|x| _ = arena.insert_or_update(x.0, x.1));
+            key_values[thread_range(i)].iter().for_each(|x| arena.remove(x.0));
+        }));
+        println!("churn secs global allocator: {} arena: {}", global_churn, arena_churn);
+    }
+
+    #[test]
+    fn sharded_root_test(){
+        //the first two levels are shards, 16 of them, that stay when empty
+        let empty_size = Map::<32, u64>::new().get_memory_size();
+        let map = Map::<32, u64>::with_sharded_root(2);
+        assert!(map.is_empty());
+        assert_eq!(map.depth(), 2);
+        let sharded_size = map.get_memory_size();
+        assert!(sharded_size > empty_size);
+        let no_threads = num_cpus::get().max(4);
+        let total_per_thread = 5000;
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(no_threads * total_per_thread);
+        let thread_range = |i: usize| (i * total_per_thread)..((i + 1) * total_per_thread);
+        time_threads(no_threads, |i| key_values[thread_range(i)].iter()
+            .for_each(|x| assert_eq!(map.insert_or_update(x.0, x.1), InsertOrUpdateResult::Inserted)));
+        assert_eq!(map.len(), key_values.len());
+        assert!(key_values.iter().all(|x| map.get(x.0) == Some(x.1)));
+        assert_eq!(map.validate(), Ok(()));
+        //the same answers as a map without shards
+        let unsharded = Map::<32, u64>::new();
+        key_values.iter().for_each(|x| _ = unsharded.insert_or_update(x.0, x.1));
+        assert!(map.depth() > 2);
+        assert_eq!((map.get_min(), map.get_max()), (unsharded.get_min(), unsharded.get_max()));
+        get_vec_of_key_values::<[u8; 32]>(1000).iter().for_each(|x| {
+            assert_eq!(map.get_or_closest_by_key(*x, true, true), unsharded.get_or_closest_by_key(*x, true, true));
+            assert_eq!(map.get_or_closest_by_key_leading_zeroes(*x, false), unsharded.get_or_closest_by_key_leading_zeroes(*x, false));
+        });
+        time_threads(no_threads, |i| key_values[thread_range(i)].iter()
+            .for_each(|x| assert!(map.remove_if(x.0, &|_| true))));
+        assert!(map.is_empty());
+        assert_eq!(map.depth(), 2);
+        assert_eq!(map.validate(), Ok(()));
+        assert_eq!(map.get_memory_size(), sharded_size);
+        key_values.iter().take(100).for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        map.clear();
+        assert_eq!(map.get_memory_size(), sharded_size);
+    }
+
+    #[test]
+    fn remove_doesnt_stall_gets_test(){
+        let map = Map::<32, u64, 2, 4>::new();
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(10000);
+        key_values.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        let blocked = key_values[0];
+        //keys under other first digits, so the top list is the only one they have in common with the blocked remove
+        let others = key_values.iter().filter(|x| (x.0[0] >> 6) != (blocked.0[0] >> 6)).copied().collect::<Vec<_>>();
+        let (started, released, done) = (AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false));
+        std::thread::scope(|scope| {
+            //should_remove runs with the blocked remove holding read locks on the lists above it
+            scope.spawn(|| assert!(!map.remove_if(blocked.0, &|_| {
+                started.store(true, Ordering::SeqCst);
+                let start_time = SystemTime::now();
+                while !done.load(Ordering::SeqCst) && (start_time.elapsed().unwrap() < Duration::from_secs(10)) {std::thread::yield_now()}
+                released.store(true, Ordering::SeqCst);
+                false
+            })));
+            while !started.load(Ordering::SeqCst) {std::thread::yield_now()}
+            //half of the other keys are removed, leaving every list above them with children so none collapse
+            time_threads(4, |i| others.iter().skip(i).step_by(4).for_each(|x| {
+                if i % 2 == 0 {map.remove(x.0)} else {assert_eq!(map.get(x.0), Some(x.1))}
+            }));
+            assert!(!released.load(Ordering::SeqCst));
+            done.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(map.get(blocked.0), Some(blocked.1));
+        assert_eq!(map.len(), key_values.len() - others.iter().step_by(4).count() - others.iter().skip(2).step_by(4).count());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn to_non_concurrent_test(){
+        let map = Map::<32, u64, 2, 2>::new();
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(20000);
+        let (inserted, removed) = key_values.split_at(10000);
+        inserted.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        let copy = map.to_non_concurrent();
+        //later writes don't reach it
+        removed.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        inserted.iter().step_by(2).for_each(|x| map.remove(x.0));
+        assert_eq!(copy.len(), inserted.len());
+        assert!(inserted.iter().all(|x| copy.get(x.0) == Some(x.1)));
+        assert_eq!(copy.validate(), Ok(()));
+        //one thread inserts then removes keys in a random order while others take copies, each of which has to have
+        //every key that went in before any it has and none that came out after any it doesn't
+        map.clear();
+        let positions = key_values.iter().enumerate().map(|x| (x.1.0, x.0)).collect::<std::collections::HashMap<_, _>>();
+        let (done, copies) = (AtomicBool::new(false), AtomicUsize::new(0));
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                key_values.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+                key_values.iter().for_each(|x| map.remove(x.0));
+                done.store(true, Ordering::SeqCst);
+            });
+            time_threads(2, |_| while !done.load(Ordering::SeqCst){
+                copies.fetch_add(1, Ordering::SeqCst);
+                let copy = map.to_non_concurrent();
+                let mut keys = Vec::new();
+                copy.for_each(|key, _| keys.push(positions[&key]));
+                keys.sort();
+                //the keys still in are a run of positions, from the first not yet removed to the last inserted
+                assert!(keys.windows(2).all(|x| x[1] == x[0] + 1), "copy isn't of one moment");
+            });
+        });
+        assert!(map.is_empty());
+        println!("copies taken {}", copies.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "optimistic")]
+    #[test]
+    fn snapshot_handle_test(){
+        let map = Map::<32, u64, 2, 2>::new();
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(20000);
+        let (inserted, later) = key_values.split_at(10000);
+        inserted.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        let snapshot = map.snapshot_handle();
+        let (min, max) = (map.get_min(), map.get_max());
+        //later inserts, removes and updates don't reach it, nor a snapshot taken in between
+        later.iter().for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        let between = map.snapshot_handle();
+        inserted.iter().step_by(2).for_each(|x| map.remove(x.0));
+        inserted.iter().skip(1).step_by(2).for_each(|x| _ = map.insert_or_update(x.0, x.1 + 1));
+        map.insert_or_update([0; 32], 0);
+        assert_eq!(snapshot.len(), inserted.len());
+        assert!(inserted.iter().all(|x| snapshot.get(x.0) == Some(x.1)));
+        assert!(later.iter().all(|x| snapshot.get(x.0).is_none()));
+        assert_eq!((snapshot.get_min(), snapshot.get_max()), (min, max));
+        assert_eq!(snapshot.get_or_closest_by_key([0; 32], true, false), min);
+        assert_eq!(snapshot.get_or_closest_by_key_leading_zeroes(inserted[1].0, true), Some(inserted[1]));
+        let copy = snapshot.to_non_concurrent();
+        assert_eq!(copy.len(), inserted.len());
+        assert_eq!(copy.validate(), Ok(()));
+        assert_eq!(between.len(), key_values.len());
+        assert!(key_values.iter().all(|x| between.get(x.0) == Some(x.1)));
+        drop((snapshot, between));
+        assert_eq!(map.len(), later.len() + (inserted.len() / 2) + 1);
+        assert_eq!(map.get(inserted[1].0), Some(inserted[1].1 + 1));
+        assert_eq!(map.validate(), Ok(()));
+        //a snapshot is taken and read while a writer holds a lock, seeing the map as it was before the write
+        let (locked, unlocked) = (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        std::thread::scope(|scope| {
+            scope.spawn(|| map.insert_or_update_if(later[0].0, 0, &|_, _| {
+                locked.wait();
+                unlocked.wait();
+                true
+            }));
+            locked.wait();
+            let snapshot = map.snapshot_handle();
+            assert_eq!(snapshot.get(later[0].0), Some(later[0].1));
+            assert_eq!(snapshot.len(), map.len());
+            unlocked.wait();
+            std::thread::sleep(Duration::from_millis(10));
+            assert_eq!(snapshot.get(later[0].0), Some(later[0].1));
+        });
+        assert_eq!(map.snapshot_handle().get(later[0].0), Some(0));
+        //threads move random amounts between accounts in transactions while others take snapshots, each of which has to
+        //hold the same total and read the same each time
+        let accounts = get_vec_of_key_values::<[u8; 32]>(64);
+        map.clear();
+        accounts.iter().for_each(|x| _ = map.insert_or_update(*x, 1000));
+        let total = 1000 * accounts.len() as u64;
+        let (done, snapshots) = (AtomicBool::new(false), AtomicUsize::new(0));
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                time_threads(3, |_| (0..2000).for_each(|_| {
+                    _ = map.transaction(|tx| {
+                        let (from, to) = (accounts[random_range(0..accounts.len())], accounts[random_range(0..accounts.len())]);
+                        if from == to {return Err(())}
+                        let (balance, other) = (tx.get(from).ok_or(())?, tx.get(to).unwrap_or(0));
+                        let amount = random_range(1..=balance);
+                        if amount == balance {tx.remove(from);} else {tx.insert_or_update(from, balance - amount);}
+                        tx.insert_or_update(to, other + amount);
+                        Ok(())
+                    });
+                }));
+                done.store(true, Ordering::SeqCst);
+            });
+            time_threads(2, |_| while !done.load(Ordering::SeqCst){
+                snapshots.fetch_add(1, Ordering::SeqCst);
+                let snapshot = map.snapshot_handle();
+                let (mut sum, len) = (0, snapshot.len());
+                snapshot.for_each(|_, value| sum += value);
+                assert_eq!(sum, total, "snapshot isn't of one moment");
+                assert_eq!(accounts.iter().map(|x| snapshot.get(*x).unwrap_or(0)).sum::<u64>(), total);
+                assert_eq!(snapshot.len(), len);
+            });
+        });
+        assert_eq!(map.snapshot_handle().to_non_concurrent().len(), map.len());
+        assert_eq!(map.validate(), Ok(()));
+        println!("snapshots taken {}", snapshots.load(Ordering::SeqCst));
+    }
+
+    //readers racing writers that split, grow, shrink and collapse the lists above the keys they read, which with the
+    //optimistic feature is done without any read locks
+    fn check_get_during_writes<const BITS: usize, const B: usize, const COMPACT: bool, const ARENA: bool>() where BitsPerLevel<BITS>: SupportedBitsPerLevel, Map<4, u64, BITS, B, COMPACT, ARENA>: Sync{
+        let map = Map::<4, u64, BITS, B, COMPACT, ARENA>::new();
+        let keys = get_vec_of_key_values::<[u8; 4]>(4000);
+        let (kept, churned) = keys.split_at(1000);
+        kept.iter().for_each(|x| _ = map.insert_or_update(*x, u32::from_be_bytes(*x) as u64));
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                (0..4).for_each(|i| {
+                    churned.iter().for_each(|x| _ = map.insert_or_update(*x, u32::from_be_bytes(*x) as u64 + i));
+                    churned.iter().step_by(2).for_each(|x| map.remove(*x));
+                });
+                churned.iter().for_each(|x| map.remove(*x));
+                done.store(true, Ordering::SeqCst);
+            });
+            time_threads(2, |_| while !done.load(Ordering::SeqCst){
+                assert!(kept.iter().all(|x| map.get(*x) == Some(u32::from_be_bytes(*x) as u64)));
+                assert!(churned.iter().all(|x| map.get(*x).is_none_or(|y| (y - u32::from_be_bytes(*x) as u64) < 4)));
+            });
+        });
+        assert_eq!(map.len(), kept.len());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn get_during_writes_test(){
+        check_get_during_writes::<2, 1, false, false>();
+        check_get_during_writes::<4, 4, true, false>();
+        check_get_during_writes::<8, 2, false, true>();
+    }
+
+    //the queries that look down more than one path don't lock either, so they go past a bucket a writer has locked
+    //(here while should_update runs) and see what it held, where with read locks they would wait until it was let go of
+    #[cfg(feature = "optimistic")]
+    #[test]
+    fn queries_during_write_test(){
+        let map = Map::<4, u64>::new();
+        SMALL_TREE.iter().for_each(|x| _ = map.insert_or_update(*x, u32::from_be_bytes(*x) as u64));
+        let (locked, unlocked) = (std::sync::Barrier::new(2), std::sync::Barrier::new(2));
+        let first = (SMALL_TREE[0], u32::from_be_bytes(SMALL_TREE[0]) as u64);
+        std::thread::scope(|scope| {
+            scope.spawn(|| map.insert_or_update_if(first.0, 0, &|_, _| {
+                locked.wait();
+                unlocked.wait();
+                true
+            }));
+            locked.wait();
+            assert_eq!(map.get_or_closest_by_key(first.0, true, false), Some(first));
+            assert_eq!(map.get_or_closest_by_key([1, 2, 3, 3], false, true), Some(first));
+            assert_eq!(map.get_or_closest_by_key_leading_zeroes(first.0, true), Some(first));
+            assert_eq!(map.get_min(), Some(first));
+            assert_eq!(map.get_max().map(|x| x.0), Some(SMALL_TREE[4]));
+            let mut keys = Vec::new();
+            map.for_each(|key, _| keys.push(key));
+            assert_eq!((keys.len(), map.len()), (SMALL_TREE.len(), SMALL_TREE.len()));
+            unlocked.wait();
+        });
+        assert_eq!(map.get(first.0), Some(0));
+    }
+
+    #[test]
+    fn transaction_test(){
+        let map = Map::<4, u64, 2, 2, false, false, true>::new();
+        let accounts = get_vec_of_key_values::<[u8; 4]>(64);
+        accounts.iter().for_each(|x| _ = map.insert_or_update(*x, 1000));
+        let total = 1000 * accounts.len() as u64;
+        //writes made before an Err or a panic don't reach the map
+        let result: Result<(), &str> = map.transaction(|tx| {
+            assert_eq!(tx.insert_or_update(accounts[0], 0), InsertOrUpdateResult::Updated);
+            assert_eq!(tx.remove(accounts[1]), Some(1000));
+            assert_eq!(tx.get(accounts[1]), None);
+            assert_eq!(tx.insert_or_update([0; 4], 5), InsertOrUpdateResult::Inserted);
+            Err("rolled back")
+        });
+        assert_eq!(result, Err("rolled back"));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| map.transaction(|tx| -> Result<(), ()> {
+            tx.remove(accounts[0]);
+            panic!("rolled back")
+        })));
+        assert!(panicked.is_err());
+        assert!(accounts.iter().all(|x| map.get(*x) == Some(1000)));
+        assert_eq!(map.get([0; 4]), None);
+        //threads move random amounts between accounts, emptied accounts being removed and put back when paid into,
+        //while another reads every account in one transaction and has to see the same total each time
+        let (done, reads) = (AtomicBool::new(false), AtomicUsize::new(0));
+        std::thread::scope(|scope| {
+            scope.spawn(|| while !done.load(Ordering::SeqCst){
+                reads.fetch_add(1, Ordering::SeqCst);
+                let sum = map.transaction(|tx| Ok::<_, ()>(accounts.iter().map(|x| tx.get(*x).unwrap_or(0)).sum::<u64>()));
+                assert_eq!(sum, Ok(total));
+            });
+            time_threads(4, |_| (0..2000).for_each(|_| {
+                _ = map.transaction(|tx| {
+                    let (from, to) = (accounts[random_range(0..accounts.len())], accounts[random_range(0..accounts.len())]);
+                    if from == to {return Err(())}
+                    let (balance, other) = (tx.get(from).ok_or(())?, tx.get(to).unwrap_or(0));
+                    let amount = random_range(1..=balance);
+                    if amount == balance {tx.remove(from);} else {tx.insert_or_update(from, balance - amount);}
+                    tx.insert_or_update(to, other + amount);
+                    Ok(())
+                });
+            }));
+            done.store(true, Ordering::SeqCst);
+        });
+        let mut sum = 0;
+        map.for_each(|_, value| sum += value);
+        assert_eq!(sum, total);
+        assert_eq!(map.len_approx(), map.len());
+        assert_eq!(map.validate(), Ok(()));
+        println!("read transactions {}", reads.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn compare_exchange_test(){
+        let map = Map::<4, u64, 2, 2, false, false, true>::new();
+        let key = [1, 2, 3, 4];
+        assert_eq!(map.compare_exchange(key, None, None), Ok(None));
+        assert_eq!(map.compare_exchange(key, Some(1), Some(2)), Err(None));
+        assert_eq!(map.get(key), None);
+        assert_eq!(map.compare_exchange(key, None, Some(1)), Ok(None));
+        assert_eq!(map.compare_exchange(key, None, Some(5)), Err(Some(1)));
+        assert_eq!(map.compare_exchange(key, None, None), Err(Some(1)));
+        assert_eq!(map.compare_exchange(key, Some(2), Some(3)), Err(Some(1)));
+        assert_eq!(map.compare_exchange(key, Some(1), Some(3)), Ok(Some(1)));
+        assert_eq!(map.compare_exchange(key, Some(1), None), Err(Some(3)));
+        assert_eq!(map.get(key), Some(3));
+        assert_eq!(map.swap(key, 4), Some(3));
+        assert_eq!(map.compare_exchange(key, Some(4), None), Ok(Some(4)));
+        assert_eq!(map.get(key), None);
+        assert_eq!(map.swap(key, 6), None);
+        assert_eq!(map.get(key), Some(6));
+        assert_eq!(map.len_approx(), 1);
+        //threads add to counters in compare_exchange loops, creating and deleting them as they go, and claim keys that
+        //only one of them can get
+        map.clear();
+        let no_threads = 4;
+        let counters = (0..8u8).map(|x| [255, 0, 0, x]).collect::<Vec<_>>();
+        let claims = (0..1000u32).map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        let claimed = AtomicUsize::new(0);
+        time_threads(no_threads, |i| {
+            (0..2000).for_each(|j| {
+                let key = counters[j % counters.len()];
+                let mut current = map.get(key);
+                //a counter at 0 isn't in the map
+                while let Err(actual) = map.compare_exchange(key, current, Some(current.unwrap_or(0) + 1)) {current = actual}
+                let mut current = map.get(key);
+                while let Err(actual) = map.compare_exchange(key, current, current.filter(|x| *x > 1).map(|x| x - 1)) {current = actual}
+                let mut current = map.get(key);
+                while let Err(actual) = map.compare_exchange(key, current, Some(current.unwrap_or(0) + 1)) {current = actual}
+            });
+            claims.iter().for_each(|x| if map.compare_exchange(*x, None, Some(i as u64)).is_ok() {claimed.fetch_add(1, Ordering::SeqCst);});
+        });
+        assert_eq!(counters.iter().map(|x| map.get(*x).unwrap_or(0)).sum::<u64>(), (no_threads * 2000) as u64);
+        assert_eq!(claimed.load(Ordering::SeqCst), claims.len());
+        assert_eq!(map.len_approx(), map.len());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn pop_test(){
+        let map = Map::<4, u64, 2, 2, true, false, true>::new();
+        assert_eq!(map.pop_min(), None);
+        [[0, 0, 0, 5], [0, 0, 0, 9], [3, 0, 0, 1], [7, 7, 7, 7]].iter().enumerate().for_each(|x| _ = map.insert_or_update(*x.1, x.0 as u64));
+        assert_eq!(map.pop_max(), Some(([7, 7, 7, 7], 3)));
+        assert_eq!(map.pop_min_if(&|key, _| key[0] > 0), None);
+        assert_eq!(map.pop_min_if(&|_, value| *value == 0), Some(([0, 0, 0, 5], 0)));
+        assert_eq!(map.pop_min(), Some(([0, 0, 0, 9], 1)));
+        assert_eq!(map.pop_max_if(&|_, value| *value == 0), None);
+        assert_eq!(map.pop_max(), Some(([3, 0, 0, 1], 2)));
+        assert!(map.is_empty());
+        assert_eq!(map.len_approx(), 0);
+        //threads pop a queue of keys until it is empty, while others add to it. each key goes to one thread, and as
+        //keys are added in order each thread gets its keys in order
+        let no_threads = 4;
+        let total_per_thread = 5000;
+        let keys = (0..((2 * no_threads * total_per_thread) as u32)).map(|x| x.to_be_bytes()).collect::<Vec<_>>();
+        let (queued, added) = keys.split_at(no_threads * total_per_thread);
+        queued.iter().for_each(|x| _ = map.insert_or_update(*x, 0));
+        let popped = (0..no_threads).map(|_| std::sync::Mutex::new(Vec::new())).collect::<Vec<_>>();
+        let adding = AtomicUsize::new(no_threads);
+        time_threads(2 * no_threads, |i| {
+            if i < no_threads{
+                added[(i * total_per_thread)..((i + 1) * total_per_thread)].iter().for_each(|x| _ = map.insert_or_update(*x, 0));
+                adding.fetch_sub(1, Ordering::SeqCst);
+                return
+            }
+            let mut popped = popped[i - no_threads].lock().unwrap();
+            loop{
+                let done = adding.load(Ordering::SeqCst) == 0;
+                match map.pop_min(){
+                    Some((key, _)) => popped.push(key),
+                    None if done => break,
+                    None => std::thread::yield_now()
+                }
+            }
+        });
+        let popped = popped.into_iter().map(|x| x.into_inner().unwrap()).collect::<Vec<_>>();
+        //the queued keys are all smaller than the added ones so come out in order
+        assert!(popped.iter().all(|x| x.iter().filter(|y| **y < added[0]).is_sorted()));
+        let mut all = popped.concat();
+        all.sort();
+        assert_eq!(all, keys);
+        assert!(map.is_empty());
+        assert_eq!(map.len_approx(), 0);
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn pop_max_transaction_test(){
+        //pop_max passes over the shards above the edge, which a transaction locks after the one below it, so the two
+        //have to take their locks in the same order or each ends up waiting on the other
+        let map = Map::<1, u64, 2, 1, false, false, true>::with_sharded_root(1);
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                (0..200000).for_each(|_| _ = map.transaction(|tx| {
+                    tx.insert_or_update([0x80], 0);
+                    tx.remove([0xC0]);
+                    Ok::<_, ()>(())
+                }));
+                done.store(true, Ordering::SeqCst);
+            });
+            scope.spawn(|| while !done.load(Ordering::SeqCst) {_ = map.pop_max()});
+        });
+        assert_eq!(map.len_approx(), map.len());
+        assert_eq!(map.validate(), Ok(()));
+    }
+
+    #[test]
+    fn counted_test(){
+        //the count is only on the map itself, not the maps in list slots
+        assert_eq!(size_of::<Map<32, u64>>(), SLOT);
+        let map = Map::<32, u64, 2, 1, false, false, true>::with_sharded_root(1);
+        assert_eq!(map.len_approx(), 0);
+        let no_threads = num_cpus::get().max(4);
+        let total_per_thread = 5000;
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(no_threads * total_per_thread);
+        let thread_range = |i: usize| (i * total_per_thread)..((i + 1) * total_per_thread);
+        //updates of keys already there and removes of keys that aren't don't count
+        time_threads(no_threads, |i| key_values[thread_range(i)].iter().for_each(|x| {
+            map.insert_or_update(x.0, x.1);
+            map.insert_or_update(x.0, x.1);
+            if x.1 % 3 == 0 {map.remove(x.0); map.remove(x.0)}
+        }));
+        assert_eq!(map.len_approx(), map.len());
+        assert_eq!(map.len(), key_values.iter().filter(|x| x.1 % 3 != 0).count());
+        time_threads(no_threads, |i| key_values[thread_range(i)].iter().step_by(2).for_each(|x| map.remove(x.0)));
+        assert_eq!(map.len_approx(), map.len());
+        map.clear();
+        assert_eq!(map.len_approx(), 0);
+        key_values.iter().take(100).for_each(|x| _ = map.insert_or_update(x.0, x.1));
+        assert_eq!(map.len_approx(), 100);
+        //changed from a thread local's destructor as the thread exits
+        static EXITING: Map<32, u64, 2, 1, false, false, true> = Map::new();
+        struct InsertOnExit;
+        impl Drop for InsertOnExit{
+            fn drop(&mut self){
+                EXITING.insert_or_update([1; 32], 1);
+            }
+        }
+        thread_local!{
+            static ON_EXIT: InsertOnExit = const {InsertOnExit};
+        }
+        std::thread::spawn(|| ON_EXIT.with(|_| ())).join().unwrap();
+        assert_eq!((EXITING.len_approx(), EXITING.get([1; 32])), (1, Some(1)));
+    }
+
+    #[test]
+    fn bucket_test(){
+        check_bits_per_level::<Map<4, u64, 2, 8>>();
+        check_bits_per_level::<Map<4, u64, 4, 16>>();
+        check_bits_per_level::<Map<4, u64, 8, 4>>();
+        check_buckets::<Map<32, [u8; 32]>, Map<32, [u8; 32], 2, 8>>();
+    }
+
+    #[test]
+    fn compact_test(){
+        check_bits_per_level::<Map<4, u64, 2, 1, true>>();
+        check_bits_per_level::<Map<4, u64, 4, 8, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 1, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 4, true>>();
+        check_compact::<Map<64, u64, 8, 4>, Map<64, u64, 8, 4, true>>();
+    }
+
+    #[test]
+    fn dump_test(){
+        let map = check_dump(Map::<4, u64, 2, 2>::with_sharded_root(1), &[
+            "/0 sharded 2 children",
+            "  0: 0102/16 2 children",
+            "    0: bucket 01020304, 01020305",
+            "    2: bucket 01028300",
+            "  2: bucket 81000000, 82000000",
+            ""
+        ]);
+        assert_eq!(map.dump_tree(0, usize::MAX), "/0 sharded 2 children\n  2 more\n");
+        let dot = map.to_dot(usize::MAX, 3);
+        assert!(dot.contains("n1 -> n2 [label=\"0\"]") && dot.contains("n1_more [label=\"1 more\" shape=plaintext]"));
+    }
+
+    #[test]
+    fn stats_test(){
+        check_stats::<_, Map<32, u64, 4, 4>>(Map::<4, u64, 2, 2>::with_sharded_root(1), MapStats{len: 5, buckets: 3, lists: 2, empty_slots: 2,
+            bucket_depths: vec![0, 0, 1, 2], list_occupancy: vec![0, 0, 2], depth: 3, average_depth: 2.6, ..MapStats::default()});
+        assert_eq!(Map::<32, u64>::new().stats(), MapStats{empty_slots: 1, memory: MemoryUsage{slots: SLOT, ..MemoryUsage::default()}, ..MapStats::default()});
+    }
+
+    #[test]
+    fn bits_per_level_test(){
+        check_bits_per_level::<Map<4, u64, 1>>();
+        check_bits_per_level::<Map<4, u64, 2>>();
+        check_bits_per_level::<Map<4, u64, 4>>();
+        check_bits_per_level::<Map<4, u64, 8>>();
+    }
+
     static GLOBAL_MAP: Map<32, [u8;32]> = Map::new();
 
     static TRUE_COUNT: AtomicUsize = AtomicUsize::new(0);
 
+    #[allow(clippy::declare_interior_mutable_const, clippy::redundant_closure)]
     const NO_THREADS: LazyLock<usize> = LazyLock::new(|| num_cpus::get());
     const TOTAL_PER_THREAD: usize = 100000;
 
+    #[allow(clippy::type_complexity, clippy::borrow_interior_mutable_const)]
     static USER_LIST: LazyLock<RwLock<Vec<([u8; 32], [u8;32])>>> = LazyLock::new(|| RwLock::new(get_vec_of_key_values((*NO_THREADS)*TOTAL_PER_THREAD)));
 
     #[test]
+    #[allow(clippy::borrow_interior_mutable_const)]
     fn bench_multi_thread(){
         println!("no_threads: {}", *NO_THREADS);
         tokio::runtime::Builder::new_multi_thread()
@@ -209,7 +1007,7 @@ mod non_concurrent_tests{
 
     #[test]
     fn get_size(){
-        assert_eq!(size_of::<Map<32, u64>>(), 16);
+        assert_eq!(size_of::<Map<32, u64>>(), 8);
     }
 
     #[test]
@@ -220,6 +1018,7 @@ mod non_concurrent_tests{
         array[array.len()-1] = 1;
         map.insert_or_update(array, 0);
         println!("max_depth: {}", map.depth());
+        assert_eq!(map.depth(), 2);
     }
 
     #[test]
@@ -273,6 +1072,105 @@ mod non_concurrent_tests{
         assert!(map.get_or_closest_by_key_leading_zeroes([1;32], true).is_some_and(|x| x.0 == key));
     }
 
+    #[test]
+    fn shared_prefix_test(){
+        check_shared_prefix::<Map<4, u64>>();
+    }
+
+    #[test]
+    fn adaptive_node_test(){
+        check_adaptive_node::<Map<4, u64, 8>>();
+    }
+
+    #[test]
+    fn arena_test(){
+        check_bits_per_level::<Map<4, u64, 2, 1, false, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 4, true, true>>();
+        let mut arena = Map::<32, u64, 2, 1, false, true>::new();
+        check_churn(&mut arena, &get_vec_of_key_values(10000));
+        //the nodes this thread freed are still held by the arena until they are given back
+        assert!(arena.shrink_to_fit() > 0);
+    }
+
+    #[test]
+    #[ignore = "prints timings, run with --ignored"]
+    fn bench_arena(){
+        //insert and remove churn, against the global allocator
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(20000);
+        let global_churn = check_churn(&mut Map::<32, u64>::new(), &key_values);
+        let arena_churn = check_churn(&mut Map::<32, u64, 2, 1, false, true>::new(), &key_values);
+        println!("churn secs global allocator: {} arena: {}", global_churn, arena_churn);
+    }
+
+    #[test]
+    fn bucket_test(){
+        check_bits_per_level::<Map<4, u64, 2, 8>>();
+        check_bits_per_level::<Map<4, u64, 4, 16>>();
+        check_bits_per_level::<Map<4, u64, 8, 4>>();
+        check_buckets::<Map<32, [u8; 32]>, Map<32, [u8; 32], 2, 8>>();
+    }
+
+    #[test]
+    fn compact_test(){
+        check_bits_per_level::<Map<4, u64, 2, 1, true>>();
+        check_bits_per_level::<Map<4, u64, 4, 8, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 1, true>>();
+        check_bits_per_level::<Map<4, u64, 8, 4, true>>();
+        check_compact::<Map<64, u64, 8, 4>, Map<64, u64, 8, 4, true>>();
+    }
+
+    #[test]
+    fn dump_test(){
+        let tree = [
+            "/0 2 children",
+            "  0: 0102/16 2 children",
+            "    0: 01020304/30 2 children",
+            "      0: bucket 01020304",
+            "      1: bucket 01020305",
+            "    2: bucket 01028300",
+            "  2: 80/6 2 children",
+            "    1: bucket 81000000",
+            "    2: bucket 82000000",
+            ""
+        ];
+        let map = check_dump(Map::<4, u64>::new(), &tree);
+        //compact buckets show their full keys
+        check_dump(Map::<4, u64, 2, 1, true>::new(), &tree);
+        assert_eq!(map.dump_tree(1, usize::MAX), "/0 2 children\n  0: 0102/16 2 children\n    2 more\n  2: 80/6 2 children\n    2 more\n");
+        assert_eq!(map.to_dot(usize::MAX, 4), [
+            "digraph{",
+            "    n0 [label=\"/0 2 children\" shape=box]",
+            "    n1 [label=\"0102/16 2 children\" shape=box]",
+            "    n0 -> n1 [label=\"0\"]",
+            "    n2 [label=\"01020304/30 2 children\" shape=box]",
+            "    n1 -> n2 [label=\"0\"]",
+            "    n3 [label=\"01020304\" shape=ellipse]",
+            "    n2 -> n3 [label=\"0\"]",
+            "    n2_more [label=\"1 more\" shape=plaintext]",
+            "    n2 -> n2_more",
+            "    n1_more [label=\"1 more\" shape=plaintext]",
+            "    n1 -> n1_more",
+            "    n0_more [label=\"1 more\" shape=plaintext]",
+            "    n0 -> n0_more",
+            "}",
+            ""
+        ].join("\n"));
+    }
+
+    #[test]
+    fn stats_test(){
+        check_stats::<_, Map<32, u64, 8>>(Map::<4, u64>::new(), MapStats{len: 5, buckets: 5, lists: 4, empty_slots: 0,
+            bucket_depths: vec![0, 0, 0, 3, 2], list_occupancy: vec![0, 0, 4], depth: 4, average_depth: 3.4, ..MapStats::default()});
+    }
+
+    #[test]
+    fn bits_per_level_test(){
+        check_bits_per_level::<Map<4, u64, 1>>();
+        check_bits_per_level::<Map<4, u64, 2>>();
+        check_bits_per_level::<Map<4, u64, 4>>();
+        check_bits_per_level::<Map<4, u64, 8>>();
+    }
+
     #[test]
     fn bench(){
         let mut map = Map::<32, u64>::new();
@@ -310,3 +1208,657 @@ mod non_concurrent_tests{
     }
 
 }
+
+mod expiring_tests{
+    use std::sync::Arc;
+    use rust_map::expiring::{Clock, ExpiringMap, ManualClock};
+    use super::*;
+
+    #[test]
+    fn expiry_test(){
+        let map = ExpiringMap::<2, u64, ManualClock>::new(ManualClock::new());
+        let second = Duration::from_secs(1);
+        (0..10u8).for_each(|x| assert_eq!(map.insert_or_update([x, 0], x as u64, second * (x as u32 + 1)), InsertOrUpdateResult::Inserted));
+        assert_eq!(map.len(), 10);
+        map.clock().advance(second * 5);
+        //keys 0 to 4 have expired but are still in the map
+        assert_eq!((map.len(), map.len_with_expired()), (5, 10));
+        assert_eq!(map.get([4, 0]), None);
+        assert_eq!(map.get([5, 0]), Some(5));
+        assert_eq!(map.get_deadline([5, 0]), Some(map.clock().now() + second));
+        let mut live = Vec::new();
+        map.for_each(|key, value| live.push((key, value)));
+        assert_eq!(live, (5..10u8).map(|x| ([x, 0], x as u64)).collect::<Vec<_>>());
+        //closest searches step over expired entries, removing them
+        assert_eq!(map.get_or_closest_by_key([2, 0], true, false), Some(([5, 0], 5)));
+        assert_eq!(map.get_or_closest_by_key_leading_zeroes([1, 0], true), Some(([5, 0], 5)));
+        assert_eq!(map.get_min(), Some(([5, 0], 5)));
+        assert_eq!(map.get_max(), Some(([9, 0], 9)));
+        assert!(map.len_with_expired() < 10);
+        //an expired entry counts as not there for inserts and conditional removes
+        assert_eq!(map.insert_or_update_if([0, 0], 100, second, &|_, _| false), InsertOrUpdateResult::Inserted);
+        assert_eq!(map.insert_or_update_if([0, 0], 101, second, &|_, _| false), InsertOrUpdateResult::Neither);
+        map.insert_or_update([1, 0], 1, Duration::ZERO);
+        assert!(!map.remove_if([1, 0], &|_| true));
+        assert!(map.remove_if([0, 0], &|_| true));
+        map.clock().advance(second * 2);
+        let expired = map.len_with_expired() - 3;
+        assert_eq!(map.sweep(map.clock().now()), expired);
+        assert_eq!((map.len(), map.len_with_expired()), (3, 3));
+        assert_eq!(map.sweep(map.clock().now()), 0);
+        map.clock().advance(second * 10);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn sweeper_test(){
+        let map = Arc::new(ExpiringMap::<32, u64, ManualClock>::new(ManualClock::new()));
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(10000);
+        //half the entries expire after a second, the others after an hour
+        time_threads(4, |i| key_values.iter().skip(i).step_by(4).for_each(|x| {
+            map.insert_or_update(x.0, x.1, Duration::from_secs(if x.1 % 2 == 0 {1} else {3600}));
+        }));
+        let sweeper = map.start_sweeper(Duration::from_millis(10));
+        map.clock().advance(Duration::from_secs(2));
+        let live = key_values.iter().filter(|x| x.1 % 2 == 1).count();
+        let start_time = SystemTime::now();
+        while (map.len_with_expired() > live) && (start_time.elapsed().unwrap() < Duration::from_secs(10)) {std::thread::sleep(Duration::from_millis(10))}
+        assert_eq!(map.len_with_expired(), live);
+        assert!(key_values.iter().all(|x| map.get(x.0) == if x.1 % 2 == 1 {Some(x.1)} else {None}));
+        drop(sweeper);
+        //stopped, so nothing is swept any more
+        map.clock().advance(Duration::from_secs(3600));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!((map.len(), map.len_with_expired()), (0, live));
+        //a sweeper holds the map weakly so ends with it
+        let sweeper = map.start_sweeper(Duration::from_millis(10));
+        drop(map);
+        drop(sweeper);
+        //an interval past what an Instant can hold never sweeps but still stops
+        let map = Arc::new(ExpiringMap::<32, u64, ManualClock>::new(ManualClock::new()));
+        map.insert_or_update([0; 32], 0, Duration::from_secs(1));
+        let sweeper = map.start_sweeper(Duration::MAX);
+        map.clock().advance(Duration::from_secs(2));
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(map.len_with_expired(), 1);
+        drop(sweeper);
+    }
+
+    #[test]
+    fn max_ttl_test(){
+        let map = ExpiringMap::<2, u64, ManualClock>::new(ManualClock::new());
+        //a ttl past what an Instant can hold never expires
+        assert_eq!(map.insert_or_update([0, 0], 0, Duration::MAX), InsertOrUpdateResult::Inserted);
+        assert_eq!(map.insert_or_update([1, 0], 1, Duration::from_secs(1)), InsertOrUpdateResult::Inserted);
+        assert_eq!(map.get_deadline([0, 0]), None);
+        map.clock().advance(Duration::from_secs(3600 * 24 * 365 * 100));
+        assert_eq!(map.sweep(map.clock().now()), 1);
+        assert_eq!(map.get([0, 0]), Some(0));
+        assert_eq!(map.insert_or_update_if([0, 0], 2, Duration::MAX, &|_, _| false), InsertOrUpdateResult::Neither);
+        assert!(map.remove_if([0, 0], &|_| true));
+        assert!(map.is_empty());
+    }
+}
+
+mod bounded_tests{
+    use rust_map::bounded::{BoundedMap, EvictMax, EvictMin, EvictionPolicy, FarthestFrom, Reject, Rejected};
+    use super::*;
+
+    #[test]
+    fn reject_test(){
+        let map = BoundedMap::<2, u64>::new(3, Reject);
+        (0..3u8).for_each(|x| assert_eq!(map.insert_or_update([x, 0], x as u64), Ok((InsertOrUpdateResult::Inserted, None))));
+        assert!(map.is_full());
+        assert_eq!(map.insert_or_update([3, 0], 3), Err(Rejected([3, 0])));
+        //updates don't need room
+        assert_eq!(map.insert_or_update([0, 0], 10), Ok((InsertOrUpdateResult::Updated, None)));
+        map.remove([1, 0]);
+        assert_eq!(map.insert_or_update([3, 0], 3), Ok((InsertOrUpdateResult::Inserted, None)));
+        assert_eq!(map.len(), 3);
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn evict_min_max_test(){
+        let mut keys = get_vec_of_key_values::<[u8; 32]>(1000);
+        let min_map = BoundedMap::<32, u64, EvictMin>::new(10, EvictMin);
+        let max_map = BoundedMap::<32, u64, EvictMax>::new(10, EvictMax);
+        keys.iter().for_each(|x| {
+            _ = min_map.insert_or_update(*x, 0);
+            _ = max_map.insert_or_update(*x, 0);
+        });
+        assert_eq!((min_map.len(), max_map.len()), (10, 10));
+        keys.sort();
+        let (mut largest, mut smallest) = (Vec::new(), Vec::new());
+        min_map.for_each(|key, _| largest.push(key));
+        max_map.for_each(|key, _| smallest.push(key));
+        assert_eq!(largest, keys[990..]);
+        assert_eq!(smallest, keys[..10]);
+        //the evicted entry is given back
+        let evicted = max_map.insert_or_update([0; 32], 1).unwrap();
+        assert_eq!(evicted, (InsertOrUpdateResult::Inserted, Some((keys[9], 0))));
+    }
+
+    #[test]
+    fn dyn_policy_test(){
+        //a policy picked at runtime
+        let maps = [true, false].map(|evict_min| {
+            let policy: Box<dyn EvictionPolicy<2, u64>> = if evict_min {Box::new(EvictMin)} else {Box::new(EvictMax)};
+            BoundedMap::<2, u64, Box<dyn EvictionPolicy<2, u64>>>::new(2, policy)
+        });
+        for map in &maps{
+            [[1, 0], [2, 0], [3, 0]].iter().for_each(|x| _ = map.insert_or_update(*x, 0));
+            assert_eq!(map.len(), 2);
+        }
+        assert_eq!((maps[0].get_min().map(|x| x.0), maps[1].get_max().map(|x| x.0)), (Some([2, 0]), Some([2, 0])));
+    }
+
+    #[test]
+    fn concurrent_writes_test(){
+        //threads insert, update and remove keys at once, evicting as they go, and the count has to come out at what is
+        //in the map
+        let map = BoundedMap::<2, u64, EvictMin>::new(64, EvictMin);
+        let keys = get_vec_of_key_values::<[u8; 2]>(4000);
+        time_threads(4, |i| keys.iter().skip(i).step_by(4).enumerate().for_each(|(j, x)| {
+            _ = map.insert_or_update(*x, j as u64);
+            if (j % 3) == 0 {map.remove(keys[random_range(0..keys.len())])}
+            assert!(map.len() <= map.max_len());
+        }));
+        let mut len = 0;
+        map.for_each(|_, _| len += 1);
+        assert_eq!(map.len(), len);
+        assert!(len <= map.max_len());
+        map.clear();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn routing_table_test(){
+        let own_id = random::<[u8; 32]>();
+        let common_bits = |key: [u8; 32]| (0..256).find(|i| ((key[i / 8] ^ own_id[i / 8]) & (0x80 >> (i % 8))) != 0).unwrap_or(256);
+        let map = BoundedMap::<32, u64, FarthestFrom<32>>::new(16, FarthestFrom(own_id));
+        //peers near own_id, sharing up to 24 leading bits with it
+        let peers = (0..2000).map(|_| {
+            let mut peer = random::<[u8; 32]>();
+            let shared = random_range(0..24);
+            (0..shared).for_each(|i| peer[i / 8] = (peer[i / 8] & !(0x80 >> (i % 8))) | (own_id[i / 8] & (0x80 >> (i % 8))));
+            peer
+        }).collect::<Vec<_>>();
+        time_threads(4, |i| peers.iter().skip(i).step_by(4).for_each(|x| {
+            _ = map.insert_or_update(*x, 0);
+            assert!(map.len() <= map.max_len());
+        }));
+        assert_eq!(map.len(), 16);
+        let mut kept = Vec::new();
+        map.for_each(|key, _| kept.push(key));
+        assert_eq!(kept.len(), 16);
+        let nearest_left_out = peers.iter().filter(|x| !kept.contains(x)).map(|x| common_bits(*x)).max().unwrap();
+        assert!(kept.iter().all(|x| common_bits(*x) >= nearest_left_out));
+    }
+}
+
+mod persistent_tests{
+    use std::collections::BTreeMap;
+    use rust_map::persistent::Map;
+    use super::*;
+
+    #[test]
+    fn model_test(){
+        //small keys so inserts and removes meet the same keys, every version checked against a btree map
+        let mut versions = vec![(Map::<2, u64>::new(), BTreeMap::new())];
+        for _ in 0..20000{
+            let (map, model) = versions.last().unwrap().clone();
+            let key = [random_range(0..4u8), random()];
+            let value = random_range(0..100u64);
+            let (map, model) = if random_range(0..3) == 0{
+                let (map, removed) = map.remove_if(key, &|x| x % 2 == 0);
+                let mut model = model;
+                assert_eq!(removed, model.get(&key).is_some_and(|x| x % 2 == 0));
+                if removed {model.remove(&key);}
+                (map, model)
+            }
+            else{
+                let (map, result) = map.insert_or_update_if(key, value, &should_update);
+                let mut model = model;
+                let expected = match model.get(&key){
+                    None => InsertOrUpdateResult::Inserted,
+                    Some(x) if should_update(x, &value) => InsertOrUpdateResult::Updated,
+                    Some(_) => InsertOrUpdateResult::Neither
+                };
+                assert_eq!(result, expected);
+                if expected != InsertOrUpdateResult::Neither {model.insert(key, value);}
+                (map, model)
+            };
+            assert_eq!(map.get(key), model.get(&key).copied());
+            let probe = random::<[u8; 2]>();
+            assert_eq!(map.get_min(), model.iter().next().map(|x| (*x.0, *x.1)));
+            assert_eq!(map.get_max(), model.iter().next_back().map(|x| (*x.0, *x.1)));
+            assert_eq!(map.get_or_closest_by_key(probe, false, false).map(|x| distance(x.0, probe)), closest(&model, probe).map(|x| distance(x, probe)));
+            versions.push((map, model));
+        }
+        //the old versions are unchanged
+        for (map, model) in versions.iter().step_by(100){
+            let mut entries = Vec::new();
+            map.for_each(|key, value| entries.push((key, value)));
+            assert_eq!(entries, model.iter().map(|x| (*x.0, *x.1)).collect::<Vec<_>>());
+            assert_eq!(map.len(), model.len());
+        }
+    }
+
+    //the way round the key space that is shorter
+    fn distance(key_1: [u8; 2], key_2: [u8; 2]) -> u16{
+        let difference = u16::from_be_bytes(key_1).wrapping_sub(u16::from_be_bytes(key_2));
+        difference.min(difference.wrapping_neg())
+    }
+
+    //the nearer of the keys either side of key, without looping around
+    fn closest(model: &BTreeMap<[u8; 2], u64>, key: [u8; 2]) -> Option<[u8; 2]>{
+        let below = model.range(..key).next_back().map(|x| *x.0);
+        let above = model.range(key..).find(|x| *x.0 != key).map(|x| *x.0);
+        [below, above].into_iter().flatten().min_by_key(|x| distance(*x, key))
+    }
+
+    #[test]
+    fn sharing_test(){
+        let key_values = get_vec_of_key_values::<([u8; 32], u64)>(10000);
+        let map = key_values.iter().fold(Map::<32, u64>::new(), |map, x| map.insert_or_update(x.0, x.1).0);
+        assert_eq!(map.len(), key_values.len());
+        let snapshot = map.clone();
+        assert!(snapshot.shallow_eq(&map));
+        //a few writes copy a few paths and share the rest
+        let changed = key_values.iter().take(10).fold(map.clone(), |map, x| map.insert_or_update(x.0, x.1 + 1).0);
+        let changed = changed.remove(key_values[10].0);
+        let new_key = random::<[u8; 32]>();
+        let changed = changed.insert_or_update(new_key, 0).0;
+        assert!(!changed.shallow_eq(&map));
+        let mut differences = Vec::new();
+        map.diff(&changed, |key, old, new| differences.push((key, old, new)));
+        let mut expected = key_values.iter().take(10).map(|x| (x.0, Some(x.1), Some(x.1 + 1)))
+            .chain([(key_values[10].0, Some(key_values[10].1), None), (new_key, None, Some(0))]).collect::<Vec<_>>();
+        expected.sort_by_key(|x| x.0);
+        assert_eq!(differences, expected);
+        //updates that don't change anything give back the same version
+        assert!(map.insert_or_update_if(key_values[0].0, 0, &|_, _| false).0.shallow_eq(&map));
+        assert!(map.remove_if(key_values[0].0, &|_| false).0.shallow_eq(&map));
+        map.diff(&snapshot, |_, _, _| panic!("no differences"));
+        //removing every key collapses the tree back to nothing
+        let empty = key_values.iter().fold(map.clone(), |map, x| map.remove(x.0));
+        assert!(empty.is_empty());
+        assert_eq!(empty.depth(), 0);
+        assert_eq!(map.len(), key_values.len());
+        //a single entry is held inline, not behind an Arc, so it is compared by value
+        let single = Map::<32, u64>::new().insert_or_update(new_key, 0).0;
+        assert!(single.clone().shallow_eq(&single));
+        assert!(Map::<32, u64>::new().insert_or_update(new_key, 0).0.shallow_eq(&single));
+        assert!(!single.insert_or_update(new_key, 1).0.shallow_eq(&single));
+    }
+
+    #[test]
+    fn get_closest_by_key_leading_zeroes_test(){
+        let mut key = [255; 32];
+        key[0] = 0;
+        let map = Map::<32, u64>::new().insert_or_update(key, 1).0;
+        key = [0; 32];
+        key[0] = 1;
+        key[key.len()-1] = u8::MAX;
+        let map = map.insert_or_update(key, 1).0;
+        assert!(map.get_or_closest_by_key_leading_zeroes([1;32], true).is_some_and(|x| x.0 == key));
+    }
+}
+
+mod linearizability_tests{
+    use std::collections::{BTreeMap, HashSet};
+    use std::time::Instant;
+    use rust_map::concurrent::Map;
+    use super::*;
+
+    type Model = BTreeMap<[u8; 4], u64>;
+    type Seen = HashSet<(u128, Vec<([u8; 4], u64)>)>;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Op{
+        Insert([u8; 4], u64),
+        UpdateIfGreater([u8; 4], u64),
+        Remove([u8; 4]),
+        RemoveIfEven([u8; 4]),
+        Get([u8; 4]),
+        Closest([u8; 4], bool),
+        LeadingZeroes([u8; 4], bool)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Output{
+        Insert(InsertOrUpdateResult),
+        Remove(bool),
+        Get(Option<u64>),
+        Closest(Option<([u8; 4], u64)>)
+    }
+
+    //an operation and what it returned, with when it was called and when it returned
+    #[derive(Debug)]
+    struct Event{
+        thread: usize,
+        op: Op,
+        output: Output,
+        start: Duration,
+        end: Duration
+    }
+
+    fn random_op(keys: &[[u8; 4]]) -> Op{
+        let key = keys[random_range(0..keys.len())];
+        match random_range(0..7){
+            0 => Op::Insert(key, random_range(0..100)),
+            1 => Op::UpdateIfGreater(key, random_range(0..100)),
+            2 => Op::Remove(key),
+            3 => Op::RemoveIfEven(key),
+            4 => Op::Get(key),
+            5 => Op::Closest(key, random()),
+            _ => Op::LeadingZeroes(key, random())
+        }
+    }
+
+    fn run<const BITS: usize, const B: usize, const COMPACT: bool>(map: &Map<4, u64, BITS, B, COMPACT>, op: Op) -> Output where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+        match op{
+            Op::Insert(key, value) => Output::Insert(map.insert_or_update(key, value)),
+            Op::UpdateIfGreater(key, value) => Output::Insert(map.insert_or_update_if(key, value, &should_update)),
+            Op::Remove(key) => Output::Remove(map.remove_if(key, &|_| true)),
+            Op::RemoveIfEven(key) => Output::Remove(map.remove_if(key, &|x| x % 2 == 0)),
+            Op::Get(key) => Output::Get(map.get(key)),
+            Op::Closest(key, include_key) => Output::Closest(map.get_or_closest_by_key(key, include_key, true)),
+            Op::LeadingZeroes(key, include_key) => Output::Closest(map.get_or_closest_by_key_leading_zeroes(key, include_key))
+        }
+    }
+
+    //the model after op if output is what op can return from it, None if it can't
+    fn step(model: &Model, op: Op, output: Output) -> Option<Model>{
+        let mut model = model.clone();
+        let expected = match op{
+            Op::Insert(key, value) => Output::Insert(if model.insert(key, value).is_some() {InsertOrUpdateResult::Updated} else {InsertOrUpdateResult::Inserted}),
+            Op::UpdateIfGreater(key, value) => Output::Insert(match model.get(&key).copied(){
+                Some(old) if !should_update(&old, &value) => InsertOrUpdateResult::Neither,
+                old => {
+                    model.insert(key, value);
+                    if old.is_some() {InsertOrUpdateResult::Updated} else {InsertOrUpdateResult::Inserted}
+                }
+            }),
+            Op::Remove(key) => Output::Remove(model.remove(&key).is_some()),
+            Op::RemoveIfEven(key) => Output::Remove(model.get(&key).is_some_and(|x| x % 2 == 0) && model.remove(&key).is_some()),
+            Op::Get(key) => Output::Get(model.get(&key).copied()),
+            //any of the nearest entries will do
+            Op::Closest(key, include_key) => return is_best(&model, key, include_key, output, |x| get_circular_distance(key, x)).then_some(model),
+            Op::LeadingZeroes(key, include_key) => return is_best(&model, key, include_key, output, |x| u32::MAX - get_leading_zeroes(key, x)).then_some(model)
+        };
+        (output == expected).then_some(model)
+    }
+
+    //whether output is an entry of the model with the lowest score, or None if there are no entries to choose from
+    fn is_best(model: &Model, key: [u8; 4], include_key: bool, output: Output, score: impl Fn([u8; 4]) -> u32) -> bool{
+        let best = model.keys().filter(|x| include_key || (**x != key)).map(|x| score(*x)).min();
+        match output{
+            Output::Closest(Some(entry)) => (include_key || (entry.0 != key)) && (model.get(&entry.0) == Some(&entry.1)) && (Some(score(entry.0)) == best),
+            Output::Closest(None) => best.is_none(),
+            _ => false
+        }
+    }
+
+    //searches for an order of the events that respects real time, each one going after every event that returned before
+    //it was called, and that the model agrees with. done is the set of events already ordered, pairs of done and the
+    //model's entries that have been searched from are remembered so no state is searched twice
+    fn is_linearizable(events: &[Event], model: &Model) -> bool{
+        assert!(events.len() <= 128);
+        search(events, 0, model, &mut HashSet::new())
+    }
+
+    fn search(events: &[Event], done: u128, model: &Model, seen: &mut Seen) -> bool{
+        if (done.count_ones() as usize) == events.len() {return true}
+        if !seen.insert((done, model.iter().map(|x| (*x.0, *x.1)).collect())) {return false}
+        let remaining = || events.iter().enumerate().filter(move |x| (done & (1 << x.0)) == 0);
+        let first_end = remaining().map(|x| x.1.end).min().unwrap();
+        remaining().filter(|x| x.1.start <= first_end)
+            .any(|(i, event)| step(model, event.op, event.output).is_some_and(|model| search(events, done | (1 << i), &model, seen)))
+    }
+
+    fn check_linearizable<const BITS: usize, const B: usize, const COMPACT: bool>() where BitsPerLevel<BITS>: SupportedBitsPerLevel, Map<4, u64, BITS, B, COMPACT>: Sync{
+        let no_threads = 4;
+        let ops_per_thread = 24;
+        for _ in 0..100{
+            //a few keys, half sharing their first two bytes, so threads meet on the same keys and lists split and collapse
+            let mut keys = get_vec_of_key_values::<[u8; 4]>(12);
+            keys[6..].iter_mut().for_each(|x| x[..2].copy_from_slice(&[7, 7]));
+            let map = Map::<4, u64, BITS, B, COMPACT>::new();
+            let mut model = Model::new();
+            keys.iter().step_by(2).for_each(|x| {
+                map.insert_or_update(*x, 0);
+                model.insert(*x, 0);
+            });
+            let start_time = Instant::now();
+            let mut events = std::thread::scope(|scope| {
+                let threads = (0..no_threads).map(|thread| {
+                    let (map, keys) = (&map, &keys);
+                    scope.spawn(move || (0..ops_per_thread).map(|_| {
+                        let op = random_op(keys);
+                        let start = start_time.elapsed();
+                        let output = run(map, op);
+                        Event{thread, op, output, start, end: start_time.elapsed()}
+                    }).collect::<Vec<_>>())
+                }).collect::<Vec<_>>();
+                threads.into_iter().flat_map(|x| x.join().unwrap()).collect::<Vec<_>>()
+            });
+            assert_eq!(map.validate(), Ok(()));
+            events.sort_by_key(|x| x.start);
+            for thread in 0..no_threads{
+                let times = events.iter().filter(|x| x.thread == thread).flat_map(|x| [x.start, x.end]).collect::<Vec<_>>();
+                assert!(times.is_sorted());
+            }
+            assert!(is_linearizable(&events, &model), "not linearizable from {:?}: {:#?}", model, events);
+        }
+    }
+
+    #[test]
+    fn checker_test(){
+        let key = [1, 2, 3, 4];
+        let event = |thread, op, output, start, end| Event{thread, op, output, start: Duration::from_nanos(start), end: Duration::from_nanos(end)};
+        //a get overlapping an insert can see it or not
+        for seen in [None, Some(5)]{
+            let events = [
+                event(0, Op::Insert(key, 5), Output::Insert(InsertOrUpdateResult::Inserted), 0, 10),
+                event(1, Op::Get(key), Output::Get(seen), 5, 15)
+            ];
+            assert!(is_linearizable(&events, &Model::new()));
+        }
+        //but one starting after the insert returned has to see it
+        let events = [
+            event(0, Op::Insert(key, 5), Output::Insert(InsertOrUpdateResult::Inserted), 0, 10),
+            event(1, Op::Get(key), Output::Get(None), 11, 15)
+        ];
+        assert!(!is_linearizable(&events, &Model::new()));
+        //two removes of the same key can't both remove it
+        let events = [
+            event(0, Op::Remove(key), Output::Remove(true), 0, 10),
+            event(1, Op::Remove(key), Output::Remove(true), 0, 10)
+        ];
+        assert!(!is_linearizable(&events, &Model::from([(key, 1)])));
+        //the closest key has to be one of the nearest present
+        let events = [event(0, Op::Closest([1, 2, 3, 0], true), Output::Closest(Some(([9, 9, 9, 9], 1))), 0, 10)];
+        assert!(!is_linearizable(&events, &Model::from([(key, 1), ([9, 9, 9, 9], 1)])));
+    }
+
+    #[test]
+    fn linearizability_test(){
+        check_linearizable::<2, 1, false>();
+        check_linearizable::<8, 1, false>();
+        check_linearizable::<2, 4, true>();
+        check_linearizable::<4, 2, true>();
+    }
+}
+
+mod property_tests{
+    use std::collections::BTreeMap;
+    use rust_map::non_concurrent::Map;
+    use super::*;
+
+    type Model = BTreeMap<[u8; 4], u64>;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Op{
+        UpdateIfGreater([u8; 4], u64),
+        RemoveIfEven([u8; 4]),
+        Get([u8; 4]),
+        Min,
+        Max,
+        Closest([u8; 4], bool, bool),
+        LeadingZeroes([u8; 4], bool)
+    }
+
+    //bytes are mostly from a few values so keys share prefixes, sit next to each other and wrap around
+    fn random_key() -> [u8; 4]{
+        std::array::from_fn(|_| if random_range(0..4) == 0 {random()} else {[0, 1, 128, 255][random_range(0..4)]})
+    }
+
+    fn random_op() -> Op{
+        match random_range(0..8){
+            0 | 1 => Op::UpdateIfGreater(random_key(), random_range(0..10)),
+            2 => Op::RemoveIfEven(random_key()),
+            3 => Op::Get(random_key()),
+            4 => if random() {Op::Min} else {Op::Max},
+            5 | 6 => Op::Closest(random_key(), random(), random()),
+            _ => Op::LeadingZeroes(random_key(), random())
+        }
+    }
+
+    //smaller versions of an op, its key bytes and value zeroed one at a time
+    fn simplify(op: &Op) -> Vec<Op>{
+        let keys = |key: [u8; 4]| (0..4).filter(move |i| key[*i] != 0).map(move |i| {
+            let mut key = key;
+            key[i] = 0;
+            key
+        });
+        match *op{
+            Op::UpdateIfGreater(key, value) => keys(key).map(|x| Op::UpdateIfGreater(x, value)).chain((value != 0).then_some(Op::UpdateIfGreater(key, 0))).collect(),
+            Op::RemoveIfEven(key) => keys(key).map(Op::RemoveIfEven).collect(),
+            Op::Get(key) => keys(key).map(Op::Get).collect(),
+            Op::Min | Op::Max => Vec::new(),
+            Op::Closest(key, include_key, loop_around) => keys(key).map(|x| Op::Closest(x, include_key, loop_around)).collect(),
+            Op::LeadingZeroes(key, include_key) => keys(key).map(|x| Op::LeadingZeroes(x, include_key)).collect()
+        }
+    }
+
+    //an entry of the model with the lowest score if there is one, where ties may go either way
+    fn check_best(model: &Model, key: [u8; 4], include_key: bool, result: Option<([u8; 4], u64)>, score: impl Fn([u8; 4]) -> u32) -> Result<(), String>{
+        let best = model.keys().filter(|x| include_key || (**x != key)).map(|x| score(*x)).min();
+        let ok = match result{
+            Some(entry) => (include_key || (entry.0 != key)) && (model.get(&entry.0) == Some(&entry.1)) && (Some(score(entry.0)) == best),
+            None => best.is_none()
+        };
+        if ok {Ok(())} else {Err(format!("{:?} isn't one of the nearest", result))}
+    }
+
+    fn check_op<const BITS: usize, const B: usize, const COMPACT: bool>(map: &mut Map<4, u64, BITS, B, COMPACT>, model: &mut Model, op: Op) -> Result<(), String> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+        let compare = |actual: &dyn Debug, expected: &dyn Debug| {
+            let (actual, expected) = (format!("{:?}", actual), format!("{:?}", expected));
+            if actual == expected {Ok(())} else {Err(format!("returned {} rather than {}", actual, expected))}
+        };
+        let distance = |key: [u8; 4], x: [u8; 4]| u32::from_be_bytes(key).abs_diff(u32::from_be_bytes(x));
+        match op{
+            Op::UpdateIfGreater(key, value) => {
+                let expected = match model.get(&key){
+                    Some(old) if !should_update(old, &value) => InsertOrUpdateResult::Neither,
+                    old => {
+                        let expected = if old.is_some() {InsertOrUpdateResult::Updated} else {InsertOrUpdateResult::Inserted};
+                        model.insert(key, value);
+                        expected
+                    }
+                };
+                compare(&map.insert_or_update_if(key, value, &should_update), &expected)?
+            }
+            Op::RemoveIfEven(key) => {
+                let expected = model.get(&key).is_some_and(|x| x % 2 == 0) && model.remove(&key).is_some();
+                compare(&map.remove_if(key, &|x| x % 2 == 0), &expected)?
+            }
+            Op::Get(key) => compare(&map.get(key), &model.get(&key).copied())?,
+            Op::Min => compare(&map.get_min(), &model.first_key_value().map(|x| (*x.0, *x.1)))?,
+            Op::Max => compare(&map.get_max(), &model.last_key_value().map(|x| (*x.0, *x.1)))?,
+            Op::Closest(key, include_key, loop_around) => {
+                let result = map.get_or_closest_by_key(key, include_key, loop_around);
+                if loop_around {check_best(model, key, include_key, result, |x| get_circular_distance(key, x))?}
+                else {check_best(model, key, include_key, result, |x| distance(key, x))?}
+            }
+            Op::LeadingZeroes(key, include_key) => {
+                //keys with the most leading bits in common are all on one side of key so the nearest of them is unique
+                let expected = model.iter().filter(|x| include_key || (*x.0 != key))
+                    .min_by_key(|x| (u32::MAX - get_leading_zeroes(key, *x.0), distance(key, *x.0))).map(|x| (*x.0, *x.1));
+                compare(&map.get_or_closest_by_key_leading_zeroes(key, include_key), &expected)?
+            }
+        }
+        compare(&map.len(), &model.len())?;
+        map.validate().map_err(|x| format!("left the map out of shape, {}", x))
+    }
+
+    //applies ops to a new map and model, returning which op gave a different result and how
+    fn run<const BITS: usize, const B: usize, const COMPACT: bool>(ops: &[Op]) -> Result<(), String> where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+        let mut map = Map::<4, u64, BITS, B, COMPACT>::new();
+        let mut model = Model::new();
+        ops.iter().enumerate().try_for_each(|(i, op)| check_op(&mut map, &mut model, *op).map_err(|x| format!("op {} {:?} {}", i, op, x)))
+    }
+
+    //removes ops, halving how many at a time down to one, then simplifies what is left until nothing more still fails
+    fn shrink<T: Clone>(mut ops: Vec<T>, simplify: impl Fn(&T) -> Vec<T>, fails: impl Fn(&[T]) -> bool) -> Vec<T>{
+        loop{
+            let len = ops.len();
+            let mut chunk = ops.len().div_ceil(2);
+            while chunk > 0{
+                let mut start = 0;
+                while start < ops.len(){
+                    let shorter = ops[..start].iter().chain(&ops[(start + chunk).min(ops.len())..]).cloned().collect::<Vec<_>>();
+                    if fails(&shorter) {ops = shorter} else {start += chunk}
+                }
+                chunk /= 2;
+            }
+            let mut simplified = false;
+            for i in 0..ops.len(){
+                for op in simplify(&ops[i]){
+                    let old = std::mem::replace(&mut ops[i], op);
+                    if fails(&ops){
+                        simplified = true;
+                        break
+                    }
+                    ops[i] = old;
+                }
+            }
+            if !simplified && (ops.len() == len) {return ops}
+        }
+    }
+
+    fn check_against_model<const BITS: usize, const B: usize, const COMPACT: bool>() where BitsPerLevel<BITS>: SupportedBitsPerLevel{
+        for _ in 0..300{
+            let ops = (0..random_range(1..300)).map(|_| random_op()).collect::<Vec<_>>();
+            if run::<BITS, B, COMPACT>(&ops).is_err(){
+                let ops = shrink(ops, simplify, |x| run::<BITS, B, COMPACT>(x).is_err());
+                panic!("{} for {:?}", run::<BITS, B, COMPACT>(&ops).unwrap_err(), ops);
+            }
+        }
+    }
+
+    #[test]
+    fn shrink_test(){
+        //fails once a key starting with at least 3 is inserted then removed
+        let fails = |ops: &[Op]| ops.iter().enumerate().any(|(i, x)| matches!(x, Op::UpdateIfGreater(key, _) if key[0] >= 3) &&
+            ops[i..].iter().any(|y| matches!((x, y), (Op::UpdateIfGreater(key, _), Op::RemoveIfEven(other)) if key == other)));
+        let mut ops = std::iter::repeat_with(random_op).filter(|x| !matches!(x, Op::RemoveIfEven(_))).take(200).collect::<Vec<_>>();
+        ops.insert(random_range(0..ops.len()), Op::UpdateIfGreater([9, 9, 9, 9], 5));
+        ops.push(Op::RemoveIfEven([9, 9, 9, 9]));
+        let ops = shrink(ops, simplify, fails);
+        assert_eq!(ops, [Op::UpdateIfGreater([9, 9, 9, 9], 0), Op::RemoveIfEven([9, 9, 9, 9])]);
+    }
+
+    #[test]
+    fn model_test(){
+        check_against_model::<1, 1, false>();
+        check_against_model::<2, 1, false>();
+        check_against_model::<4, 2, true>();
+        check_against_model::<8, 1, false>();
+        check_against_model::<2, 4, true>();
+        check_against_model::<8, 8, true>();
+    }
+}